@@ -0,0 +1,487 @@
+use std::{collections::HashMap, hash::Hash};
+
+use eyre::{ensure, Result};
+use petgraph::graph::Graph;
+use rand::Rng;
+
+use crate::model::{read_words, Model, Words};
+
+type Token<T> = Option<T>;
+/// Tokens seen after a prefix and how often.
+type Nexts<T> = Vec<(Token<T>, usize)>;
+
+/// Everything seen after one prefix, in the order it was first seen so that
+/// sampling with a seeded rng is reproducible.
+#[derive(Debug, Clone)]
+struct State<T> {
+    prefix: Vec<Token<T>>,
+    nexts: Nexts<T>,
+    total: usize,
+}
+
+impl<T: Clone + PartialEq> State<T> {
+    fn new(prefix: Vec<Token<T>>) -> Self {
+        Self {
+            prefix,
+            nexts: Vec::new(),
+            total: 0,
+        }
+    }
+
+    fn add(&mut self, token: Token<T>) {
+        match self.nexts.iter_mut().find(|(t, _)| *t == token) {
+            Some((_, count)) => *count += 1,
+            None => self.nexts.push((token, 1)),
+        }
+        self.total += 1;
+    }
+
+    /// Sample among the tokens that pass `allow`, if any do.
+    fn sample_where(
+        &self,
+        rng: &mut impl Rng,
+        allow: impl Fn(&Token<T>) -> bool,
+    ) -> Option<Token<T>> {
+        let total: usize = match self.nexts.iter().all(|(t, _)| allow(t)) {
+            true => self.total,
+            false => self
+                .nexts
+                .iter()
+                .filter(|(t, _)| allow(t))
+                .map(|(_, c)| c)
+                .sum(),
+        };
+        if total == 0 {
+            return None;
+        }
+        let cap = rng.gen_range(0..total);
+        let mut sum = 0;
+        for (token, count) in self.nexts.iter().filter(|(t, _)| allow(t)) {
+            sum += count;
+            if sum > cap {
+                return Some(token.clone());
+            }
+        }
+        unreachable!("counts do not add up to the total")
+    }
+}
+
+/// A Markov chain like `markov::Chain` that samples with a caller's rng.
+#[derive(Debug, Clone)]
+pub struct Chain<T> {
+    order: usize,
+    // whether states of every shorter order are kept to back off to
+    backoff: bool,
+    index: HashMap<Vec<Token<T>>, usize>,
+    states: Vec<State<T>>,
+}
+
+impl<T: Clone + Eq + Hash> Chain<T> {
+    pub fn of_order(order: usize) -> Self {
+        assert!(order != 0);
+        Self {
+            order,
+            backoff: false,
+            index: HashMap::new(),
+            states: Vec::new(),
+        }
+    }
+
+    /// Get a variable order chain, which uses up to `max_order` tokens of
+    /// context where it has seen them and backs off to shorter contexts
+    /// where it hasn't, like prediction by partial matching (method C).
+    pub fn with_backoff(max_order: usize) -> Self {
+        Self {
+            backoff: true,
+            ..Self::of_order(max_order)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// Feed the chain one run of tokens.
+    pub fn feed(&mut self, tokens: impl AsRef<[T]>) -> &mut Self {
+        let tokens = tokens.as_ref();
+        if tokens.is_empty() {
+            return self;
+        }
+        let lowest = match self.backoff {
+            true => 0,
+            false => self.order,
+        };
+        for order in lowest..=self.order {
+            let mut toks = vec![None; order];
+            toks.extend(tokens.iter().cloned().map(Some));
+            toks.push(None);
+            for w in toks.windows(order + 1) {
+                let prefix = &w[..order];
+                let i = match self.index.get(prefix) {
+                    Some(&i) => i,
+                    None => {
+                        self.states.push(State::new(prefix.to_vec()));
+                        self.index.insert(prefix.to_vec(), self.states.len() - 1);
+                        self.states.len() - 1
+                    }
+                };
+                self.states[i].add(w[order].clone());
+            }
+        }
+        self
+    }
+
+    /// Generate one run of tokens from the start of the chain to an end.
+    pub fn generate(&self, rng: &mut impl Rng) -> Vec<T> {
+        let mut out = Vec::new();
+        if self.is_empty() {
+            return out;
+        }
+        let mut curs = vec![None; self.order];
+        loop {
+            let next = self
+                .next_where(&curs, rng, |_| true)
+                .expect("every state has a next");
+            curs.remove(0);
+            curs.push(next.clone());
+            match next {
+                Some(next) => out.push(next),
+                None => break,
+            }
+        }
+        out
+    }
+
+    /// Generate a run where every token, and the end, passes `allow` given
+    /// the run so far, stopping early once `done` accepts it. Dead ends are
+    /// backtracked out of, trying other tokens where the run went wrong.
+    /// Gets `None` if no run fits within `budget` samples.
+    pub fn generate_where(
+        &self,
+        rng: &mut impl Rng,
+        budget: usize,
+        allow: impl Fn(&[T], &Token<T>) -> bool,
+        done: impl Fn(&[T]) -> bool,
+    ) -> Option<Vec<T>> {
+        let mut run: Vec<T> = Vec::new();
+        // tokens already tried after each prefix of the run
+        let mut tried: Vec<Vec<Token<T>>> = vec![Vec::new()];
+        for _ in 0..budget {
+            let mut prev = vec![None; self.order.saturating_sub(run.len())];
+            prev.extend(
+                run.iter()
+                    .skip(run.len().saturating_sub(self.order))
+                    .cloned()
+                    .map(Some),
+            );
+            let tried_here = tried.last().expect("tried is never empty");
+            let next = self.next_where(&prev, rng, |t| !tried_here.contains(t) && allow(&run, t));
+            match next {
+                Some(None) => return Some(run),
+                Some(Some(token)) => {
+                    tried.last_mut().unwrap().push(Some(token.clone()));
+                    run.push(token);
+                    if done(&run) {
+                        return Some(run);
+                    }
+                    tried.push(Vec::new());
+                }
+                None => {
+                    tried.pop();
+                    run.pop()?;
+                }
+            }
+        }
+        None
+    }
+
+    /// Sample the token after `prev`, the last `order` tokens with `None`
+    /// before the start, among those that pass `allow`. A `None` token is
+    /// the end of a run. Gets `None` if nothing known can follow.
+    pub fn next_where(
+        &self,
+        prev: &[Token<T>],
+        rng: &mut impl Rng,
+        allow: impl Fn(&Token<T>) -> bool,
+    ) -> Option<Token<T>> {
+        if !self.backoff {
+            return self
+                .index
+                .get(prev)
+                .and_then(|&i| self.states[i].sample_where(rng, allow));
+        }
+        // tokens an escape from a longer context has ruled out
+        let mut excluded = Vec::new();
+        for order in (0..=self.order.min(prev.len())).rev() {
+            let state = match self.index.get(&prev[prev.len() - order..]) {
+                Some(&i) => &self.states[i],
+                None => continue,
+            };
+            let candidates = state
+                .nexts
+                .iter()
+                .filter(|(t, _)| allow(t) && !excluded.contains(t))
+                .collect::<Vec<_>>();
+            let seen: usize = candidates.iter().map(|(_, c)| c).sum();
+            let distinct = candidates.len();
+            if distinct == 0 {
+                continue;
+            }
+            if order > 0 && rng.gen_range(0..seen + distinct) >= seen {
+                excluded.extend(candidates.into_iter().map(|(t, _)| t.clone()));
+                continue;
+            }
+            return state.sample_where(rng, |t| allow(t) && !excluded.contains(t));
+        }
+        None
+    }
+
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    /// Build a graph of the chain's states weighted by transition probability.
+    /// Backed off to states are left out.
+    pub fn graph(&self) -> Graph<Vec<Token<T>>, f64> {
+        let mut graph = Graph::new();
+        let mut nodes = HashMap::new();
+        let mut node = |graph: &mut Graph<Vec<Token<T>>, f64>, state: Vec<Token<T>>| {
+            *nodes
+                .entry(state.clone())
+                .or_insert_with(|| graph.add_node(state))
+        };
+        for state in self.states.iter().filter(|s| s.prefix.len() == self.order) {
+            let from = node(&mut graph, state.prefix.clone());
+            for (next, count) in &state.nexts {
+                let mut next_state = state.prefix[1..].to_vec();
+                next_state.push(next.clone());
+                let to = node(&mut graph, next_state);
+                graph.add_edge(from, to, *count as f64 / state.total as f64);
+            }
+        }
+        graph
+    }
+}
+
+impl<T: Clone + Eq + Hash + Words> Chain<T> {
+    /// Write the chain into `model` as `<name>` and a `<name>.state` line
+    /// per state.
+    pub fn save(&self, model: &mut Model, name: &str) {
+        model.set_words(name, &(self.order, self.backoff));
+        for state in &self.states {
+            model.set_words(
+                &format!("{}.state", name),
+                &(state.prefix.clone(), state.nexts.clone()),
+            );
+        }
+    }
+
+    /// Read a chain written by `save`.
+    pub fn load(model: &Model, name: &str) -> Result<Self> {
+        let (order, backoff): (usize, bool) = model.words(name)?;
+        ensure!(order != 0, "chain {} has order 0", name);
+        let mut chain = match backoff {
+            true => Self::with_backoff(order),
+            false => Self::of_order(order),
+        };
+        for line in model.all(&format!("{}.state", name)) {
+            let (prefix, nexts): (Vec<Token<T>>, Nexts<T>) = read_words(line)?;
+            ensure!(prefix.len() <= order, "chain {} has a state too long", name);
+            let total = nexts.iter().map(|(_, c)| c).sum();
+            ensure!(total > 0, "chain {} has a state with no nexts", name);
+            chain.index.insert(prefix.clone(), chain.states.len());
+            chain.states.push(State {
+                prefix,
+                nexts,
+                total,
+            });
+        }
+        if !chain.is_empty() {
+            chain.check_closed(name)?;
+        }
+        Ok(chain)
+    }
+
+    // check generation can't reach a prefix without a state, which a
+    // truncated or edited model could
+    fn check_closed(&self, name: &str) -> Result<()> {
+        if self.backoff {
+            ensure!(
+                self.index.contains_key(&Vec::new()),
+                "chain {} has nothing to back off to",
+                name
+            );
+            return Ok(());
+        }
+        ensure!(
+            self.index.contains_key(&vec![None; self.order]),
+            "chain {} has no start",
+            name
+        );
+        for state in &self.states {
+            ensure!(
+                state.prefix.len() == self.order,
+                "chain {} has a state of the wrong order",
+                name
+            );
+            for (next, _) in state.nexts.iter().filter(|(next, _)| next.is_some()) {
+                let mut to = state.prefix[1..].to_vec();
+                to.push(next.clone());
+                ensure!(
+                    self.index.contains_key(&to),
+                    "chain {} has a next with no state",
+                    name
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_chain {
+    use super::*;
+    use hamcrest2::prelude::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    #[test]
+    fn test_chain() {
+        rspec::run(&rspec::describe("Chain", Env::default(), |ctx| {
+            ctx.it("generates runs it was fed", |_| {
+                let mut chain = Chain::of_order(1);
+                chain.feed(vec![3u8, 5, 10]).feed(vec![5, 12]);
+                let v = chain.generate(&mut StdRng::seed_from_u64(1));
+                assert!([vec![3, 5, 10], vec![3, 5, 12], vec![5, 10], vec![5, 12]].contains(&v));
+            });
+
+            ctx.it("is reproducible with a seed", |_| {
+                let mut chain = Chain::of_order(1);
+                chain.feed((0u8..20).chain(10..30).collect::<Vec<_>>());
+                let run = |seed| {
+                    let mut rng = StdRng::seed_from_u64(seed);
+                    (0..5).map(|_| chain.generate(&mut rng)).collect::<Vec<_>>()
+                };
+                assert_that!(run(7), eq(run(7)));
+            });
+
+            ctx.it("samples only what is allowed", |_| {
+                let mut chain = Chain::of_order(1);
+                chain.feed(vec![1u8, 2]).feed(vec![1, 3]);
+                let mut rng = StdRng::seed_from_u64(1);
+                for _ in 0..10 {
+                    let next = chain.next_where(&[Some(1)], &mut rng, |t| *t != Some(2));
+                    assert_that!(next, eq(Some(Some(3))));
+                }
+                assert_that!(chain.next_where(&[Some(1)], &mut rng, |_| false), none());
+                assert_that!(chain.next_where(&[Some(9)], &mut rng, |_| true), none());
+            });
+
+            ctx.it("backs off to shorter contexts", |_| {
+                let mut chain = Chain::with_backoff(2);
+                chain.feed(vec![1u8, 2, 3]).feed(vec![4, 2, 5]);
+                let mut rng = StdRng::seed_from_u64(1);
+                let nexts = (0..100)
+                    .map(|_| chain.next_where(&[Some(9), Some(2)], &mut rng, |_| true))
+                    .collect::<Vec<_>>();
+                assert!(nexts.iter().all(Option::is_some));
+                let after_two = nexts
+                    .iter()
+                    .filter(|n| [Some(Some(3)), Some(Some(5))].contains(n))
+                    .count();
+                assert_that!(after_two, gt(30));
+                let mut fixed = Chain::of_order(2);
+                fixed.feed(vec![1u8, 2, 3]);
+                assert_that!(
+                    fixed.next_where(&[Some(9), Some(2)], &mut rng, |_| true),
+                    none()
+                );
+            });
+
+            ctx.it("uses long contexts where it has seen them", |_| {
+                let mut chain = Chain::with_backoff(2);
+                for _ in 0..50 {
+                    chain.feed(vec![1u8, 2, 3]).feed(vec![4, 2, 5]);
+                }
+                let mut rng = StdRng::seed_from_u64(1);
+                let threes = (0..100)
+                    .filter(|_| {
+                        chain.next_where(&[Some(1), Some(2)], &mut rng, |_| true) == Some(Some(3))
+                    })
+                    .count();
+                assert_that!(threes, gt(90));
+                let run = chain.generate(&mut rng);
+                assert!(!run.is_empty());
+            });
+
+            ctx.it("backtracks out of dead ends", |_| {
+                let mut chain = Chain::of_order(1);
+                chain
+                    .feed(vec![1u8, 2, 3])
+                    .feed(vec![1, 4, 5])
+                    .feed(vec![1, 4, 6]);
+                for seed in 0..20 {
+                    let mut rng = StdRng::seed_from_u64(seed);
+                    // runs must end on 3
+                    let run = chain.generate_where(
+                        &mut rng,
+                        100,
+                        |run, t| t.is_some() || run.last() == Some(&3),
+                        |_| false,
+                    );
+                    assert_that!(run, eq(Some(vec![1, 2, 3])));
+                    // stop once two long
+                    let run =
+                        chain.generate_where(&mut rng, 100, |_, _| true, |run| run.len() == 2);
+                    assert_that!(run.map(|r| r.len()), eq(Some(2)));
+                }
+                let impossible = chain.generate_where(
+                    &mut StdRng::seed_from_u64(1),
+                    100,
+                    |_, t| *t != Some(1),
+                    |_| false,
+                );
+                assert_that!(impossible, none());
+            });
+
+            ctx.it("saves and loads", |_| {
+                let mut chain = Chain::with_backoff(2);
+                chain.feed(vec![1u8, 2, 3]).feed(vec![4, 2, 5]);
+                let mut model = Model::default();
+                chain.save(&mut model, "c");
+                let model: Model = model.to_string().parse().unwrap();
+                let loaded = Chain::<u8>::load(&model, "c").unwrap();
+                let run = |chain: &Chain<u8>| chain.generate(&mut StdRng::seed_from_u64(4));
+                assert_that!(run(&loaded), eq(run(&chain)));
+                assert_that!(loaded.states.len(), eq(chain.states.len()));
+                assert_that!(Chain::<u8>::load(&model, "d"), err());
+            });
+
+            ctx.it("refuses to load a chain with missing states", |_| {
+                let mut chain = Chain::of_order(2);
+                chain.feed(vec![1u8, 2, 3]);
+                let mut model = Model::default();
+                chain.save(&mut model, "c");
+                let text = model.to_string();
+                let truncated: String = text
+                    .lines()
+                    .filter(|l| !l.contains("c.state") || !l.contains('3'))
+                    .map(|l| format!("{}\n", l))
+                    .collect();
+                assert_that!(truncated.len(), lt(text.len()));
+                let model: Model = truncated.parse().unwrap();
+                let err = Chain::<u8>::load(&model, "c").unwrap_err();
+                assert_that!(err.to_string(), eq("chain c has a next with no state"));
+            });
+
+            ctx.it("generates nothing when empty", |_| {
+                let chain = Chain::<u8>::of_order(2);
+                assert!(chain.generate(&mut StdRng::seed_from_u64(1)).is_empty());
+                let chain = Chain::<u8>::with_backoff(2);
+                assert!(chain.generate(&mut StdRng::seed_from_u64(1)).is_empty());
+            });
+        }));
+    }
+}
@@ -0,0 +1,279 @@
+use std::str::FromStr;
+
+use eyre::{eyre, Result};
+
+use crate::{sequence::Event, theory::Scale};
+
+/// Keys from `low` to `high` inclusive, e.g. an instrument's range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyRange {
+    pub low: u8,
+    pub high: u8,
+}
+
+impl KeyRange {
+    pub fn contains(&self, key: u8) -> bool {
+        (self.low..=self.high).contains(&key)
+    }
+}
+
+impl FromStr for KeyRange {
+    type Err = eyre::Error;
+
+    /// Parse `low-high` MIDI keys, e.g. `40-76`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (low, high) = s
+            .split_once('-')
+            .ok_or_else(|| eyre!("range must be low-high: {}", s))?;
+        let (low, high) = (low.trim().parse()?, high.trim().parse()?);
+        match low <= high && high <= 127 {
+            true => Ok(Self { low, high }),
+            false => Err(eyre!("bad key range {}", s)),
+        }
+    }
+}
+
+/// What the last notes of a phrase must be.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Cadence {
+    /// The last notes include the tonic of the scale.
+    Tonic,
+    /// The last notes are all in the chord and include its root, as pitch
+    /// classes from the root.
+    Chord(Vec<u8>),
+}
+
+impl FromStr for Cadence {
+    type Err = eyre::Error;
+
+    /// Parse `tonic`, or a chord as comma separated keys from its root,
+    /// e.g. `g,b,d`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "tonic" => Ok(Self::Tonic),
+            chord => chord
+                .split(',')
+                .map(|k| k.parse::<Scale>().map(|s| s.tonic()))
+                .collect::<Result<Vec<_>>>()
+                .map(Self::Chord),
+        }
+    }
+}
+
+/// Rules generated phrases must follow, checked token by token while
+/// sampling rather than by filtering what comes out.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Constraints {
+    /// Only keys in the scale.
+    pub scale: Option<Scale>,
+    /// Only keys in the range.
+    pub range: Option<KeyRange>,
+    /// Phrases exactly this many beats long.
+    pub beats: Option<u32>,
+    pub cadence: Option<Cadence>,
+}
+
+/// Get the ticks waited through `events`.
+fn ticks(events: &[Event]) -> u32 {
+    events
+        .iter()
+        .map(|ev| match ev {
+            Event::Wait { ticks } => *ticks,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Get the keys of the notes played after the last wait.
+fn last_onset(events: &[Event]) -> Vec<u8> {
+    let from = events
+        .iter()
+        .rposition(|ev| matches!(ev, Event::PlayNote { .. } | Event::PlayNoteTicks { .. }))
+        .and_then(|last| {
+            events[..last]
+                .iter()
+                .rposition(|ev| matches!(ev, Event::Wait { .. }))
+        })
+        .map_or(0, |wait| wait + 1);
+    events[from..]
+        .iter()
+        .filter_map(|ev| match ev {
+            Event::PlayNote { key, .. } | Event::PlayNoteTicks { key, .. } => Some(key.as_int()),
+            _ => None,
+        })
+        .collect()
+}
+
+impl Constraints {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether any constraint is on keys rather than time.
+    pub fn has_pitch(&self) -> bool {
+        self.scale.is_some() || self.range.is_some() || self.cadence.is_some()
+    }
+
+    /// Check the constraints can be met at all.
+    pub fn check(&self) -> Result<()> {
+        match (&self.cadence, self.scale) {
+            (Some(Cadence::Tonic), None) => Err(eyre!("a tonic cadence needs a scale")),
+            (Some(Cadence::Chord(c)), _) if c.is_empty() => Err(eyre!("empty cadence chord")),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn fits_key(&self, key: u8) -> bool {
+        self.scale.is_none_or(|s| s.contains(key)) && self.range.is_none_or(|r| r.contains(key))
+    }
+
+    /// Whether `next` may follow `so_far`, at `ticks_per_beat`.
+    pub fn allows(&self, so_far: &[Event], next: &[Event], ticks_per_beat: u32) -> bool {
+        let end = self.beats.map(|b| b * ticks_per_beat);
+        let mut at = ticks(so_far);
+        next.iter().all(|ev| match *ev {
+            Event::Wait { ticks } => {
+                at += ticks;
+                end.is_none_or(|end| at <= end)
+            }
+            Event::PlayNote { key, .. } | Event::PlayNoteTicks { key, .. } => {
+                self.fits_key(key.as_int()) && end.is_none_or(|end| at < end)
+            }
+            _ => true,
+        })
+    }
+
+    fn cadence_fits(&self, events: &[Event]) -> bool {
+        let last = last_onset(events);
+        match &self.cadence {
+            None => true,
+            Some(_) if last.is_empty() => false,
+            Some(Cadence::Tonic) => {
+                let tonic = self.scale.map_or(0, |s| s.tonic());
+                last.iter().any(|k| k % 12 == tonic)
+            }
+            Some(Cadence::Chord(chord)) => {
+                last.iter().all(|k| chord.contains(&(k % 12)))
+                    && last.iter().any(|k| k % 12 == chord[0])
+            }
+        }
+    }
+
+    /// Whether a phrase may end after `so_far` where the source did.
+    pub fn may_end(&self, so_far: &[Event]) -> bool {
+        self.beats.is_none() && self.cadence_fits(so_far)
+    }
+
+    /// Whether `so_far` is a whole phrase.
+    pub fn is_done(&self, so_far: &[Event], ticks_per_beat: u32) -> bool {
+        self.beats
+            .is_some_and(|b| ticks(so_far) == b * ticks_per_beat && self.cadence_fits(so_far))
+    }
+
+    /// Get the constraints on keys moved by `by` semitones.
+    pub fn transposed(&self, by: i16) -> Self {
+        let key = |k: u8| (k as i16 + by).clamp(0, 127) as u8;
+        Self {
+            scale: self.scale.map(|s| s.transposed(by)),
+            range: self.range.map(|r| KeyRange {
+                low: key(r.low),
+                high: key(r.high),
+            }),
+            beats: self.beats,
+            cadence: match &self.cadence {
+                Some(Cadence::Chord(chord)) => Some(Cadence::Chord(
+                    chord
+                        .iter()
+                        .map(|&pc| (pc as i16 + by).rem_euclid(12) as u8)
+                        .collect(),
+                )),
+                cadence => cadence.clone(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_constraint {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    fn constraints() -> Constraints {
+        Constraints {
+            scale: Some("c".parse().unwrap()),
+            range: Some("60-72".parse().unwrap()),
+            beats: Some(2),
+            cadence: Some(Cadence::Tonic),
+        }
+    }
+
+    #[test]
+    fn test_constraints() {
+        rspec::run(&rspec::describe("Constraints", Env::default(), |ctx| {
+            ctx.it("parses ranges and cadences", |_| {
+                assert_that!("40-76".parse::<KeyRange>().unwrap().high, eq(76));
+                assert_that!("76-40".parse::<KeyRange>(), err());
+                assert_that!("tonic".parse::<Cadence>().unwrap(), eq(Cadence::Tonic));
+                assert_that!(
+                    "g,b,d".parse::<Cadence>().unwrap(),
+                    eq(Cadence::Chord(vec![7, 11, 2]))
+                );
+                assert_that!("g,h".parse::<Cadence>(), err());
+            });
+
+            ctx.it("allows keys in the scale and range", |_| {
+                let c = constraints();
+                assert!(c.allows(&[], &[Event::play_ticks(62, 64, 1)], 2));
+                assert!(!c.allows(&[], &[Event::play_ticks(61, 64, 1)], 2));
+                assert!(!c.allows(&[], &[Event::play_ticks(74, 64, 1)], 2));
+            });
+
+            ctx.it("keeps phrases to length", |_| {
+                let c = constraints();
+                let so_far = [Event::play_ticks(62, 64, 1), Event::wait(3u32)];
+                assert!(c.allows(&so_far, &[Event::play_ticks(60, 64, 1)], 2));
+                assert!(!c.allows(&so_far, &[Event::wait(2u32)], 2));
+                let full = [Event::play_ticks(62, 64, 1), Event::wait(4u32)];
+                assert!(!c.allows(&full, &[Event::play_ticks(60, 64, 1)], 2));
+            });
+
+            ctx.it("ends on the cadence", |_| {
+                let c = constraints();
+                let on = |key: u8| [Event::play_ticks(key, 64, 1), Event::wait(4u32)];
+                assert!(c.is_done(&on(72), 2));
+                assert!(!c.is_done(&on(64), 2));
+                assert!(!c.may_end(&on(72)));
+                let chord = Constraints {
+                    cadence: Some("g,b,d".parse().unwrap()),
+                    ..Default::default()
+                };
+                let g = [
+                    Event::play_ticks(60, 64, 1),
+                    Event::wait(1u32),
+                    Event::play_ticks(67, 64, 1),
+                    Event::play_ticks(71, 64, 1),
+                ];
+                assert!(chord.may_end(&g));
+                assert!(!chord.may_end(&g[..2]));
+            });
+
+            ctx.it("checks a tonic cadence has a scale", |_| {
+                assert_that!(constraints().check(), ok());
+                let c = Constraints {
+                    cadence: Some(Cadence::Tonic),
+                    ..Default::default()
+                };
+                assert_that!(c.check(), err());
+            });
+
+            ctx.it("moves with the keys", |_| {
+                let c = constraints().transposed(2);
+                assert_that!(c.scale.unwrap().to_string(), eq("d-major"));
+                assert_that!(c.range, eq(Some(KeyRange { low: 62, high: 74 })));
+            });
+        }));
+    }
+}
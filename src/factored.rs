@@ -0,0 +1,496 @@
+use std::{collections::VecDeque, hash::Hash};
+
+use eyre::{eyre, Result};
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::{
+    chain::Chain,
+    constraint::Constraints,
+    generator::{Generator, Kind},
+    midi::MidiSequence,
+    model::{read_words, Model, Words},
+    sequence::{Dynamic, Event},
+};
+
+/// Ticks from a note's onset to the next, and its length in ticks if it
+/// has one.
+type Rhythm = (u32, Option<u32>);
+
+/// Chain orders of each stream of a `Factored` generator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Orders {
+    pub pitch: usize,
+    pub rhythm: usize,
+    pub velocity: usize,
+}
+
+impl Orders {
+    pub fn all(order: usize) -> Self {
+        Self {
+            pitch: order,
+            rhythm: order,
+            velocity: order,
+        }
+    }
+}
+
+/// A note of a sequence, split into what each stream learns.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Onset {
+    key: u8,
+    rhythm: Rhythm,
+    dynamic: Dynamic,
+}
+
+impl Words for Onset {
+    fn write(&self, out: &mut Vec<String>) {
+        self.key.write(out);
+        self.rhythm.write(out);
+        self.dynamic.write(out);
+    }
+
+    fn read<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Self> {
+        Ok(Self {
+            key: u8::read(words)?,
+            rhythm: Rhythm::read(words)?,
+            dynamic: Dynamic::read(words)?,
+        })
+    }
+}
+
+/// Get the notes of `events` in order, dropping anything else.
+fn onsets(events: &[Event]) -> Vec<Onset> {
+    let mut notes: Vec<(u32, u8, Option<u32>, Dynamic)> = Vec::new();
+    let mut at = 0;
+    for ev in events {
+        match *ev {
+            Event::PlayNote { key, dynamic } => notes.push((at, key.as_int(), None, dynamic)),
+            Event::PlayNoteTicks {
+                key,
+                dynamic,
+                ticks,
+                ..
+            } => notes.push((at, key.as_int(), Some(ticks), dynamic)),
+            Event::Wait { ticks } => at += ticks,
+            _ => {}
+        }
+    }
+    let next_at = notes
+        .iter()
+        .skip(1)
+        .map(|n| n.0)
+        .chain(std::iter::once(at))
+        .collect::<Vec<_>>();
+    notes
+        .iter()
+        .zip(next_at)
+        .map(|(&(at, key, ticks, dynamic), next)| Onset {
+            key,
+            rhythm: (next - at, ticks),
+            dynamic,
+        })
+        .collect()
+}
+
+/// One chain and where generation has got to in it.
+struct Stream<T> {
+    chain: Chain<T>,
+    history: Vec<Option<T>>,
+}
+
+impl<T: Clone + Eq + Hash> Stream<T> {
+    fn new(order: usize, backoff: bool) -> Self {
+        let order = order.max(1);
+        Self {
+            chain: match backoff {
+                true => Chain::with_backoff(order),
+                false => Chain::of_order(order),
+            },
+            history: vec![None; order],
+        }
+    }
+
+    fn load(model: &Model, name: &str) -> Result<Self>
+    where
+        T: Words,
+    {
+        let chain = Chain::load(model, name)?;
+        let history = vec![None; chain.order()];
+        Ok(Self { chain, history })
+    }
+
+    fn restart(&mut self) {
+        self.history = vec![None; self.chain.order()];
+    }
+
+    /// Sample the next token among those passing `require`, preferring
+    /// those passing `prefer` too. `None` at the end of a run, or if nothing
+    /// passes.
+    fn next(
+        &mut self,
+        rng: &mut StdRng,
+        require: impl Fn(&T) -> bool,
+        prefer: impl Fn(&T) -> bool,
+    ) -> Option<T> {
+        let next = self
+            .chain
+            .next_where(&self.history, rng, |t| {
+                t.as_ref().is_none_or(|t| require(t) && prefer(t))
+            })
+            .or_else(|| {
+                self.chain
+                    .next_where(&self.history, rng, |t| t.as_ref().is_none_or(&require))
+            })
+            .flatten();
+        self.history.remove(0);
+        self.history.push(next.clone());
+        next
+    }
+}
+
+/// Generates with separate chains for pitch, rhythm and dynamics, so each
+/// learns from fewer distinct tokens, and recombines them note by note.
+/// Cross-conditioned, rhythm follows the pitch just chosen and dynamics
+/// the rhythm.
+pub struct Factored {
+    orders: Orders,
+    cross: bool,
+    backoff: bool,
+    backwards: bool,
+    pitch: Stream<u8>,
+    rhythm: Stream<(Option<u8>, Rhythm)>,
+    dynamics: Stream<(Option<Rhythm>, Dynamic)>,
+    rng: StdRng,
+    // everything trained on, to train again at another order
+    trained: Vec<Vec<Onset>>,
+    ticks_per_beat: u32,
+    constraints: Constraints,
+    run: VecDeque<Event>,
+}
+
+impl Factored {
+    pub fn new(orders: Orders, rng: StdRng) -> Self {
+        Self {
+            orders,
+            cross: false,
+            backoff: false,
+            backwards: true,
+            pitch: Stream::new(orders.pitch, false),
+            rhythm: Stream::new(orders.rhythm, false),
+            dynamics: Stream::new(orders.velocity, false),
+            rng,
+            trained: Vec::new(),
+            ticks_per_beat: 12,
+            constraints: Constraints::default(),
+            run: VecDeque::new(),
+        }
+    }
+
+    /// Condition rhythm on pitch and dynamics on rhythm.
+    pub fn with_cross(mut self, cross: bool) -> Self {
+        self.cross = cross;
+        self.retrain();
+        self
+    }
+
+    /// Back off from each stream's order to shorter contexts where the
+    /// longer ones weren't seen.
+    pub fn with_backoff(mut self, backoff: bool) -> Self {
+        self.backoff = backoff;
+        self.retrain();
+        self
+    }
+
+    fn feed(&mut self, notes: &[Onset]) {
+        let cross = self.cross;
+        let passes = match self.backwards {
+            true => 2,
+            false => 1,
+        };
+        for notes in [notes.to_vec(), notes.iter().rev().copied().collect()]
+            .into_iter()
+            .take(passes)
+        {
+            self.pitch
+                .chain
+                .feed(notes.iter().map(|n| n.key).collect::<Vec<_>>());
+            self.rhythm.chain.feed(
+                notes
+                    .iter()
+                    .map(|n| (cross.then_some(n.key), n.rhythm))
+                    .collect::<Vec<_>>(),
+            );
+            self.dynamics.chain.feed(
+                notes
+                    .iter()
+                    .map(|n| (cross.then_some(n.rhythm), n.dynamic))
+                    .collect::<Vec<_>>(),
+            );
+        }
+    }
+
+    fn retrain(&mut self) {
+        self.pitch = Stream::new(self.orders.pitch, self.backoff);
+        self.rhythm = Stream::new(self.orders.rhythm, self.backoff);
+        self.dynamics = Stream::new(self.orders.velocity, self.backoff);
+        for notes in std::mem::take(&mut self.trained) {
+            self.feed(&notes);
+            self.trained.push(notes);
+        }
+        self.reset();
+    }
+
+    /// Read a generator written by `save`.
+    pub fn load(model: &Model, rng: StdRng) -> Result<Self> {
+        let (pitch, (rhythm, velocity)) = model.words("orders")?;
+        Ok(Self {
+            orders: Orders {
+                pitch,
+                rhythm,
+                velocity,
+            },
+            cross: model.parse("cross")?,
+            backoff: model.parse("backoff")?,
+            backwards: model.parse("backwards")?,
+            pitch: Stream::load(model, "pitch_chain")?,
+            rhythm: Stream::load(model, "rhythm_chain")?,
+            dynamics: Stream::load(model, "dynamics_chain")?,
+            rng,
+            trained: model
+                .all("trained")
+                .map(read_words)
+                .collect::<Result<_>>()?,
+            ticks_per_beat: model.parse("ticks_per_beat")?,
+            constraints: Constraints::default(),
+            run: VecDeque::new(),
+        })
+    }
+
+    /// Get the next note from each stream, or `None` if any has ended.
+    fn next_note(&mut self) -> Option<Onset> {
+        let fits = |k: &u8| self.constraints.fits_key(*k);
+        let key = self.pitch.next(&mut self.rng, fits, |_| true)?;
+        let by_key = self.cross.then_some(key);
+        let (_, rhythm) = self
+            .rhythm
+            .next(&mut self.rng, |_| true, |(k, _)| *k == by_key)?;
+        let by_rhythm = self.cross.then_some(rhythm);
+        let (_, dynamic) = self
+            .dynamics
+            .next(&mut self.rng, |_| true, |(r, _)| *r == by_rhythm)?;
+        Some(Onset {
+            key,
+            rhythm,
+            dynamic,
+        })
+    }
+}
+
+impl Generator for Factored {
+    fn train(&mut self, seq: &MidiSequence) {
+        self.ticks_per_beat = seq.ticks_per_beat();
+        let notes = onsets(&seq.events);
+        self.feed(&notes);
+        self.trained.push(notes);
+    }
+
+    fn reset(&mut self) {
+        self.pitch.restart();
+        self.rhythm.restart();
+        self.dynamics.restart();
+        self.run.clear();
+    }
+
+    fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+        self.reset();
+    }
+
+    fn set_order(&mut self, order: usize) {
+        self.orders = Orders::all(order.max(1));
+        self.retrain();
+    }
+
+    fn set_backwards(&mut self, backwards: bool) {
+        if self.backwards != backwards {
+            self.backwards = backwards;
+            self.retrain();
+        }
+    }
+
+    fn set_constraints(&mut self, constraints: Constraints) -> Result<()> {
+        constraints.check()?;
+        if constraints.beats.is_some() || constraints.cadence.is_some() {
+            return Err(eyre!(
+                "the factored generator only takes scale and range constraints"
+            ));
+        }
+        self.constraints = constraints;
+        self.reset();
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Option<Event> {
+        if self.run.is_empty() {
+            let note = match self.next_note() {
+                Some(note) => note,
+                None => {
+                    self.reset();
+                    self.next_note()?
+                }
+            };
+            let (wait, ticks) = note.rhythm;
+            self.run.push_back(match ticks {
+                Some(ticks) => Event::play_ticks(note.key, note.dynamic, ticks),
+                None => Event::play(note.key, note.dynamic),
+            });
+            if wait > 0 {
+                self.run.push_back(Event::wait(wait));
+            }
+        }
+        self.run.pop_front()
+    }
+
+    fn ticks_per_beat(&self) -> u32 {
+        self.ticks_per_beat
+    }
+
+    fn save(&self, model: &mut Model) {
+        let o = self.orders;
+        model.set_choice("generator", &Kind::Factored);
+        model.set_words("orders", &(o.pitch, (o.rhythm, o.velocity)));
+        model.set("cross", self.cross);
+        model.set("backoff", self.backoff);
+        model.set("backwards", self.backwards);
+        model.set("ticks_per_beat", self.ticks_per_beat);
+        self.pitch.chain.save(model, "pitch_chain");
+        self.rhythm.chain.save(model, "rhythm_chain");
+        self.dynamics.chain.save(model, "dynamics_chain");
+        for notes in &self.trained {
+            model.set_words("trained", notes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_factored {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    fn seq() -> MidiSequence {
+        MidiSequence::new(
+            vec![
+                Event::play_ticks(60, 100, 4),
+                Event::wait(4u32),
+                Event::play_ticks(62, 40, 1),
+                Event::wait(1u32),
+                Event::play_ticks(64, 40, 1),
+                Event::play_ticks(67, 40, 1),
+                Event::wait(1u32),
+                Event::play_ticks(65, 100, 4),
+                Event::wait(4u32),
+            ],
+            2,
+        )
+    }
+
+    fn factored(seed: u64, cross: bool) -> Factored {
+        let mut factored =
+            Factored::new(Orders::all(1), StdRng::seed_from_u64(seed)).with_cross(cross);
+        factored.train(&seq());
+        factored
+    }
+
+    #[test]
+    fn test_factored() {
+        rspec::run(&rspec::describe("Factored", Env::default(), |ctx| {
+            ctx.it("splits notes into streams", |_| {
+                let notes = onsets(&seq().events);
+                assert_that!(notes.len(), eq(5));
+                assert_that!(notes[0].rhythm, eq((4, Some(4))));
+                assert_that!(notes[2].rhythm, eq((0, Some(1))));
+                assert_that!(notes[4].rhythm, eq((4, Some(4))));
+            });
+
+            ctx.it("recombines what each stream learned", |_| {
+                let mut factored = factored(1, false);
+                let known = onsets(&seq().events);
+                for _ in 0..200 {
+                    let note = factored.next_note().unwrap_or_else(|| {
+                        factored.reset();
+                        factored.next_note().unwrap()
+                    });
+                    assert!(known.iter().any(|n| n.key == note.key));
+                    assert!(known.iter().any(|n| n.rhythm == note.rhythm));
+                    assert!(known.iter().any(|n| n.dynamic == note.dynamic));
+                }
+            });
+
+            ctx.it("conditions dynamics on rhythm", |_| {
+                let mut factored = factored(2, true);
+                let events = factored.take_beats(64);
+                let mut notes = 0;
+                for ev in events {
+                    if let Event::PlayNoteTicks { dynamic, ticks, .. } = ev {
+                        let loud = dynamic == Dynamic::from(100);
+                        assert_that!(loud, eq(ticks == 4));
+                        notes += 1;
+                    }
+                }
+                assert_that!(notes, gt(10));
+            });
+
+            ctx.it("is reproducible with a seed", |_| {
+                let (mut a, mut b) = (factored(3, false), factored(5, false));
+                b.set_seed(3);
+                assert_that!(a.take_beats(16), eq(b.take_beats(16)));
+            });
+
+            ctx.it("trains again at another order", |_| {
+                let mut factored = factored(1, true);
+                factored.set_order(2);
+                assert_that!(factored.orders, eq(Orders::all(2)));
+                assert!(factored.next_event().is_some());
+            });
+
+            ctx.it("keeps to the scale and range", |_| {
+                let mut factored = factored(1, false);
+                let constraints = Constraints {
+                    scale: Some("c".parse().unwrap()),
+                    range: Some("60-64".parse().unwrap()),
+                    ..Default::default()
+                };
+                factored.set_constraints(constraints.clone()).unwrap();
+                let events = factored.take_beats(32);
+                assert!(events.len() > 10);
+                for ev in events {
+                    if let Event::PlayNoteTicks { key, .. } = ev {
+                        assert!(constraints.fits_key(key.as_int()));
+                    }
+                }
+                let bars = Constraints {
+                    beats: Some(4),
+                    ..Default::default()
+                };
+                assert_that!(factored.set_constraints(bars), err());
+            });
+
+            ctx.it("saves and loads", |_| {
+                let mut model = Model::default();
+                factored(2, true).save(&mut model);
+                let model: Model = model.to_string().parse().unwrap();
+                let mut loaded = Factored::load(&model, StdRng::seed_from_u64(2)).unwrap();
+                assert_that!(loaded.take_beats(16), eq(factored(2, true).take_beats(16)));
+                assert!(loaded.cross);
+            });
+
+            ctx.it("generates nothing untrained", |_| {
+                let mut factored = Factored::new(Orders::all(2), StdRng::seed_from_u64(1));
+                assert_that!(factored.next_event(), none());
+            });
+        }));
+    }
+}
@@ -0,0 +1,473 @@
+use std::collections::VecDeque;
+
+use clap::ArgEnum;
+use eyre::{eyre, Result};
+use itertools::Itertools;
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::{
+    chain::Chain,
+    constraint::Constraints,
+    factored::Factored,
+    midi::MidiSequence,
+    model::{read_words, Model},
+    sequence::Event,
+    token::{tokenize, Token, Tokens},
+};
+
+/// Which `Generator` makes new material.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Kind {
+    /// One chain over whole tokens.
+    #[default]
+    Markov,
+    /// Separate chains for pitch, rhythm and velocity.
+    Factored,
+}
+
+/// Something that learns from sequences and plays new material in the same
+/// vein, one event at a time.
+pub trait Generator {
+    /// Learn from the events of `seq`.
+    fn train(&mut self, seq: &MidiSequence);
+
+    /// Start generating from the beginning again.
+    fn reset(&mut self);
+
+    /// Reseed and start again, to get a different take.
+    fn set_seed(&mut self, seed: u64);
+
+    /// Train again at another order and start again.
+    fn set_order(&mut self, order: usize);
+
+    /// Whether to learn each sequence backwards as well as forwards, which
+    /// is the default. Trains again if it changes.
+    fn set_backwards(&mut self, backwards: bool);
+
+    /// Only generate what fits `constraints` from now on, once trained.
+    fn set_constraints(&mut self, constraints: Constraints) -> Result<()>;
+
+    /// Get the next event, or `None` if nothing has been learned.
+    fn next_event(&mut self) -> Option<Event>;
+
+    /// Ticks per beat of the generated events.
+    fn ticks_per_beat(&self) -> u32;
+
+    /// Write what was learned, and how, into `model`.
+    fn save(&self, model: &mut Model);
+
+    /// Get what was learned as a Graphviz dot graph, if it is a graph.
+    fn dot(&self) -> Option<String> {
+        None
+    }
+
+    /// Get the next `beats` of events, cutting the last wait short to fit.
+    /// Fewer if the generator ends, or goes `MAX_EVENTS_WITHOUT_WAIT` events
+    /// without a wait.
+    fn take_beats(&mut self, beats: u32) -> Vec<Event> {
+        let end = beats * self.ticks_per_beat();
+        let mut ticks = 0;
+        let mut since_wait = 0;
+        let mut events = Vec::new();
+        while ticks < end && since_wait < MAX_EVENTS_WITHOUT_WAIT {
+            let event = match self.next_event() {
+                Some(Event::Wait { ticks: wait }) => {
+                    let wait = wait.min(end - ticks);
+                    ticks += wait;
+                    since_wait = 0;
+                    Event::wait(wait)
+                }
+                Some(event) => {
+                    since_wait += 1;
+                    event
+                }
+                None => break,
+            };
+            events.push(event);
+        }
+        events
+    }
+}
+
+/// Read a generator written by `Generator::save`, sampling with `rng`.
+pub fn load(model: &Model, rng: StdRng) -> Result<Box<dyn Generator + Send>> {
+    match model.choice("generator")? {
+        Kind::Markov => Ok(Box::new(Markov::load(model, rng)?)),
+        Kind::Factored => Ok(Box::new(Factored::load(model, rng)?)),
+    }
+}
+
+/// Events in a row without a wait after which a generator is taken to be
+/// stuck, e.g. one trained on a single chord that never moves on.
+pub const MAX_EVENTS_WITHOUT_WAIT: usize = 10_000;
+
+/// Samples to try for a run that fits the constraints before giving up.
+const BUDGET: usize = 100_000;
+
+/// Generates with a Markov chain fed each sequence forwards and backwards,
+/// in runs of `chunk_size` tokens.
+pub struct Markov {
+    order: usize,
+    backoff: bool,
+    backwards: bool,
+    chunk_size: usize,
+    tokens: Tokens,
+    chain: Chain<Token>,
+    rng: StdRng,
+    // everything trained on, to train again at another order
+    trained: Vec<Vec<Event>>,
+    ticks_per_beat: u32,
+    constraints: Constraints,
+    run: VecDeque<Event>,
+}
+
+impl Markov {
+    pub fn new(order: usize, rng: StdRng) -> Self {
+        Self {
+            order,
+            backoff: false,
+            backwards: true,
+            chunk_size: usize::MAX,
+            tokens: Tokens::default(),
+            chain: Chain::of_order(order),
+            rng,
+            trained: Vec::new(),
+            ticks_per_beat: 12,
+            constraints: Constraints::default(),
+            run: VecDeque::new(),
+        }
+    }
+
+    /// Feed the chain runs of at most `chunk_size` tokens.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Back off from `order` to shorter contexts where the longer ones
+    /// weren't seen.
+    pub fn with_backoff(mut self, backoff: bool) -> Self {
+        self.backoff = backoff;
+        self.chain = self.new_chain();
+        self
+    }
+
+    /// Read a generator written by `save`.
+    pub fn load(model: &Model, rng: StdRng) -> Result<Self> {
+        let chain = Chain::load(model, "chain")?;
+        let order: usize = model.parse("order")?;
+        if chain.order() != order {
+            return Err(eyre!(
+                "model chain is order {}, not {}",
+                chain.order(),
+                order
+            ));
+        }
+        Ok(Self {
+            order,
+            backoff: model.parse("backoff")?,
+            backwards: model.parse("backwards")?,
+            chunk_size: model.parse("chunk_size")?,
+            tokens: model.choice("tokens")?,
+            chain,
+            rng,
+            trained: model
+                .all("trained")
+                .map(read_words)
+                .collect::<Result<_>>()?,
+            ticks_per_beat: model.parse("ticks_per_beat")?,
+            constraints: Constraints::default(),
+            run: VecDeque::new(),
+        })
+    }
+
+    /// Generate a run that fits the constraints, if one can be found.
+    fn constrained(&mut self) -> Option<Vec<Token>> {
+        let (c, tpb) = (&self.constraints, self.ticks_per_beat);
+        let events = |run: &[Token]| run.iter().flat_map(Token::events).collect::<Vec<_>>();
+        self.chain.generate_where(
+            &mut self.rng,
+            BUDGET,
+            |run, next| match next {
+                Some(token) => c.allows(&events(run), &token.events(), tpb),
+                None => c.may_end(&events(run)),
+            },
+            |run| c.is_done(&events(run), tpb),
+        )
+    }
+
+    fn new_chain(&self) -> Chain<Token> {
+        match self.backoff {
+            true => Chain::with_backoff(self.order),
+            false => Chain::of_order(self.order),
+        }
+    }
+
+    /// Set how sequences are split into tokens.
+    pub fn with_tokens(mut self, tokens: Tokens) -> Self {
+        self.tokens = tokens;
+        self
+    }
+
+    fn feed(&mut self, events: &[Event]) {
+        let tokens = tokenize(events, self.tokens);
+        let backwards = self.backwards;
+        let iter = tokens.iter().cloned();
+        let rev_iter = iter.clone().rev().filter(move |_| backwards);
+        let iter = iter.chain(rev_iter);
+        // let quieter = iter.clone().map(|e| {
+        //     if let Event::PlayNote { key, dynamic } = e {
+        //         Event::play(key, dynamic.down())
+        //     } else {
+        //         e
+        //     }
+        // });
+        // let iter = iter.chain(quieter);
+        for chunk in &iter.chunks(self.chunk_size) {
+            let tokens = chunk.collect::<Vec<_>>();
+            self.chain.feed(tokens);
+        }
+    }
+
+    fn retrain(&mut self) {
+        self.chain = self.new_chain();
+        for events in std::mem::take(&mut self.trained) {
+            self.feed(&events);
+            self.trained.push(events);
+        }
+        self.reset();
+    }
+}
+
+impl Generator for Markov {
+    fn train(&mut self, seq: &MidiSequence) {
+        self.ticks_per_beat = seq.ticks_per_beat();
+        self.feed(&seq.events);
+        self.trained.push(seq.events.clone());
+    }
+
+    fn reset(&mut self) {
+        self.run.clear();
+    }
+
+    fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+        self.reset();
+    }
+
+    fn set_order(&mut self, order: usize) {
+        self.order = order.max(1);
+        self.retrain();
+    }
+
+    fn set_backwards(&mut self, backwards: bool) {
+        if self.backwards != backwards {
+            self.backwards = backwards;
+            self.retrain();
+        }
+    }
+
+    fn set_constraints(&mut self, constraints: Constraints) -> Result<()> {
+        constraints.check()?;
+        self.constraints = constraints;
+        self.reset();
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Option<Event> {
+        // a backed off chain can end a run as soon as it starts
+        while self.run.is_empty() && !self.chain.is_empty() {
+            let tokens = match self.constraints.is_empty() {
+                true => self.chain.generate(&mut self.rng),
+                false => self.constrained()?,
+            };
+            self.run = tokens.iter().flat_map(Token::events).collect();
+        }
+        self.run.pop_front()
+    }
+
+    fn ticks_per_beat(&self) -> u32 {
+        self.ticks_per_beat
+    }
+
+    /// The chain is saved so loading skips training, and what it was
+    /// trained on so it can train again at another order.
+    fn save(&self, model: &mut Model) {
+        model.set_choice("generator", &Kind::Markov);
+        model.set("order", self.order);
+        model.set("backoff", self.backoff);
+        model.set("backwards", self.backwards);
+        model.set("chunk_size", self.chunk_size);
+        model.set_choice("tokens", &self.tokens);
+        model.set("ticks_per_beat", self.ticks_per_beat);
+        self.chain.save(model, "chain");
+        for events in &self.trained {
+            model.set_words("trained", events);
+        }
+    }
+
+    fn dot(&self) -> Option<String> {
+        let graph = self.chain.graph();
+        Some(format!(
+            "{:?}",
+            petgraph::dot::Dot::with_config(&graph, &[])
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test_generator {
+    use super::*;
+    use crate::constraint::Cadence;
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    fn seq() -> MidiSequence {
+        MidiSequence::new(
+            vec![
+                Event::play_ticks(60, 64, 1),
+                Event::wait(2u32),
+                Event::play_ticks(62, 64, 1),
+                Event::wait(3u32),
+                Event::play_ticks(64, 64, 1),
+                Event::wait(2u32),
+            ],
+            2,
+        )
+    }
+
+    fn markov(seed: u64) -> Markov {
+        let mut markov = Markov::new(1, StdRng::seed_from_u64(seed));
+        markov.train(&seq());
+        markov
+    }
+
+    #[test]
+    fn test_markov() {
+        rspec::run(&rspec::describe("Markov", Env::default(), |ctx| {
+            ctx.it("generates events it was trained on", |_| {
+                let mut markov = markov(1);
+                let known = seq().events;
+                for _ in 0..50 {
+                    assert!(known.contains(&markov.next_event().unwrap()));
+                }
+            });
+
+            ctx.it("is reproducible with a seed", |_| {
+                let (mut a, mut b) = (markov(3), markov(5));
+                b.set_seed(3);
+                assert_that!(a.take_beats(16), eq(b.take_beats(16)));
+            });
+
+            ctx.it("takes whole beats", |_| {
+                let events = markov(1).take_beats(5);
+                let ticks: u32 = events
+                    .iter()
+                    .map(|e| match e {
+                        Event::Wait { ticks } => *ticks,
+                        _ => 0,
+                    })
+                    .sum();
+                assert_that!(ticks, eq(10));
+            });
+
+            ctx.it("trains again at another order", |_| {
+                let mut markov = markov(1);
+                markov.set_order(3);
+                assert!(markov.next_event().is_some());
+                assert!(markov.dot().unwrap().contains("digraph"));
+            });
+
+            ctx.it("backs off to shorter contexts", |_| {
+                let mut markov = Markov::new(4, StdRng::seed_from_u64(1)).with_backoff(true);
+                markov.train(&seq());
+                let known = seq().events;
+                let events = markov.take_beats(32);
+                assert!(events
+                    .iter()
+                    .all(|ev| matches!(ev, Event::Wait { .. }) || known.contains(ev)));
+                assert_that!(events.len(), gt(6));
+            });
+
+            ctx.it("generates within constraints", |_| {
+                let mut markov = markov(1);
+                markov
+                    .set_constraints(Constraints {
+                        range: Some("60-62".parse().unwrap()),
+                        beats: Some(4),
+                        cadence: Some(Cadence::Chord(vec![2])),
+                        ..Default::default()
+                    })
+                    .unwrap();
+                for _ in 0..3 {
+                    let events = markov.take_beats(4);
+                    let keys = events
+                        .iter()
+                        .filter_map(|ev| match ev {
+                            Event::PlayNoteTicks { key, .. } => Some(key.as_int()),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+                    assert!(keys.iter().all(|&k| k == 60 || k == 62));
+                    assert_that!(keys.last(), eq(Some(&62)));
+                    // phrases are exactly 4 beats, so the next starts on the beat
+                    assert!(matches!(events.last(), Some(Event::Wait { .. })));
+                }
+                let fits = markov.set_constraints(Constraints {
+                    cadence: Some(Cadence::Tonic),
+                    ..Default::default()
+                });
+                assert_that!(fits, err());
+            });
+
+            ctx.it("keeps chords whole", |_| {
+                let chord = [Event::play_ticks(60, 64, 2), Event::play_ticks(64, 64, 2)];
+                let mut seq = seq();
+                seq.events.splice(0..1, chord);
+                let mut markov =
+                    Markov::new(1, StdRng::seed_from_u64(2)).with_tokens(Tokens::Chords);
+                markov.train(&seq);
+                let events = markov.take_beats(32);
+                for (i, ev) in events.iter().enumerate() {
+                    if *ev == chord[0] {
+                        assert_that!(events.get(i + 1), eq(Some(&chord[1])));
+                    }
+                }
+                assert!(events.contains(&chord[0]));
+            });
+
+            ctx.it("saves and loads", |_| {
+                let mut markov = Markov::new(2, StdRng::seed_from_u64(1))
+                    .with_tokens(Tokens::Intervals)
+                    .with_backoff(true);
+                markov.train(&seq());
+                let mut model = Model::default();
+                markov.save(&mut model);
+                let model: Model = model.to_string().parse().unwrap();
+                let mut loaded = load(&model, StdRng::seed_from_u64(1)).unwrap();
+                let mut markov = Markov::new(2, StdRng::seed_from_u64(1))
+                    .with_tokens(Tokens::Intervals)
+                    .with_backoff(true);
+                markov.train(&seq());
+                assert_that!(loaded.take_beats(16), eq(markov.take_beats(16)));
+                loaded.set_order(1);
+                assert!(loaded.next_event().is_some());
+            });
+
+            ctx.it("stops taking beats from a source with no waits", |_| {
+                let mut markov = Markov::new(1, StdRng::seed_from_u64(1));
+                let chord = vec![Event::play_ticks(60, 64, 4), Event::play_ticks(64, 64, 4)];
+                markov.train(&MidiSequence::new(chord, 2));
+                assert_that!(markov.take_beats(4).len(), eq(MAX_EVENTS_WITHOUT_WAIT));
+            });
+
+            ctx.it("generates nothing untrained", |_| {
+                let mut markov = Markov::new(2, StdRng::seed_from_u64(1));
+                assert_that!(markov.next_event(), none());
+                assert!(markov.take_beats(4).is_empty());
+            });
+        }));
+    }
+}
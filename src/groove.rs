@@ -0,0 +1,299 @@
+use std::{fmt::Display, fs, str::FromStr};
+
+use eyre::{bail, ensure, eyre, Result};
+
+use crate::{midi, sequence::Event};
+
+/// Beats in a bar unless told otherwise, for grooves and accents.
+pub const DEFAULT_BEATS_PER_BAR: u32 = 4;
+/// Ticks per beat used when reading a reference file.
+const EXTRACT_TICKS_PER_BEAT: u32 = 480;
+const HEADER: &str = "groove v1";
+
+/// How one step of a bar is pushed or pulled.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Step {
+    /// Timing shift in beats.
+    pub shift: f64,
+    pub vel: i8,
+}
+
+/// Deterministic timing and velocity offsets per step of a bar.
+///
+/// Stored as text, e.g. for straight 8ths with a heavy downbeat:
+///
+/// ```text
+/// groove v1
+/// steps_per_beat 2
+/// beats_per_bar 4
+/// 0 10
+/// 0 0
+/// ...
+/// ```
+///
+/// with one `shift vel` line per step of a bar. Files without
+/// `beats_per_bar` are in 4/4.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Groove {
+    steps_per_beat: u32,
+    steps: Vec<Step>,
+}
+
+// the number of steps in a bar
+fn bar_steps(steps_per_beat: u32, beats_per_bar: u32) -> Result<usize> {
+    ensure!(steps_per_beat > 0, "steps_per_beat must be positive");
+    ensure!(beats_per_bar > 0, "beats_per_bar must be positive");
+    let steps = steps_per_beat
+        .checked_mul(beats_per_bar)
+        .ok_or_else(|| eyre!("too many steps in a bar: {}", steps_per_beat))?;
+    Ok(steps as usize)
+}
+
+impl Groove {
+    /// Check there is a step for every step of a bar, each shifted by no
+    /// more than one step.
+    fn new(steps_per_beat: u32, beats_per_bar: u32, steps: Vec<Step>) -> Result<Self> {
+        let len = bar_steps(steps_per_beat, beats_per_bar)?;
+        ensure!(
+            steps.len() == len,
+            "expected {} steps, got {}",
+            len,
+            steps.len()
+        );
+        let max_shift = 1.0 / steps_per_beat as f64;
+        for step in &steps {
+            ensure!(
+                step.shift.is_finite() && step.shift.abs() <= max_shift,
+                "a step can't shift by more than one step: {}",
+                step.shift
+            );
+        }
+        Ok(Self {
+            steps_per_beat,
+            steps,
+        })
+    }
+
+    /// Push every other step later. 50% is straight and 66% is a triplet
+    /// feel.
+    pub fn swing(percent: f64, steps_per_beat: u32) -> Result<Self> {
+        ensure!(
+            (0.0..=100.0).contains(&percent),
+            "swing must be 0-100%, got {}",
+            percent
+        );
+        let shift = (percent / 100.0 - 0.5) * 2.0 / steps_per_beat.max(1) as f64;
+        let steps = (0..bar_steps(steps_per_beat, DEFAULT_BEATS_PER_BAR)?)
+            .map(|i| Step {
+                shift: if i % 2 == 1 { shift } else { 0.0 },
+                vel: 0,
+            })
+            .collect();
+        Self::new(steps_per_beat, DEFAULT_BEATS_PER_BAR, steps)
+    }
+
+    /// Learn a groove from how far notes in `tracks` of a reference file
+    /// land from the grid and how loud they are at each step of a bar.
+    pub fn extract(
+        data: &[u8],
+        tracks: &[usize],
+        steps_per_beat: u32,
+        beats_per_bar: u32,
+    ) -> Result<Self> {
+        let len = bar_steps(steps_per_beat, beats_per_bar)?;
+        let seq = midi::Parser::default()
+            .with_ticks_per_beat(EXTRACT_TICKS_PER_BEAT)
+            .parse_tracks(data, tracks)?;
+        let tpb = seq.ticks_per_beat() as f64;
+        // (sum of shifts, sum of velocities, count) per step
+        let mut sums = vec![(0.0, 0.0, 0u32); len];
+        let mut tick = 0;
+        for ev in &seq.events {
+            match *ev {
+                Event::Wait { ticks } => tick += ticks,
+                Event::PlayNote { dynamic, .. } | Event::PlayNoteTicks { dynamic, .. } => {
+                    let beats = tick as f64 / tpb;
+                    let step = (beats * steps_per_beat as f64).round();
+                    let sum = &mut sums[step as usize % len];
+                    sum.0 += beats - step / steps_per_beat as f64;
+                    sum.1 += dynamic.vel() as f64;
+                    sum.2 += 1;
+                }
+                _ => {}
+            }
+        }
+        let count: u32 = sums.iter().map(|s| s.2).sum();
+        ensure!(count > 0, "no notes to extract a groove from");
+        let mean_vel = sums.iter().map(|s| s.1).sum::<f64>() / count as f64;
+        let steps = sums
+            .iter()
+            .map(|&(shift, vel, n)| match n {
+                0 => Step::default(),
+                _ => Step {
+                    shift: shift / n as f64,
+                    vel: (vel / n as f64 - mean_vel).round() as i8,
+                },
+            })
+            .collect();
+        Self::new(steps_per_beat, beats_per_bar, steps)
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    /// Get the step a note at `tick` falls on, if it is on the grid, in
+    /// bars of `beats_per_bar`. Bars shorter than the groove's use its
+    /// first steps, and longer ones go round it again.
+    pub fn step_at(&self, tick: u32, ticks_per_beat: u32, beats_per_bar: u32) -> Option<Step> {
+        let ticks = tick as u64 * self.steps_per_beat as u64;
+        if !ticks.is_multiple_of(ticks_per_beat as u64) {
+            return None;
+        }
+        let bar = self.steps_per_beat as u64 * beats_per_bar.max(1) as u64;
+        let i = (ticks / ticks_per_beat as u64 % bar) as usize % self.steps.len();
+        Some(self.steps[i])
+    }
+
+    fn beats_per_bar(&self) -> usize {
+        self.steps.len() / self.steps_per_beat as usize
+    }
+}
+
+impl Display for Groove {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "steps_per_beat {}", self.steps_per_beat)?;
+        writeln!(f, "beats_per_bar {}", self.beats_per_bar())?;
+        for step in &self.steps {
+            writeln!(f, "{} {}", step.shift, step.vel)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Groove {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .peekable();
+        ensure!(lines.next() == Some(HEADER), "not a groove file");
+        let steps_per_beat: u32 = match lines.next().map(|l| l.split_once(' ')) {
+            Some(Some(("steps_per_beat", n))) => n.parse()?,
+            _ => bail!("missing steps_per_beat"),
+        };
+        let beats_per_bar = match lines.peek().and_then(|l| l.split_once(' ')) {
+            Some(("beats_per_bar", n)) => {
+                let n = n.parse()?;
+                lines.next();
+                n
+            }
+            _ => DEFAULT_BEATS_PER_BAR,
+        };
+        let steps = lines
+            .map(|l| {
+                let (shift, vel) = l
+                    .split_once(' ')
+                    .ok_or_else(|| eyre!("bad groove step: {}", l))?;
+                Ok(Step {
+                    shift: shift.parse()?,
+                    vel: vel.trim().parse()?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(steps_per_beat, beats_per_bar, steps)
+    }
+}
+
+#[cfg(test)]
+mod test_groove {
+    use super::*;
+    use crate::output::{Output, SmfRecorder};
+    use hamcrest2::prelude::*;
+    use std::time::Duration;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    #[test]
+    fn test_groove() {
+        rspec::run(&rspec::describe("Groove", Env::default(), |ctx| {
+            ctx.it("swings off-beats", |_| {
+                let groove = Groove::swing(75.0, 2).unwrap();
+                assert_that!(groove.step_at(0, 12, 4).unwrap().shift, eq(0.0));
+                assert_that!(groove.step_at(6, 12, 4).unwrap().shift, eq(0.25));
+                assert_that!(groove.step_at(3, 12, 4), none());
+            });
+
+            ctx.it("extracts a groove from a reference file", |_| {
+                let mut rec = SmfRecorder::new("unused.mid", 60.0);
+                for (ms, vel) in [(0, 112), (550, 40), (1000, 112), (1550, 40)] {
+                    rec.send(Duration::from_millis(ms), &[0x90, 60, vel])
+                        .unwrap();
+                }
+                let mut data = Vec::new();
+                rec.smf().write_std(&mut data).unwrap();
+                let groove = Groove::extract(&data, &[0], 2, 4).unwrap();
+                assert_that!(
+                    groove.steps[0],
+                    eq(Step {
+                        shift: 0.0,
+                        vel: 36
+                    })
+                );
+                assert_that!((groove.steps[1].shift - 0.05).abs(), lt(0.001));
+                assert_that!(groove.steps[1].vel, eq(-36));
+            });
+
+            ctx.it("round trips through text", |_| {
+                let mut groove = Groove::swing(60.0, 4).unwrap();
+                groove.steps[0].vel = 12;
+                let parsed: Groove = groove.to_string().parse().unwrap();
+                assert_that!(parsed, eq(groove));
+            });
+
+            ctx.it("follows the bar length", |_| {
+                let mut groove = Groove::new(1, 3, vec![Step::default(); 3]).unwrap();
+                groove.steps[0].vel = 10;
+                assert_that!(groove.step_at(3, 1, 3).unwrap().vel, eq(10));
+                assert_that!(groove.step_at(3, 1, 4).unwrap().vel, eq(10));
+                assert_that!(groove.step_at(5, 1, 4).unwrap().vel, eq(0));
+                let parsed: Groove = groove.to_string().parse().unwrap();
+                assert_that!(parsed, eq(groove));
+            });
+
+            ctx.it("needs at least one step per beat", |_| {
+                assert_that!(Groove::swing(60.0, 0), err());
+                assert_that!(Groove::extract(&[], &[0], 0, 4), err());
+                assert_that!(Groove::swing(60.0, u32::MAX), err());
+            });
+
+            ctx.it("rejects swing and shifts out of range", |_| {
+                assert_that!(Groove::swing(f64::NAN, 1), err());
+                assert_that!(Groove::swing(1e30, 1), err());
+                assert_that!(Groove::swing(100.0, 1), ok());
+                let text =
+                    |shift| format!("groove v1\nsteps_per_beat 1\n{} 0\n0 0\n0 0\n0 0\n", shift);
+                assert_that!(text("0.5").parse::<Groove>(), ok());
+                assert_that!(text("NaN").parse::<Groove>(), err());
+                assert_that!(text("1.5").parse::<Groove>(), err());
+            });
+
+            ctx.it("rejects the wrong number of steps", |_| {
+                assert_that!(
+                    "groove v1\nsteps_per_beat 1\n0 0\n".parse::<Groove>(),
+                    err()
+                );
+            });
+        }));
+    }
+}
@@ -0,0 +1,219 @@
+use clap::ArgEnum;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::groove::DEFAULT_BEATS_PER_BAR;
+
+/// How random offsets are drawn for timing and velocity.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Model {
+    /// Uniform in ±range/2.
+    #[default]
+    Uniform,
+    /// Gaussian centred on zero with ~95% of values in ±range/2.
+    Gaussian,
+    /// A slow random walk that stays within ±range/2.
+    Drift,
+}
+
+/// Number of steps the drift model takes to cross its whole range.
+const DRIFT_STEPS: f64 = 16.0;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Walk {
+    pos: f64,
+}
+
+impl Walk {
+    fn step(&mut self, rng: &mut StdRng, range: f64) -> f64 {
+        let half = range / 2.0;
+        if half <= 0.0 {
+            return 0.0;
+        }
+        let step = range / DRIFT_STEPS;
+        self.pos = (self.pos + rng.gen_range(-step..=step)).clamp(-half, half);
+        self.pos
+    }
+}
+
+/// Produces timing and velocity offsets for the `Player`.
+pub struct Humanizer {
+    model: Model,
+    rng: StdRng,
+    ms_range: f64,
+    vel_range: f64,
+    // extra velocity added on beats, the full amount on the first beat of a bar
+    accent: f64,
+    beats_per_bar: u32,
+    ms_walk: Walk,
+    vel_walk: Walk,
+}
+
+impl Humanizer {
+    pub fn new(ms_range: f64, vel_range: f64) -> Self {
+        Self {
+            model: Model::default(),
+            rng: StdRng::from_entropy(),
+            ms_range,
+            vel_range,
+            accent: 0.0,
+            beats_per_bar: DEFAULT_BEATS_PER_BAR,
+            ms_walk: Walk::default(),
+            vel_walk: Walk::default(),
+        }
+    }
+
+    /// Set the humanizer's model.
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+    }
+
+    /// Reseed the humanizer's random number generator.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+        self.ms_walk = Walk::default();
+        self.vel_walk = Walk::default();
+    }
+
+    /// Set the humanizer's ms range.
+    pub fn set_ms_range(&mut self, ms_range: f64) {
+        self.ms_range = ms_range;
+    }
+
+    /// Set the humanizer's vel range.
+    pub fn set_vel_range(&mut self, vel_range: f64) {
+        self.vel_range = vel_range;
+    }
+
+    /// Set the humanizer's accent velocity.
+    pub fn set_accent(&mut self, accent: f64) {
+        self.accent = accent;
+    }
+
+    /// Set the number of beats between full accents.
+    pub fn set_beats_per_bar(&mut self, beats_per_bar: u32) {
+        self.beats_per_bar = beats_per_bar.max(1);
+    }
+
+    fn gaussian(&mut self, range: f64) -> f64 {
+        // Box-Muller, sigma = range / 4
+        let u1: f64 = 1.0 - self.rng.gen::<f64>();
+        let u2: f64 = self.rng.gen();
+        let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+        z * range / 4.0
+    }
+
+    fn uniform(&mut self, range: f64) -> f64 {
+        let half = range / 2.0;
+        if half <= 0.0 {
+            return 0.0;
+        }
+        self.rng.gen_range(-half..=half)
+    }
+
+    /// Get the next timing offset in ms.
+    pub fn ms(&mut self) -> f64 {
+        let range = self.ms_range;
+        match self.model {
+            Model::Uniform => self.uniform(range),
+            Model::Gaussian => self.gaussian(range),
+            Model::Drift => self.ms_walk.step(&mut self.rng, range),
+        }
+    }
+
+    /// Get the next velocity offset for a note starting at `tick`.
+    pub fn vel(&mut self, tick: u32, ticks_per_beat: u32) -> i8 {
+        let range = self.vel_range;
+        let jitter = match self.model {
+            Model::Uniform => self.uniform(range),
+            Model::Gaussian => self.gaussian(range),
+            Model::Drift => self.vel_walk.step(&mut self.rng, range),
+        };
+        (jitter + self.accent_at(tick, ticks_per_beat))
+            .round()
+            .clamp(i8::MIN as f64, i8::MAX as f64) as i8
+    }
+
+    fn accent_at(&self, tick: u32, ticks_per_beat: u32) -> f64 {
+        if ticks_per_beat == 0 || !tick.is_multiple_of(ticks_per_beat) {
+            return 0.0;
+        }
+        match (tick / ticks_per_beat) % self.beats_per_bar {
+            0 => self.accent,
+            _ => self.accent / 2.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_human {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    fn humanizer(model: Model) -> Humanizer {
+        let mut h = Humanizer::new(30.0, 12.0);
+        h.set_model(model);
+        h.set_seed(42);
+        h
+    }
+
+    #[test]
+    fn test_humanizer() {
+        rspec::run(&rspec::describe("Humanizer", Env::default(), |ctx| {
+            [
+                ("uniform is centred on zero", Model::Uniform),
+                ("gaussian is centred on zero", Model::Gaussian),
+                ("drift is centred on zero", Model::Drift),
+            ]
+            .iter()
+            .for_each(|&(name, model)| {
+                ctx.it(name, move |_| {
+                    let mut h = humanizer(model);
+                    let n = 10_000;
+                    let mean = (0..n).map(|_| h.ms()).sum::<f64>() / n as f64;
+                    assert_that!(mean.abs(), lt(3.0));
+                });
+            });
+
+            ctx.it("uniform stays within ±range/2", |_| {
+                let mut h = humanizer(Model::Uniform);
+                assert!((0..1000).map(|_| h.ms()).all(|ms| ms.abs() <= 15.0));
+            });
+
+            ctx.it("drift moves in small steps", |_| {
+                let mut h = humanizer(Model::Drift);
+                let steps: Vec<f64> = (0..1000).map(|_| h.ms()).collect();
+                assert!(steps
+                    .windows(2)
+                    .all(|w| (w[1] - w[0]).abs() <= 30.0 / DRIFT_STEPS));
+                assert!(steps.iter().all(|ms| ms.abs() <= 15.0));
+            });
+
+            ctx.it("is reproducible with a seed", |_| {
+                let (mut a, mut b) = (humanizer(Model::Gaussian), humanizer(Model::Gaussian));
+                let a: Vec<f64> = (0..10).map(|_| a.ms()).collect();
+                let b: Vec<f64> = (0..10).map(|_| b.ms()).collect();
+                assert_that!(a, eq(b));
+            });
+
+            ctx.it("accents beats by bar position", |_| {
+                let mut h = Humanizer::new(0.0, 0.0);
+                h.set_accent(10.0);
+                assert_that!(h.vel(0, 12), eq(10));
+                assert_that!(h.vel(12, 12), eq(5));
+                assert_that!(h.vel(6, 12), eq(0));
+                assert_that!(h.vel(48, 12), eq(10));
+            });
+
+            ctx.it("accents the first beat of shorter bars", |_| {
+                let mut h = Humanizer::new(0.0, 0.0);
+                h.set_accent(10.0);
+                h.set_beats_per_bar(3);
+                assert_that!(h.vel(36, 12), eq(10));
+                assert_that!(h.vel(48, 12), eq(5));
+            });
+        }));
+    }
+}
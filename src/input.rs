@@ -0,0 +1,265 @@
+use std::time::{Duration, Instant};
+
+use eyre::{eyre, Result};
+use midir::{MidiInput, MidiInputConnection, MidiInputPort};
+use midly::{live::LiveEvent, MidiMessage};
+
+use crate::{
+    midi::MidiSequence,
+    output::{Capture, Output, Sent, SmfRecorder},
+    sequence::{Event, DEFAULT_RELEASE},
+};
+
+/// Find the input port called `port_name`.
+pub fn find_port(midi_in: &MidiInput, port_name: &str) -> Result<MidiInputPort> {
+    midi_in
+        .ports()
+        .into_iter()
+        .find(|p| midi_in.port_name(p).is_ok_and(|name| name == port_name))
+        .ok_or_else(|| eyre!("could not find input port {}", port_name))
+}
+
+/// Send everything played on an input port to `out`, timed from when
+/// listening starts. With `virtual_port` a new port called `port_name` is
+/// made for other programs to play into instead.
+pub fn listen(
+    client_name: &str,
+    port_name: &str,
+    virtual_port: bool,
+    out: impl Output + 'static,
+) -> Result<MidiInputConnection<()>> {
+    let midi_in = MidiInput::new(client_name)?;
+    let start = Instant::now();
+    let mut out = out;
+    let callback = move |_: u64, msg: &[u8], _: &mut ()| {
+        if let Err(e) = out.send(start.elapsed(), msg) {
+            eprintln!("failed to record message: {}", e);
+        }
+    };
+    if virtual_port {
+        return connect_virtual(midi_in, port_name, callback);
+    }
+    let port = find_port(&midi_in, port_name)?;
+    midi_in
+        .connect(&port, "record", callback, ())
+        .map_err(|e| eyre!("could not connect to {}: {}", port_name, e))
+}
+
+#[cfg(unix)]
+fn connect_virtual(
+    midi_in: MidiInput,
+    port_name: &str,
+    callback: impl FnMut(u64, &[u8], &mut ()) + Send + 'static,
+) -> Result<MidiInputConnection<()>> {
+    use midir::os::unix::VirtualInput;
+
+    midi_in
+        .create_virtual(port_name, callback, ())
+        .map_err(|e| eyre!("could not create input port {}: {}", port_name, e))
+}
+
+#[cfg(not(unix))]
+fn connect_virtual(
+    _midi_in: MidiInput,
+    _port_name: &str,
+    _callback: impl FnMut(u64, &[u8], &mut ()) + Send + 'static,
+) -> Result<MidiInputConnection<()>> {
+    Err(eyre!("virtual input ports are not supported here"))
+}
+
+/// Records a take of notes, controllers and pedal sent to it, e.g. by
+/// `listen`. Clones share the same take so one can be handed to the input.
+///
+/// Silence before the first message is left out, and every channel is
+/// recorded as one like `midi::Parser` does by default.
+#[derive(Clone)]
+pub struct Recorder {
+    take: Capture,
+    tempo: f32,
+    ticks_per_beat: u32,
+}
+
+impl Recorder {
+    pub fn new(tempo: f32, ticks_per_beat: u32) -> Self {
+        Self {
+            take: Capture::new(),
+            tempo,
+            ticks_per_beat,
+        }
+    }
+
+    /// Get the take in order, starting at the first message.
+    fn take(&self) -> Vec<Sent> {
+        let mut take = self.take.sent();
+        take.sort_by_key(|s| s.at);
+        let first = take.first().map_or(Duration::ZERO, |s| s.at);
+        take.iter_mut().for_each(|s| s.at -= first);
+        take
+    }
+
+    fn ticks(&self, at: Duration) -> u32 {
+        (at.as_secs_f64() * self.tempo as f64 / 60.0 * self.ticks_per_beat as f64).round() as u32
+    }
+
+    /// Get the take as a sequence of notes with lengths. Program changes
+    /// are kept out of the events like `midi::Parser` does, with only the
+    /// first to send before playing.
+    pub fn sequence(&self) -> MidiSequence {
+        use MidiMessage::*;
+
+        let take = self.take();
+        let end = take.last().map_or(0, |s| self.ticks(s.at));
+        let mut events = Vec::with_capacity(take.len());
+        let mut programs = Vec::new();
+        let mut prev = 0;
+        for (i, sent) in take.iter().enumerate() {
+            let at = self.ticks(sent.at);
+            let event = match LiveEvent::parse(&sent.msg) {
+                Ok(LiveEvent::Midi { channel, message }) => match message {
+                    NoteOn { key, vel } if vel > 0 => {
+                        // notes still held at the end of the take stop there
+                        let (off, release) = take[i + 1..]
+                            .iter()
+                            .find_map(|s| match LiveEvent::parse(&s.msg) {
+                                Ok(LiveEvent::Midi {
+                                    channel: ch,
+                                    message: NoteOff { key: k, vel },
+                                }) if ch == channel && k == key => Some((self.ticks(s.at), vel)),
+                                Ok(LiveEvent::Midi {
+                                    channel: ch,
+                                    message: NoteOn { key: k, .. },
+                                }) if ch == channel && k == key => {
+                                    Some((self.ticks(s.at), DEFAULT_RELEASE.into()))
+                                }
+                                _ => None,
+                            })
+                            .unwrap_or((end, DEFAULT_RELEASE.into()));
+                        Event::play_ticks(key, vel, (off - at).max(1)).with_release(release)
+                    }
+                    Controller { controller, value } => Event::control(controller, value),
+                    ProgramChange { program } => {
+                        if programs.is_empty() {
+                            programs.push(Event::program(program));
+                        }
+                        continue;
+                    }
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            if at > prev {
+                events.push(Event::wait(at - prev));
+                prev = at;
+            }
+            events.push(event);
+        }
+        MidiSequence::new(events, self.ticks_per_beat).with_programs(programs)
+    }
+
+    /// Write the take to a standard MIDI file.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let mut smf = SmfRecorder::new(path, self.tempo);
+        self.take()
+            .into_iter()
+            .try_for_each(|s| smf.send(s.at, &s.msg))?;
+        smf.flush()
+    }
+}
+
+impl Output for Recorder {
+    fn send(&mut self, at: Duration, msg: &[u8]) -> Result<()> {
+        self.take.send(at, msg)
+    }
+}
+
+#[cfg(test)]
+mod test_input {
+    use super::*;
+    use crate::midi::Parser;
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    /// Plays a take into a recorder from another thread, like an input port.
+    fn play(recorder: &Recorder, take: &[(u64, [u8; 3])]) {
+        let mut input = recorder.clone();
+        let take = take.to_vec();
+        std::thread::spawn(move || {
+            for (ms, msg) in take {
+                input.send(Duration::from_millis(ms), &msg).unwrap();
+            }
+        })
+        .join()
+        .unwrap();
+    }
+
+    // at 120 bpm and 2 ticks per beat a tick is 250ms
+    const TAKE: [(u64, [u8; 3]); 6] = [
+        (1000, [0x90, 60, 100]),
+        (1000, [0xB0, 64, 127]),
+        (1250, [0x91, 64, 40]),
+        (1500, [0x80, 60, 30]),
+        (1750, [0x91, 64, 0]),
+        (2000, [0xB0, 64, 0]),
+    ];
+
+    #[test]
+    fn test_recorder() {
+        rspec::run(&rspec::describe("Recorder", Env::default(), |ctx| {
+            ctx.it("records notes, controllers and pedal", |_| {
+                let recorder = Recorder::new(120.0, 2);
+                play(&recorder, &TAKE);
+                assert_that!(
+                    recorder.sequence().events,
+                    eq(vec![
+                        Event::play_ticks(60, 100, 2).with_release(30),
+                        Event::control(64, 127),
+                        Event::wait(1u32),
+                        Event::play_ticks(64, 40, 2),
+                        Event::wait(3u32),
+                        Event::control(64, 0),
+                    ])
+                );
+            });
+
+            ctx.it("keeps program changes out of the events", |_| {
+                let recorder = Recorder::new(120.0, 2);
+                play(&recorder, &[(0, [0xC0, 5, 0]), (0, [0x90, 60, 100])]);
+                let seq = recorder.sequence();
+                assert_that!(seq.programs().to_vec(), eq(vec![Event::program(5)]));
+                assert_that!(seq.events, eq(vec![Event::play_ticks(60, 100, 1)]));
+            });
+
+            ctx.it("stops held notes at the end of the take", |_| {
+                let recorder = Recorder::new(120.0, 2);
+                play(&recorder, &[(0, [0x90, 60, 100]), (500, [0xB0, 1, 10])]);
+                assert_that!(
+                    recorder.sequence().events[0],
+                    eq(Event::play_ticks(60, 100, 2))
+                );
+            });
+
+            ctx.it("saves a take that can be parsed", |_| {
+                let path = std::env::temp_dir().join("test_recorder.mid");
+                let path = path.to_str().unwrap();
+                let recorder = Recorder::new(120.0, 2);
+                play(&recorder, &TAKE);
+                recorder.save(path).unwrap();
+                let seq = Parser::default()
+                    .parse_tracks(&std::fs::read(path).unwrap(), &[0])
+                    .unwrap();
+                let keys: Vec<u8> = seq
+                    .events
+                    .iter()
+                    .filter_map(|e| match e {
+                        Event::PlayNoteTicks { key, .. } => Some(key.as_int()),
+                        _ => None,
+                    })
+                    .collect();
+                assert_that!(keys, eq(vec![60, 64]));
+                std::fs::remove_file(path).unwrap();
+            });
+        }));
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    str::FromStr,
+    sync::mpsc::{self, Receiver},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use eyre::{ensure, eyre, Result};
+use midir::{MidiInput, MidiInputConnection};
+
+use crate::{input::find_port, output::Output};
+
+/// GM side stick, a short click on the drum channel.
+const CLICK: [u8; 3] = [0x99, 37, 100];
+const CLICK_OFF: [u8; 3] = [0x89, 37, 0];
+/// Time between clicks so an echo is not mistaken for the next one.
+const CLICK_GAP: Duration = Duration::from_millis(250);
+/// How long to wait for a click to come back.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How late a port sounds, written as `port=ms`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Latency {
+    pub port: String,
+    pub ms: f64,
+}
+
+impl FromStr for Latency {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (port, ms) = s
+            .rsplit_once('=')
+            .ok_or_else(|| eyre!("latency must be port=ms: {}", s))?;
+        let ms: f64 = ms.trim().parse()?;
+        ensure!(ms >= 0.0, "latency must not be negative");
+        Ok(Self {
+            port: port.trim().to_string(),
+            ms,
+        })
+    }
+}
+
+/// Listen on an input port, getting the time each note on arrives.
+pub fn listen(
+    client_name: &str,
+    port_name: &str,
+) -> Result<(MidiInputConnection<()>, Receiver<Instant>)> {
+    let midi_in = MidiInput::new(client_name)?;
+    let port = find_port(&midi_in, port_name)?;
+    let (tx, rx) = mpsc::channel();
+    let conn = midi_in
+        .connect(
+            &port,
+            "calibrate",
+            move |_, msg, _| {
+                if msg.len() == 3 && msg[0] & 0xF0 == 0x90 && msg[2] > 0 {
+                    tx.send(Instant::now()).ok();
+                }
+            },
+            (),
+        )
+        .map_err(|e| eyre!("could not connect to {}: {}", port_name, e))?;
+    Ok((conn, rx))
+}
+
+/// Play `clicks` clicks to `out` and time each one's return on `echoes`,
+/// e.g. from a loopback cable into an input. Gets the median round trip.
+pub fn calibrate(
+    out: &mut dyn Output,
+    echoes: &Receiver<Instant>,
+    clicks: usize,
+) -> Result<Duration> {
+    ensure!(clicks > 0, "need at least one click");
+    let mut trips = Vec::with_capacity(clicks);
+    for _ in 0..clicks {
+        // drop anything left over from the last click
+        while echoes.try_recv().is_ok() {}
+        let sent = Instant::now();
+        out.send(Duration::ZERO, &CLICK)?;
+        let back = echoes
+            .recv_timeout(ECHO_TIMEOUT)
+            .map_err(|_| eyre!("no click came back, is the loopback connected?"))?;
+        trips.push(back.saturating_duration_since(sent));
+        out.send(Duration::ZERO, &CLICK_OFF)?;
+        sleep(CLICK_GAP);
+    }
+    trips.sort();
+    Ok(trips[trips.len() / 2])
+}
+
+#[cfg(test)]
+mod test_latency {
+    use super::*;
+    use hamcrest2::prelude::*;
+    use std::{sync::mpsc::Sender, thread};
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    /// Sends note ons back after a fixed delay, like a synth with a loopback.
+    struct Echo {
+        tx: Sender<Instant>,
+        delay: Duration,
+    }
+
+    impl Output for Echo {
+        fn send(&mut self, _at: Duration, msg: &[u8]) -> Result<()> {
+            if msg[0] & 0xF0 == 0x90 {
+                let (tx, delay) = (self.tx.clone(), self.delay);
+                thread::spawn(move || {
+                    sleep(delay);
+                    tx.send(Instant::now()).unwrap();
+                });
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_latency() {
+        rspec::run(&rspec::describe("Latency", Env::default(), |ctx| {
+            ctx.it("parses port=ms", |_| {
+                let latency: Latency = "Synth = B=12.5".parse().unwrap();
+                assert_that!(latency.port.as_str(), eq("Synth = B"));
+                assert_that!(latency.ms, eq(12.5));
+                assert_that!("Synth".parse::<Latency>(), err());
+            });
+
+            ctx.it("measures the round trip of a click", |_| {
+                let (tx, rx) = mpsc::channel();
+                let mut echo = Echo {
+                    tx,
+                    delay: Duration::from_millis(15),
+                };
+                let trip = calibrate(&mut echo, &rx, 3).unwrap();
+                assert_that!(
+                    trip,
+                    is(all!(
+                        gt(Duration::from_millis(14)),
+                        lt(Duration::from_millis(100))
+                    ))
+                );
+            });
+        }));
+    }
+}
@@ -3,31 +3,73 @@ use std::{
     fs,
     rc::Rc,
     sync::{
-        atomic::{AtomicBool, AtomicU32, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
 use clap::Parser;
-use eyre::{ensure, Result};
+use eyre::{ensure, eyre, Result};
 use itertools::Itertools;
 use midly::{num::u28, TrackEvent};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
+mod chain;
+mod constraint;
 mod dsl;
 mod duration;
+mod factored;
+mod generator;
+mod groove;
+mod human;
+mod input;
+mod latency;
 mod midi;
+mod model;
+mod normalize;
 mod notes;
+mod osc;
+mod output;
 mod player;
+mod render;
+mod routing;
+mod sampler;
 mod sequence;
+mod sf2;
+mod synth;
 mod theory;
+mod thru;
+mod token;
+mod trace;
+mod transform;
+mod transport;
 
+use constraint::Constraints;
 use duration::Dur;
+use factored::{Factored, Orders};
+use generator::{Generator, Markov};
+use groove::Groove;
+use latency::Latency;
+use normalize::Normalized;
+use output::{SmfRecorder, Tee};
 use player::Player;
+use render::WavRenderer;
+use routing::{Route, Router};
+use sampler::Sampler;
+use sf2::SoundFont;
 use sixtyfps::Model;
+use synth::Synth;
+use thru::Thru;
+use transform::{Transform, Transforms};
+use transport::{Sequencer, Transport};
 
 use crate::sequence::Event;
 
 const TICKS_PER_BEAT: u16 = 100;
+const CALIBRATION_CLICKS: usize = 8;
+const DEFAULT_TICKS_PER_BEAT: u32 = 24;
+const NO_FIT: &str = "no phrase fits the constraints";
 const ZERO_TICKS: u28 = u28::new(0);
 const BEAT: u28 = u28::new(TICKS_PER_BEAT as u32);
 
@@ -69,34 +111,225 @@ fn write_some_midi() {
 #[derive(Debug, Parser)]
 #[clap(about, version, author)]
 struct Args {
-    /// Path to MIDI file to rip off
-    path: String,
-    /// Order of the markov chain
-    #[clap(long, default_value_t = 1)]
+    /// Path to MIDI file to rip off, or to record a take to with --listen
+    #[clap(required_unless_present_any = &["calibrate", "thru", "model", "audition"])]
+    path: Option<String>,
+    /// Load a trained model instead of training from scratch, training it
+    /// further on the MIDI file if one is given
+    #[clap(long)]
+    model: Option<String>,
+    /// Save the trained model, to load with --model
+    #[clap(long)]
+    save_model: Option<String>,
+    /// Order of the markov chain, the longest context with --backoff
+    #[clap(long, default_value_t = 1, validator = at_least_one)]
     order: usize,
+    /// Back off to shorter contexts where longer ones weren't seen, so high
+    /// orders don't just quote the source
+    #[clap(long)]
+    backoff: bool,
     /// Tempo
     #[clap(long, default_value_t = 120)]
     tempo: usize,
-    /// Ticks per beat
-    #[clap(long, default_value_t = 24)]
-    ticks_per_beat: u32,
-    /// Number of events to use per chunk
+    /// Ticks per beat, by default 24 or what the --model was trained at
+    #[clap(long)]
+    ticks_per_beat: Option<u32>,
+    /// Number of tokens to use per chunk
     #[clap(long, default_value_t = usize::MAX)]
     chunk_size: usize,
+    /// How the MIDI file is split into tokens for the chain
+    #[clap(long, arg_enum, default_value = "events")]
+    tokens: token::Tokens,
+    /// Which model generates new material
+    #[clap(long, arg_enum, default_value = "markov")]
+    generator: generator::Kind,
+    /// Order of the factored model's pitch chain, or --order
+    #[clap(long, validator = at_least_one)]
+    pitch_order: Option<usize>,
+    /// Order of the factored model's rhythm chain, or --order
+    #[clap(long, validator = at_least_one)]
+    rhythm_order: Option<usize>,
+    /// Order of the factored model's velocity chain, or --order
+    #[clap(long, validator = at_least_one)]
+    velocity_order: Option<usize>,
+    /// Condition the factored model's rhythm on pitch and velocity on rhythm
+    #[clap(long)]
+    cross_condition: bool,
+    /// How pitch is learned, so sources in different keys share statistics
+    #[clap(long, arg_enum, default_value = "absolute")]
+    pitch: normalize::Pitch,
+    /// Key of the source, e.g. a-minor, instead of its key signature or a
+    /// guess from its notes
+    #[clap(long)]
+    source_key: Option<theory::Scale>,
+    /// Key to play in, or the key of the source
+    #[clap(long)]
+    target_key: Option<theory::Scale>,
+    /// Only generate keys in this scale, e.g. d-minor
+    #[clap(long)]
+    scale: Option<theory::Scale>,
+    /// Only generate keys in this range, e.g. 40-76
+    #[clap(long)]
+    range: Option<constraint::KeyRange>,
+    /// Generate phrases exactly this many bars long, with the markov
+    /// generator only
+    #[clap(long)]
+    bars: Option<u32>,
+    /// Beats in a bar for --bars, accents and grooves
+    #[clap(long, default_value_t = groove::DEFAULT_BEATS_PER_BAR, validator = at_least_one)]
+    beats_per_bar: u32,
+    /// End phrases on the tonic of --scale, or a chord such as g,b,d, with
+    /// the markov generator only
+    #[clap(long)]
+    cadence: Option<constraint::Cadence>,
     /// The humanization delay range (±ms/2)
     #[clap(long)]
     human_ms: Option<u8>,
     /// The humanization velocity range (±vel/2)
     #[clap(long)]
     human_vel: Option<u8>,
+    /// How humanization offsets are drawn
+    #[clap(long, arg_enum, default_value = "uniform")]
+    human_model: human::Model,
+    /// Extra velocity on beats, the full amount on the first beat of a bar
+    #[clap(long)]
+    human_accent: Option<u8>,
+    /// Swing percentage for off-beat steps, 50 is straight
+    #[clap(long)]
+    swing: Option<f64>,
+    /// Steps per beat that --swing applies to, 2 swings 8ths
+    #[clap(long, default_value_t = 2, validator = at_least_one)]
+    swing_steps: u32,
+    /// Groove template file to play with
+    #[clap(long, conflicts_with = "swing")]
+    groove: Option<String>,
+    /// Extract a groove template from the MIDI file to this path
+    #[clap(long)]
+    extract_groove: Option<String>,
+    /// Seed for generation and humanization, to reproduce a take
+    #[clap(long)]
+    seed: Option<u64>,
+    /// Name of the MIDI output port
+    #[clap(long, default_value = "loopMIDI Port")]
+    port: String,
+    /// Route a source to a port, as `source=[port][@channel][,transpose[,velocity]]`
+    #[clap(long = "route")]
+    routes: Vec<Route>,
+    /// File of routes, one per line
+    #[clap(long)]
+    routes_file: Option<String>,
+    /// What the source of a route is
+    #[clap(long, arg_enum, default_value = "midi")]
+    route_by: midi::Channels,
+    /// Tracks of the MIDI file to read
+    #[clap(long, default_values = &["0"], multiple_values = true)]
+    tracks: Vec<usize>,
+    /// How late a port sounds, as `port=ms`, so it can be sent to ahead of
+    /// the others
+    #[clap(long = "latency")]
+    latencies: Vec<Latency>,
+    /// Measure the latency of --port by timing clicks that come back on this
+    /// input port through a loopback
+    #[clap(long)]
+    calibrate: Option<String>,
+    /// Play notes like `c3+e3+g3b2 c4 1.5@g4` and quit: `+` joins a chord,
+    /// and `beats@` starts a group at that beat instead of after the last
+    #[clap(long, conflicts_with_all = &["thru", "ui"])]
+    audition: Option<String>,
+    /// Record a take from this input port to the MIDI file first, until
+    /// enter is pressed, and rip that off
+    #[clap(long)]
+    listen: Option<String>,
+    /// Play notes from this input port live through --transform instead
+    #[clap(long, conflicts_with_all = &["listen", "ui", "latencies"])]
+    thru: Option<String>,
+    /// Make the --listen or --thru input a new virtual port for other
+    /// programs to play into
+    #[clap(long)]
+    virtual_input: bool,
+    /// Change every note, as `transpose=n`, `snap=<scale>`, `harmonize=<scale>`,
+    /// `velocity=<power>` or `double=<octaves>`, e.g. `snap=a-minor`
+    #[clap(long = "transform")]
+    transforms: Vec<Transform>,
+    /// What to do when a sounding key is played again
+    #[clap(long, arg_enum, default_value = "stack")]
+    repeat: player::Repeat,
+    /// End each note when the next one starts, for mono synths
+    #[clap(long)]
+    mono_legato: bool,
+    /// Most keys to sound at once
+    #[clap(long, validator = at_least_one)]
+    max_voices: Option<usize>,
+    /// Which key to stop when going over --max-voices
+    #[clap(long, arg_enum, default_value = "oldest")]
+    steal: player::Steal,
+    /// Send note offs as note ons with velocity 0, dropping release velocity
+    #[clap(long)]
+    zero_vel_off: bool,
+    /// Also record the played output to this MIDI file
+    #[clap(long)]
+    record: Option<String>,
+    /// Write every message played with its timing to this file
+    #[clap(long)]
+    trace: Option<String>,
+    /// Format of --trace
+    #[clap(long, arg_enum, default_value = "jsonl")]
+    trace_format: trace::Format,
+    /// Render offline to this WAV file with the built-in synth instead of
+    /// playing to a port
+    #[clap(long, conflicts_with = "ui")]
+    render: Option<String>,
+    /// Waveform of the built-in synth
+    #[clap(long, arg_enum, default_value = "sine")]
+    waveform: synth::Waveform,
+    /// Render with the presets of this SF2 file instead of the built-in synth
+    #[clap(long, requires = "render")]
+    soundfont: Option<String>,
+    /// Sample rate of rendered audio
+    #[clap(long, default_value_t = 44100)]
+    sample_rate: u32,
+    /// Beats of new material to render
+    #[clap(long, default_value_t = 64)]
+    length: u32,
+    /// Play the source file instead of generating
+    #[clap(long, requires = "path")]
+    original: bool,
+    /// Follow the tempo changes of the source file with --original, rather
+    /// than playing at --tempo
+    #[clap(long, requires = "original")]
+    file_tempo: bool,
+    /// Beat to start playing the source file from
+    #[clap(long)]
+    start: Option<f64>,
+    /// Beat at which a loop of the source file starts
+    #[clap(long, requires = "loop-end")]
+    loop_start: Option<f64>,
+    /// Beat at which a loop of the source file jumps back to --loop-start
+    #[clap(long, requires = "loop-start")]
+    loop_end: Option<f64>,
     /// Show the UI
     #[clap(long)]
     ui: bool,
+    /// Take tempo, humanization, transport, seed and order over OSC, and
+    /// publish ticks and notes
+    #[clap(long, conflicts_with = "ui")]
+    osc: bool,
+    /// Address the OSC server listens on
+    #[clap(long, default_value = "127.0.0.1:9000")]
+    osc_addr: String,
     /// Show the UI
     #[clap(long)]
     dot_file: Option<String>,
 }
 
+/// Check a count on the command line is at least 1.
+fn at_least_one(s: &str) -> Result<(), String> {
+    match s.parse::<u64>() {
+        Ok(n) if n >= 1 => Ok(()),
+        _ => Err("must be a whole number of at least 1".into()),
+    }
+}
+
 // #[derive(Clone, Debug)]
 // pub struct UIKey {
 //     key: i32,
@@ -141,6 +374,7 @@ impl<'a> UIPlayer<'a> {
             key,
             dynamic,
             ticks,
+            ..
         } = *event
         {
             let k = key.as_int() as usize;
@@ -174,7 +408,7 @@ impl<'a> UIPlayer<'a> {
         self.player.ticks_played()
     }
 
-    pub fn tick_dur(&self) -> std::time::Duration {
+    pub fn tick_dur(&self) -> Duration {
         self.player.tick_dur()
     }
 }
@@ -184,60 +418,278 @@ fn main() -> Result<()> {
     let args = Args::parse();
 
     println!("generating with order {} chain", args.order);
+    let seed = args.seed.unwrap_or_else(rand::random);
+    println!("seed: {}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
 
     let term = Arc::new(AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&term))?;
     signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&term))?;
 
-    // let data = fs::read("1st Mvmt Sonata No.14, Opus 27, No.2.mid")?;
-    let data = fs::read(args.path)?;
-    let midi_parser = midi::Parser::default().with_ticks_per_beat(args.ticks_per_beat);
-    let seq = midi_parser.parse_seq(&data, 0)?;
+    let client_name = "Bobs thing";
+    if let Some(input) = &args.calibrate {
+        let (_conn, echoes) = latency::listen(client_name, input)?;
+        let mut out = output::MidirOutput::connect(client_name, &args.port)?;
+        let trip = latency::calibrate(&mut out, &echoes, CALIBRATION_CLICKS)?;
+        println!("round trip: {:?}", trip);
+        println!(
+            "--latency \"{}={:.1}\"",
+            args.port,
+            trip.as_secs_f64() * 1000.0
+        );
+        return Ok(());
+    }
 
-    let mut player = player::Player::new("Bobs thing");
-    player.set_ticks_per_beat(seq.ticks_per_beat());
+    let mut routes = args.routes;
+    if let Some(path) = &args.routes_file {
+        routes.extend(Route::load(path)?);
+    }
+    // a model's waits and lengths are in the ticks it was trained at
+    let model = args.model.as_deref().map(model::Model::load).transpose()?;
+    let ticks_per_beat = match (&model, args.ticks_per_beat) {
+        (Some(model), tpb) => {
+            let trained: u32 = model.parse("ticks_per_beat")?;
+            ensure!(
+                tpb.is_none_or(|tpb| tpb == trained),
+                "the model was trained at {} ticks per beat",
+                trained
+            );
+            trained
+        }
+        (None, tpb) => tpb.unwrap_or(DEFAULT_TICKS_PER_BEAT),
+    };
+    let mut player = player::Player::new(client_name);
+    player.set_ticks_per_beat(ticks_per_beat);
     player.set_tempo(args.tempo as f32);
-    player.connect("loopMIDI Port")?;
+    let router = || -> Result<Router> {
+        Router::connect(client_name, &args.port, routes.clone())?.with_latencies(&args.latencies)
+    };
+    match (&args.render, &args.record) {
+        (Some(wav), record) => {
+            player.set_realtime(false);
+            let renderer = match &args.soundfont {
+                Some(path) => {
+                    let font = Arc::new(SoundFont::load(path)?);
+                    WavRenderer::new(wav, Sampler::new(font, args.sample_rate))
+                }
+                None => WavRenderer::new(wav, Synth::new(args.sample_rate, args.waveform)),
+            };
+            let mut out = Tee::new().with(renderer);
+            if let Some(path) = record {
+                out = out.with(SmfRecorder::new(path, args.tempo as f32));
+            }
+            player.set_output(out);
+        }
+        (None, Some(path)) => player.set_output(
+            Tee::new()
+                .with(router()?)
+                .with(SmfRecorder::new(path, args.tempo as f32)),
+        ),
+        (None, None) if routes.is_empty() && args.latencies.is_empty() => {
+            player.connect(&args.port)?;
+        }
+        (None, None) => player.set_output(router()?),
+    }
+    player.set_zero_vel_off(args.zero_vel_off);
+    if let Some(path) = &args.trace {
+        player.set_trace(Some(trace::Trace::create(path, args.trace_format)?));
+    }
+    player.set_repeat(args.repeat);
+    player.set_mono_legato(args.mono_legato);
+    player.set_max_voices(args.max_voices, args.steal);
     if let Some(ms) = args.human_ms {
         player.set_human_ms_range(ms as f64);
     }
     if let Some(vel) = args.human_vel {
         player.set_human_vel_range(vel as f64);
     }
+    player.set_human_model(args.human_model);
+    if let Some(accent) = args.human_accent {
+        player.set_human_accent(accent as f64);
+    }
+    player.set_beats_per_bar(args.beats_per_bar);
+    player.set_human_seed(rng.gen());
+    match (args.swing, &args.groove) {
+        (Some(swing), _) => player.set_groove(Some(Groove::swing(swing, args.swing_steps)?)),
+        (_, Some(path)) => player.set_groove(Some(Groove::load(path)?)),
+        _ => {}
+    }
 
-    ensure!(!seq.events.is_empty(), "no events");
+    let transforms = Transforms::new(args.transforms);
+    if let Some(input) = &args.thru {
+        if args.human_vel.is_none() {
+            // live playing varies enough already
+            player.set_human_vel_range(0.0);
+        }
+        let thru = Thru::new(player, transforms);
+        let conn = input::listen(client_name, input, args.virtual_input, thru)?;
+        println!("playing {} through, press enter to stop", input);
+        std::io::stdin().read_line(&mut String::new())?;
+        drop(conn);
+        return Ok(());
+    }
+    if let Some(notes) = &args.audition {
+        player.play_notes(notes::parse_notes(notes)?)?;
+        return player.finish();
+    }
 
-    // // play the original
-    // for ev in &seq.events {
-    //     if term.load(Ordering::Relaxed) {
-    //         break;
-    //     }
-    //     player.event(ev);
-    // }
+    // a model can stand in for the source file
+    let seq = match &args.path {
+        Some(path) => {
+            let take = match &args.listen {
+                Some(input) => {
+                    ensure!(
+                        !std::path::Path::new(path).exists(),
+                        "{} exists, give a new path to record the take to",
+                        path
+                    );
+                    let recorder = input::Recorder::new(args.tempo as f32, ticks_per_beat);
+                    let conn =
+                        input::listen(client_name, input, args.virtual_input, recorder.clone())?;
+                    println!("recording from {}, press enter to stop", input);
+                    std::io::stdin().read_line(&mut String::new())?;
+                    drop(conn);
+                    recorder.save(path)?;
+                    println!("wrote take to {}", path);
+                    Some(recorder.sequence())
+                }
+                None => None,
+            };
+            // let data = fs::read("1st Mvmt Sonata No.14, Opus 27, No.2.mid")?;
+            let data = fs::read(path)?;
+            if let Some(path) = &args.extract_groove {
+                Groove::extract(&data, &args.tracks, args.swing_steps, args.beats_per_bar)?
+                    .save(path)?;
+                println!("wrote groove to {}", path);
+            }
+            let channels = match routes.is_empty() {
+                true => midi::Channels::Off,
+                false => args.route_by,
+            };
+            let midi_parser = midi::Parser::default()
+                .with_ticks_per_beat(ticks_per_beat)
+                .with_channels(channels)
+                .with_inline_programs(!routes.is_empty() || args.render.is_some())
+                .with_tempos(args.file_tempo);
+            let seq = match take {
+                Some(seq) => seq,
+                None => midi_parser.parse_tracks(&data, &args.tracks)?,
+            };
+            let seq = transforms.sequence(seq);
+            ensure!(!seq.events.is_empty(), "no events");
+            seq
+        }
+        None => midi::MidiSequence::new(Vec::new(), ticks_per_beat),
+    };
 
-    // generate some new material
-    let mut seq_chain = markov::Chain::of_order(args.order);
-    let iter = seq.events.into_iter();
-    let rev_iter = iter.clone().rev();
-    let iter = iter.chain(rev_iter);
-    // let quieter = iter.clone().map(|e| {
-    //     if let Event::PlayNote { key, dynamic } = e {
-    //         Event::play(key, dynamic.down())
-    //     } else {
-    //         e
-    //     }
-    // });
-    // let iter = iter.chain(quieter);
-    let chunk_size: usize = args.chunk_size;
-    for chunk in &iter.chunks(chunk_size) {
-        let tokens = chunk.collect::<Vec<_>>();
-        seq_chain.feed(tokens);
+    let original = seq.clone();
+    let transport = Transport::with_stop_flag(Arc::clone(&term));
+    if let Some(beat) = args.start {
+        transport.seek(beat);
+    }
+    if let (Some(start), Some(end)) = (args.loop_start, args.loop_end) {
+        transport.set_loop(start, end);
+    }
+    let osc = match args.osc {
+        true => {
+            let control = osc::Control::new(transport.clone());
+            let server = osc::Server::bind(&args.osc_addr, control.clone())?;
+            println!("osc server listening on {}", server.local_addr());
+            player.add_output(server.publisher());
+            Some((control, server.publisher()))
+        }
+        false => None,
+    };
+    if args.original {
+        println!("transport: p = pause/resume, s <beat> = seek, l <start> <end> = loop, l = no loop, q = quit");
+        let transport = transport.clone();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lines() {
+                let line = line.expect("failed reading stdin");
+                if let Err(e) = transport.command(&line) {
+                    eprintln!("{}", e);
+                }
+            }
+        });
     }
 
-    if let Some(path) = args.dot_file {
-        let graph = seq_chain.graph();
-        let dot = petgraph::dot::Dot::with_config(&graph, &[]);
-        std::fs::write(path, format!("{:?}", dot)).unwrap();
+    // generate some new material, with a saved model or from scratch
+    // "<key> <path>" of every source the model was trained on
+    let mut sources = Vec::new();
+    let mut generator = match &model {
+        Some(model) => {
+            sources.extend(model.all("source").map(String::from));
+            println!("loaded model trained on: {}", sources.iter().join(", "));
+            let generator = Normalized::load(model, rng)?;
+            match args.target_key {
+                Some(key) => generator.with_target_key(Some(key)),
+                None => generator,
+            }
+        }
+        None => {
+            let generator: Box<dyn Generator + Send> = match args.generator {
+                generator::Kind::Markov => Box::new(
+                    Markov::new(args.order, rng)
+                        .with_chunk_size(args.chunk_size)
+                        .with_tokens(args.tokens)
+                        .with_backoff(args.backoff),
+                ),
+                generator::Kind::Factored => {
+                    let orders = Orders {
+                        pitch: args.pitch_order.unwrap_or(args.order),
+                        rhythm: args.rhythm_order.unwrap_or(args.order),
+                        velocity: args.velocity_order.unwrap_or(args.order),
+                    };
+                    Box::new(
+                        Factored::new(orders, rng)
+                            .with_cross(args.cross_condition)
+                            .with_backoff(args.backoff),
+                    )
+                }
+            };
+            Normalized::new(generator, args.pitch)
+                .with_source_key(args.source_key)
+                .with_target_key(args.target_key)
+        }
+    };
+    if let Some(path) = &args.path {
+        let key = args.source_key.or_else(|| normalize::key_of(&seq));
+        if let Some(key) = key {
+            println!("source key: {}", key);
+        }
+        generator.train(&seq);
+        let key = key.map_or("-".to_string(), |k| k.to_string());
+        sources.push(format!("{} {}", key, path));
+    }
+    if let Some(path) = &args.save_model {
+        let mut model = model::Model::default();
+        for source in &sources {
+            model.set("source", source);
+        }
+        generator.save(&mut model);
+        model.save(path)?;
+        println!("wrote model to {}", path);
+    }
+    let constraints = Constraints {
+        scale: args.scale,
+        range: args.range,
+        beats: args.bars.map(|bars| bars * args.beats_per_bar),
+        cadence: args.cadence.clone(),
+    };
+    // the generator only runs dry if nothing fits
+    let constrained = !constraints.is_empty();
+    if constrained {
+        generator.set_constraints(constraints)?;
+    }
+
+    if let Some(path) = &args.dot_file {
+        let dot = generator
+            .dot()
+            .ok_or_else(|| eyre!("only the markov generator has a dot graph"))?;
+        std::fs::write(path, dot)?;
+    }
+    for ev in seq.programs() {
+        player.event(ev)?;
     }
 
     if args.ui {
@@ -269,14 +721,31 @@ fn main() -> Result<()> {
         main.set_keys(sixtyfps::ModelHandle::new(keys_model));
         let mut player = UIPlayer::new(player);
         let clocked_ticks = Arc::new(AtomicU32::new(0));
-        let tick_dur = player.tick_dur();
+        // follows tempo changes so the clock keeps pace with the player
+        let tick_nanos = Arc::new(AtomicU64::new(player.tick_dur().as_nanos() as u64));
+
+        let handle_weak = main.as_weak();
+        let thread_transport = transport.clone();
+        main.on_toggle_pause(move || {
+            thread_transport.toggle_pause();
+            let main = handle_weak.unwrap();
+            main.set_paused(thread_transport.is_paused());
+        });
+        let thread_transport = transport.clone();
+        let restart_at = args.loop_start.unwrap_or(0.0);
+        main.on_restart(move || thread_transport.seek(restart_at));
 
         let thread_arc = clocked_ticks.clone();
+        let thread_nanos = tick_nanos.clone();
+        let thread_transport = transport.clone();
         let handle_weak = main.as_weak();
         std::thread::spawn(move || {
             let clocked_ticks = thread_arc;
             loop {
-                std::thread::sleep(tick_dur);
+                std::thread::sleep(Duration::from_nanos(thread_nanos.load(Ordering::Relaxed)));
+                if thread_transport.is_paused() {
+                    continue;
+                }
                 let prev = clocked_ticks.fetch_add(1, Ordering::Relaxed);
                 let main_copy = handle_weak.clone();
                 sixtyfps::invoke_from_event_loop(move || {
@@ -288,13 +757,12 @@ fn main() -> Result<()> {
 
         let handle_weak = main.as_weak();
         let thread_arc = clocked_ticks;
+        let play_original = args.original;
         std::thread::spawn(move || {
             let clocked_ticks = thread_arc;
-            for ev in seq_chain.iter().flatten() {
-                if term.load(Ordering::Relaxed) {
-                    break;
-                }
+            let show = |player: &mut UIPlayer, ev: Event| {
                 player.event(&ev).expect("failed playing event");
+                tick_nanos.store(player.tick_dur().as_nanos() as u64, Ordering::Relaxed);
                 let ticks_played = player.ticks_played();
                 if let Event::Wait { ticks: _ } = &ev {
                     clocked_ticks.store(ticks_played, Ordering::Relaxed);
@@ -302,6 +770,7 @@ fn main() -> Result<()> {
                 let clocked = clocked_ticks.load(Ordering::Relaxed);
                 let main_copy = handle_weak.clone();
                 let history: Vec<PlayedNote> = player.note_history.clone().into();
+                let notes_on = player.notes_on;
                 sixtyfps::invoke_from_event_loop(move || {
                     let main = main_copy.unwrap();
                     let keys_model = main.get_keys();
@@ -309,7 +778,7 @@ fn main() -> Result<()> {
                         .iter()
                         .enumerate()
                         .map(|(i, uik)| {
-                            let (vel, _, _) = player.notes_on[uik.key as usize];
+                            let (vel, _, _) = notes_on[uik.key as usize];
                             (
                                 i,
                                 UIKey {
@@ -325,9 +794,98 @@ fn main() -> Result<()> {
                     main.set_history(sixtyfps::ModelHandle::new(hist_model));
                     main.set_ticks_played(clocked as i32);
                 });
+            };
+            if play_original {
+                let mut seqr = Sequencer::new(original, transport);
+                while let Some(ev) = seqr.step(&mut player.player).expect("failed seeking") {
+                    show(&mut player, ev);
+                }
+                return;
+            }
+            while let Some(ev) = generator.next_event() {
+                if term.load(Ordering::Relaxed) {
+                    break;
+                }
+                transport
+                    .wait_while_paused(&mut player.player)
+                    .expect("failed pausing");
+                show(&mut player, ev);
+            }
+            if constrained && !term.load(Ordering::Relaxed) {
+                eprintln!("{}", NO_FIT);
             }
         });
         main.run();
+    } else if args.original {
+        let mut seqr = Sequencer::new(original, transport);
+        while let Some(ev) = seqr.step(&mut player)? {
+            if let Some((control, _)) = &osc {
+                control.take().apply(&mut player);
+            }
+            player.event(&ev)?;
+            if let (Some((_, publisher)), Event::Wait { .. }) = (&osc, ev) {
+                publisher.publish_tick(player.ticks_played())?;
+            }
+        }
+        player.finish()?;
+    } else if let Some(path) = &args.render {
+        let events = generator.take_beats(args.length);
+        let stuck = events
+            .iter()
+            .rev()
+            .take_while(|ev| !matches!(ev, Event::Wait { .. }))
+            .count();
+        ensure!(
+            stuck < generator::MAX_EVENTS_WITHOUT_WAIT,
+            "the generator never waits, so time doesn't move on"
+        );
+        ensure!(!(events.is_empty() && constrained), NO_FIT);
+        for ev in &events {
+            player.event(ev)?;
+        }
+        player.finish()?;
+        println!("rendered {} beats to {}", args.length, path);
+    } else {
+        // generate until stopped
+        let mut since_wait = 0;
+        loop {
+            let ev = match generator.next_event() {
+                Some(ev) => ev,
+                None if constrained => return Err(eyre!(NO_FIT)),
+                None => break,
+            };
+            since_wait = match ev {
+                Event::Wait { .. } => 0,
+                _ => since_wait + 1,
+            };
+            ensure!(
+                since_wait <= generator::MAX_EVENTS_WITHOUT_WAIT,
+                "the generator never waits, so time doesn't move on"
+            );
+            if transport.is_stopped() {
+                break;
+            }
+            if let Some((control, _)) = &osc {
+                let changes = control.take();
+                changes.apply(&mut player);
+                if let Some(seed) = changes.seed {
+                    generator.set_seed(seed);
+                }
+                if let Some(order) = changes.order {
+                    generator.set_order(order);
+                }
+                if changes.seed.is_some() || changes.order.is_some() {
+                    player.all_notes_off()?;
+                    continue;
+                }
+            }
+            transport.wait_while_paused(&mut player)?;
+            player.event(&ev)?;
+            if let (Some((_, publisher)), Event::Wait { .. }) = (&osc, ev) {
+                publisher.publish_tick(player.ticks_played())?;
+            }
+        }
+        player.finish()?;
     }
 
     Ok(())
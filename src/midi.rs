@@ -1,5 +1,6 @@
 use std::cell::RefCell;
 
+use clap::ArgEnum;
 use eyre::{ensure, eyre, Result};
 use midly::{
     num::{u4, u7},
@@ -9,7 +10,8 @@ use num::Zero;
 
 use crate::{
     notes::{Beats, Note},
-    sequence::Event,
+    sequence::{Event, DEFAULT_RELEASE},
+    theory::Scale,
 };
 
 pub struct Midi<'a> {
@@ -31,6 +33,25 @@ fn calc_ticks_per_beat(timing: &Timing, tempo: f32) -> f64 {
     }
 }
 
+/// Get the release velocity of the next note off for `key`. Note ons with
+/// velocity 0 have none so they get the default.
+fn find_release_vel<'a, I>(mut iter: I, key: u7) -> u7
+where
+    I: Iterator<Item = &'a TrackEvent<'a>>,
+{
+    use MidiMessage::*;
+
+    iter.find_map(|ev| match ev.kind {
+        TrackEventKind::Midi { message, .. } => match message {
+            NoteOff { key: k, vel } if k == key => Some(vel),
+            NoteOn { key: k, vel: _ } if k == key => Some(DEFAULT_RELEASE.into()),
+            _ => None,
+        },
+        _ => None,
+    })
+    .unwrap_or_else(|| DEFAULT_RELEASE.into())
+}
+
 fn find_next_off_delta<'a, I>(mut iter: I, key: u7, _channel: Option<u4>) -> u32
 where
     I: Iterator<Item = &'a TrackEvent<'a>>,
@@ -161,12 +182,45 @@ pub fn parse(data: &[u8]) -> Result<Midi> {
     Ok(Midi { smf, notes })
 }
 
+#[derive(Clone)]
 pub struct MidiSequence {
     pub events: Vec<Event>,
     ticks_per_beat: u32,
+    key: Option<Scale>,
+    programs: Vec<Event>,
 }
 
 impl MidiSequence {
+    pub fn new(events: Vec<Event>, ticks_per_beat: u32) -> Self {
+        Self {
+            events,
+            ticks_per_beat,
+            key: None,
+            programs: Vec::new(),
+        }
+    }
+
+    pub fn with_key(mut self, key: Option<Scale>) -> Self {
+        self.key = key;
+        self
+    }
+
+    pub fn with_programs(mut self, programs: Vec<Event>) -> Self {
+        self.programs = programs;
+        self
+    }
+
+    /// Get the key signature of the source, if it had one.
+    pub fn key(&self) -> Option<Scale> {
+        self.key
+    }
+
+    /// Get the program changes to send once before playing, when they are
+    /// not among the events.
+    pub fn programs(&self) -> &[Event] {
+        &self.programs
+    }
+
     /// Get a reference to the midi sequence's ticks per beat.
     pub fn ticks_per_beat(&self) -> u32 {
         self.ticks_per_beat
@@ -183,9 +237,24 @@ impl IntoIterator for MidiSequence {
     }
 }
 
+/// What `Event::Channel` events the parser tags notes with.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Channels {
+    /// No channel events, everything plays on one channel.
+    #[default]
+    Off,
+    /// The MIDI channel each note came from.
+    Midi,
+    /// The number of the track each note came from.
+    Track,
+}
+
 pub struct Parser {
     tempo: RefCell<f32>,
     ticks_per_beat: u32,
+    channels: Channels,
+    inline_programs: bool,
+    tempos: bool,
 }
 
 impl Default for Parser {
@@ -193,15 +262,66 @@ impl Default for Parser {
         Self {
             tempo: RefCell::new(120.0),
             ticks_per_beat: 12,
+            channels: Channels::Off,
+            inline_programs: false,
+            tempos: false,
         }
     }
 }
 
+/// A parsed note at an absolute position in the source file.
+struct Parsed {
+    // in midi ticks
+    at: u32,
+    // tempo in effect at the note
+    tempo: f32,
+    source: u4,
+    event: Event,
+}
+
 impl Parser {
     pub fn with_ticks_per_beat(&self, t: u32) -> Self {
         Self {
             ticks_per_beat: t,
             tempo: RefCell::new(*self.tempo.borrow()),
+            channels: self.channels,
+            inline_programs: self.inline_programs,
+            tempos: self.tempos,
+        }
+    }
+
+    pub fn with_channels(&self, channels: Channels) -> Self {
+        Self {
+            ticks_per_beat: self.ticks_per_beat,
+            tempo: RefCell::new(*self.tempo.borrow()),
+            channels,
+            inline_programs: self.inline_programs,
+            tempos: self.tempos,
+        }
+    }
+
+    /// Keep program changes among the notes where they happen, for routing
+    /// or rendering. Otherwise only the first is kept, to send before
+    /// playing, so they don't become tokens of the chain.
+    pub fn with_inline_programs(&self, inline_programs: bool) -> Self {
+        Self {
+            ticks_per_beat: self.ticks_per_beat,
+            tempo: RefCell::new(*self.tempo.borrow()),
+            channels: self.channels,
+            inline_programs,
+            tempos: self.tempos,
+        }
+    }
+
+    /// Turn tempo changes into `Event::Tempo`, to play a file at its own
+    /// tempo rather than the player's.
+    pub fn with_tempos(&self, tempos: bool) -> Self {
+        Self {
+            ticks_per_beat: self.ticks_per_beat,
+            tempo: RefCell::new(*self.tempo.borrow()),
+            channels: self.channels,
+            inline_programs: self.inline_programs,
+            tempos,
         }
     }
 
@@ -214,12 +334,15 @@ impl Parser {
         (beats * self.ticks_per_beat as f64).round() as u32
     }
 
-    pub fn parse_seq(&self, data: &[u8], track_i: usize) -> Result<MidiSequence> {
-        let smf = Smf::parse(data)?;
+    fn parse_track(&self, smf: &Smf, track_i: usize) -> Result<Vec<Parsed>> {
         let track = smf
             .tracks
             .get(track_i)
             .ok_or_else(|| eyre!("could not get track {}", track_i))?;
+        ensure!(
+            self.channels != Channels::Track || track_i < 16,
+            "only tracks 0-15 can be tagged as channels"
+        );
 
         let tempo = RefCell::new(50.0);
 
@@ -229,13 +352,21 @@ impl Parser {
             .map(|(i, ev)| match ev.kind {
                 TrackEventKind::Meta(MetaMessage::Tempo(t)) => {
                     tempo.replace(t.as_int() as f32);
-                    (ev.delta, None)
+                    let bpm = (60_000_000.0 / t.as_int().max(1) as f64).round();
+                    let event = Event::tempo(bpm.clamp(1.0, u16::MAX as f64) as u16);
+                    // not on any channel, so never switches channel
+                    let source = u4::new(0);
+                    (ev.delta, self.tempos.then_some((source, event)))
                 }
                 TrackEventKind::Midi {
                     channel: ch,
                     message,
                 } => {
                     use MidiMessage::*;
+                    let source = match self.channels {
+                        Channels::Track => u4::new(track_i as u8),
+                        _ => ch,
+                    };
                     match message {
                         // NoteOff { key, vel: _ } => (ev.delta, Some(Event::stop(key))),
                         // NoteOn { key, vel } if vel == 0 => (ev.delta, Some(Event::stop(key))),
@@ -244,46 +375,159 @@ impl Parser {
                         NoteOn { key, vel } => {
                             let off = find_next_off_delta(track.iter().skip(i + 1), key, Some(ch));
                             match off {
-                                0 => (ev.delta, Some(Event::play(key, vel))),
+                                0 => (ev.delta, Some((source, Event::play(key, vel)))),
                                 _ => {
                                     let ticks = self.ticks_from_delta(off, &smf.header.timing);
                                     // let ticks = match ticks {
                                     //     0 => 1,
                                     //     _ => ticks,
                                     // };
-                                    (ev.delta, Some(Event::play_ticks(key, vel, ticks)))
+                                    let release = find_release_vel(track.iter().skip(i + 1), key);
+                                    let event =
+                                        Event::play_ticks(key, vel, ticks).with_release(release);
+                                    (ev.delta, Some((source, event)))
                                 }
                             }
                         }
+                        ProgramChange { program } => {
+                            (ev.delta, Some((source, Event::program(program))))
+                        }
                         _ => (ev.delta, None),
                     }
                 }
                 _ => (ev.delta, None),
             })
-            // compress sequential waits
-            .scan(0u32, |state, (delta, event)| {
-                *state += delta.as_int();
-                match event {
-                    None => Some([None, None]),
-                    Some(_ev) => {
-                        let d = *state as f64;
-                        let tpb = calc_ticks_per_beat(&smf.header.timing, *tempo.borrow());
-                        let beats = d / tpb;
-                        let ticks = (beats * self.ticks_per_beat as f64).round() as u32;
-                        *state = 0;
-                        Some([Some(Event::wait(ticks)), event])
-                    }
-                }
+            .scan(0u32, |at, (delta, event)| {
+                *at += delta.as_int();
+                Some(event.map(|(source, event)| Parsed {
+                    at: *at,
+                    tempo: *tempo.borrow(),
+                    source,
+                    event,
+                }))
             })
-            .flatten()
-            .filter_map(|e| match e {
-                Some(Event::Wait { ticks }) if ticks == 0 => None,
-                _ => e,
-            });
+            .flatten();
+        Ok(things.collect())
+    }
+
+    /// Parse several tracks merged into one sequence.
+    pub fn parse_tracks(&self, data: &[u8], tracks: &[usize]) -> Result<MidiSequence> {
+        let smf = Smf::parse(data)?;
+        let mut parsed = Vec::new();
+        for &track_i in tracks {
+            parsed.extend(self.parse_track(&smf, track_i)?);
+        }
+        parsed.sort_by_key(|p| p.at);
+
+        let mut events = Vec::with_capacity(parsed.len() * 2);
+        let mut programs = Vec::new();
+        let mut prev_at = 0;
+        let mut source = None;
+        for p in parsed {
+            if !self.inline_programs && matches!(p.event, Event::Program { .. }) {
+                if programs.is_empty() {
+                    programs.push(p.event);
+                }
+                continue;
+            }
+            // compress sequential waits
+            let d = (p.at - prev_at) as f64;
+            let tpb = calc_ticks_per_beat(&smf.header.timing, p.tempo);
+            let beats = d / tpb;
+            let ticks = (beats * self.ticks_per_beat as f64).round() as u32;
+            prev_at = p.at;
+            if ticks > 0 {
+                events.push(Event::wait(ticks));
+            }
+            let on_channel = !matches!(p.event, Event::Tempo { .. });
+            if self.channels != Channels::Off && on_channel && source != Some(p.source) {
+                events.push(Event::channel(p.source));
+                source = Some(p.source);
+            }
+            events.push(p.event);
+        }
+
+        // the first key signature in any track, usually the tempo track
+        let key = smf.tracks.iter().flatten().find_map(|ev| match ev.kind {
+            TrackEventKind::Meta(MetaMessage::KeySignature(sharps, minor)) => {
+                Some(Scale::from_signature(sharps, minor))
+            }
+            _ => None,
+        });
 
         Ok(MidiSequence {
-            events: things.collect(),
+            events,
             ticks_per_beat: self.ticks_per_beat,
+            key,
+            programs,
         })
     }
 }
+
+#[cfg(test)]
+mod test_midi {
+    use super::*;
+    use crate::output::{Output, SmfRecorder};
+    use hamcrest2::prelude::*;
+    use std::time::Duration;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    /// A file at 60 bpm that changes program before each of two notes.
+    fn programs() -> Vec<u8> {
+        let mut rec = SmfRecorder::new("unused.mid", 60.0);
+        let take: [(u64, &[u8]); 6] = [
+            (0, &[0xC0, 5]),
+            (0, &[0x90, 60, 100]),
+            (1000, &[0x80, 60, 64]),
+            (1000, &[0xC0, 9]),
+            (1000, &[0x90, 62, 100]),
+            (2000, &[0x80, 62, 64]),
+        ];
+        for (ms, msg) in take {
+            rec.send(Duration::from_millis(ms), msg).unwrap();
+        }
+        let mut data = Vec::new();
+        rec.smf().write_std(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_parser() {
+        rspec::run(&rspec::describe("Parser", Env::default(), |ctx| {
+            ctx.it("keeps program changes out of the events by default", |_| {
+                let seq = Parser::default().parse_tracks(&programs(), &[0]).unwrap();
+                assert_that!(seq.programs().to_vec(), eq(vec![Event::program(5)]));
+                assert!(!seq
+                    .events
+                    .iter()
+                    .any(|ev| matches!(ev, Event::Program { .. })));
+                assert_that!(seq.events[1], eq(Event::wait(12u32)));
+            });
+
+            ctx.it("keeps program changes inline when asked", |_| {
+                let seq = Parser::default()
+                    .with_inline_programs(true)
+                    .parse_tracks(&programs(), &[0])
+                    .unwrap();
+                assert_that!(seq.programs().is_empty(), is(true));
+                assert_that!(seq.events[0], eq(Event::program(5)));
+                assert_that!(seq.events[3], eq(Event::program(9)));
+            });
+
+            ctx.it("reads tempo changes when asked", |_| {
+                let seq = Parser::default().parse_tracks(&programs(), &[0]).unwrap();
+                assert!(!seq
+                    .events
+                    .iter()
+                    .any(|ev| matches!(ev, Event::Tempo { .. })));
+                let seq = Parser::default()
+                    .with_tempos(true)
+                    .parse_tracks(&programs(), &[0])
+                    .unwrap();
+                assert_that!(seq.events[0], eq(Event::tempo(60)));
+            });
+        }));
+    }
+}
@@ -0,0 +1,411 @@
+use std::{fmt::Display, fs, str::FromStr};
+
+use clap::ArgEnum;
+use eyre::{ensure, eyre, Result};
+use itertools::Itertools;
+use midly::num::{u4, u7};
+
+use crate::{
+    sequence::{Curve, Dynamic, Event},
+    theory::Scale,
+    token::Token,
+};
+
+const HEADER: &str = "model v1";
+
+/// Something written into a model as whitespace separated words.
+pub trait Words: Sized {
+    fn write(&self, out: &mut Vec<String>);
+
+    fn read<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Self>;
+}
+
+fn word<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<&'a str> {
+    words.next().ok_or_else(|| eyre!("model line ends early"))
+}
+
+fn parse<'a, T: FromStr>(words: &mut impl Iterator<Item = &'a str>) -> Result<T> {
+    let w = word(words)?;
+    w.parse().map_err(|_| eyre!("bad word in model: {}", w))
+}
+
+macro_rules! words_by_parse {
+    ($($t:ty),*) => {$(
+        impl Words for $t {
+            fn write(&self, out: &mut Vec<String>) {
+                out.push(self.to_string());
+            }
+
+            fn read<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Self> {
+                parse(words)
+            }
+        }
+    )*};
+}
+
+words_by_parse!(bool, u8, u16, u32, u64, usize, Scale);
+
+impl Words for u4 {
+    fn write(&self, out: &mut Vec<String>) {
+        self.as_int().write(out)
+    }
+
+    fn read<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Self> {
+        let n = u8::read(words)?;
+        u4::try_from(n).ok_or_else(|| eyre!("{} is too big for a channel", n))
+    }
+}
+
+impl Words for u7 {
+    fn write(&self, out: &mut Vec<String>) {
+        self.as_int().write(out)
+    }
+
+    fn read<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Self> {
+        let n = u8::read(words)?;
+        u7::try_from(n).ok_or_else(|| eyre!("{} is too big for a MIDI value", n))
+    }
+}
+
+/// `-` for `None`, or `+` and the value.
+impl<T: Words> Words for Option<T> {
+    fn write(&self, out: &mut Vec<String>) {
+        match self {
+            None => out.push("-".into()),
+            Some(t) => {
+                out.push("+".into());
+                t.write(out);
+            }
+        }
+    }
+
+    fn read<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Self> {
+        match word(words)? {
+            "-" => Ok(None),
+            "+" => Ok(Some(T::read(words)?)),
+            w => Err(eyre!("expected - or + in model, got {}", w)),
+        }
+    }
+}
+
+impl<A: Words, B: Words> Words for (A, B) {
+    fn write(&self, out: &mut Vec<String>) {
+        self.0.write(out);
+        self.1.write(out);
+    }
+
+    fn read<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Self> {
+        Ok((A::read(words)?, B::read(words)?))
+    }
+}
+
+/// The length, then each item.
+impl<T: Words> Words for Vec<T> {
+    fn write(&self, out: &mut Vec<String>) {
+        self.len().write(out);
+        for t in self {
+            t.write(out);
+        }
+    }
+
+    fn read<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Self> {
+        let len = usize::read(words)?;
+        (0..len).map(|_| T::read(words)).collect()
+    }
+}
+
+impl Words for Dynamic {
+    fn write(&self, out: &mut Vec<String>) {
+        self.vel().write(out)
+    }
+
+    fn read<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Self> {
+        Ok(Dynamic::from(u8::read(words)?))
+    }
+}
+
+impl Words for Event {
+    fn write(&self, out: &mut Vec<String>) {
+        let mut put = |tag: &str, args: &[u32]| {
+            out.push(tag.into());
+            out.extend(args.iter().map(u32::to_string));
+        };
+        match *self {
+            Event::PlayNote { key, dynamic } => {
+                put("play", &[key.as_int() as u32, dynamic.vel() as u32])
+            }
+            Event::PlayNoteTicks {
+                key,
+                dynamic,
+                ticks,
+                release,
+            } => put(
+                "note",
+                &[
+                    key.as_int() as u32,
+                    dynamic.vel() as u32,
+                    ticks,
+                    release.as_int() as u32,
+                ],
+            ),
+            Event::StopNote { key, release } => {
+                put("stop", &[key.as_int() as u32, release.as_int() as u32])
+            }
+            Event::Wait { ticks } => put("wait", &[ticks]),
+            Event::Channel { channel } => put("channel", &[channel.as_int() as u32]),
+            Event::Program { program } => put("program", &[program.as_int() as u32]),
+            Event::Control { controller, value } => put(
+                "control",
+                &[controller.as_int() as u32, value.as_int() as u32],
+            ),
+            Event::Tempo { bpm, beats, curve } => {
+                let curve = match curve {
+                    Curve::Linear => 0,
+                    Curve::Exponential => 1,
+                };
+                put("tempo", &[bpm as u32, beats as u32, curve])
+            }
+        }
+    }
+
+    fn read<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Self> {
+        let tag = word(words)?;
+        Ok(match tag {
+            "play" => Event::play(u7::read(words)?, Dynamic::read(words)?),
+            "note" => {
+                let (key, dynamic) = (u7::read(words)?, Dynamic::read(words)?);
+                Event::play_ticks(key, dynamic, u32::read(words)?).with_release(u7::read(words)?)
+            }
+            "stop" => Event::stop(u7::read(words)?).with_release(u7::read(words)?),
+            "wait" => Event::wait(u32::read(words)?),
+            "channel" => Event::channel(u4::read(words)?),
+            "program" => Event::program(u7::read(words)?),
+            "control" => Event::control(u7::read(words)?, u7::read(words)?),
+            "tempo" => Event::Tempo {
+                bpm: u16::read(words)?,
+                beats: u16::read(words)?,
+                curve: match u8::read(words)? {
+                    0 => Curve::Linear,
+                    _ => Curve::Exponential,
+                },
+            },
+            x => return Err(eyre!("unknown event in model: {}", x)),
+        })
+    }
+}
+
+impl Words for Token {
+    fn write(&self, out: &mut Vec<String>) {
+        match self {
+            Token::Event(ev) => {
+                out.push("event".into());
+                ev.write(out);
+            }
+            Token::Chord(evs) => {
+                out.push("chord".into());
+                evs.write(out);
+            }
+            Token::Step(evs, ticks) => {
+                out.push("step".into());
+                evs.write(out);
+                ticks.write(out);
+            }
+        }
+    }
+
+    fn read<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Self> {
+        Ok(match word(words)? {
+            "event" => Token::Event(Event::read(words)?),
+            "chord" => Token::Chord(Vec::read(words)?),
+            "step" => Token::Step(Vec::read(words)?, u32::read(words)?),
+            x => return Err(eyre!("unknown token in model: {}", x)),
+        })
+    }
+}
+
+/// A trained generator and what it was trained on, stored as text lines of
+/// a key and its value, e.g.
+///
+/// ```text
+/// model v1
+/// source d-minor song.mid
+/// generator markov
+/// order 2
+/// chain.state 2 - - 1 + event note 62 64 12 64 1
+/// ...
+/// ```
+///
+/// Keys can repeat, e.g. one `chain.state` per state of a chain.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Model {
+    lines: Vec<(String, String)>,
+}
+
+impl Model {
+    pub fn load(path: &str) -> Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    pub fn set(&mut self, key: &str, value: impl Display) {
+        self.lines.push((key.into(), value.to_string()));
+    }
+
+    pub fn set_words(&mut self, key: &str, value: &impl Words) {
+        let mut out = Vec::new();
+        value.write(&mut out);
+        self.set(key, out.join(" "));
+    }
+
+    /// Set an `ArgEnum` by the name it has on the command line.
+    pub fn set_choice(&mut self, key: &str, value: &impl ArgEnum) {
+        let value = value.to_possible_value().expect("choices are not skipped");
+        self.set(key, value.get_name());
+    }
+
+    /// Get every value of `key` in order.
+    pub fn all<'a>(&'a self, key: &str) -> impl Iterator<Item = &'a str> + 'a {
+        let key = key.to_string();
+        self.lines
+            .iter()
+            .filter(move |(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get(&self, key: &str) -> Result<&str> {
+        self.all(key)
+            .next()
+            .ok_or_else(|| eyre!("model has no {}", key))
+    }
+
+    pub fn parse<T: FromStr>(&self, key: &str) -> Result<T> {
+        let value = self.get(key)?;
+        value
+            .parse()
+            .map_err(|_| eyre!("bad {} in model: {}", key, value))
+    }
+
+    pub fn words<T: Words>(&self, key: &str) -> Result<T> {
+        read_words(self.get(key)?)
+    }
+
+    pub fn choice<T: ArgEnum>(&self, key: &str) -> Result<T> {
+        T::from_str(self.get(key)?, false).map_err(|e| eyre!("bad {} in model: {}", key, e))
+    }
+}
+
+/// Read a whole value as `T`.
+pub fn read_words<T: Words>(value: &str) -> Result<T> {
+    let mut words = value.split_whitespace();
+    let t = T::read(&mut words)?;
+    ensure!(words.next().is_none(), "model line has words left over");
+    Ok(t)
+}
+
+impl Display for Model {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        for (key, value) in &self.lines {
+            writeln!(f, "{} {}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Model {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines().filter(|l| !l.trim().is_empty());
+        let header = lines.next().unwrap_or_default().trim();
+        ensure!(header.starts_with("model "), "not a model file");
+        ensure!(
+            header == HEADER,
+            "model is {}, this version reads {}",
+            header,
+            HEADER
+        );
+        let lines = lines
+            .map(|l| {
+                let (key, value) = l.split_once(' ').unwrap_or((l, ""));
+                (key.to_string(), value.to_string())
+            })
+            .collect_vec();
+        Ok(Self { lines })
+    }
+}
+
+#[cfg(test)]
+mod test_model {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    fn round_trip<T: Words + PartialEq + std::fmt::Debug>(t: T) {
+        let mut model = Model::default();
+        model.set_words("t", &t);
+        let model: Model = model.to_string().parse().unwrap();
+        assert_that!(model.words::<T>("t").unwrap(), eq(t));
+    }
+
+    #[test]
+    fn test_model() {
+        rspec::run(&rspec::describe("Model", Env::default(), |ctx| {
+            ctx.it("writes and reads events and tokens", |_| {
+                let events = vec![
+                    Event::play(60, 100),
+                    Event::play_ticks(62, 40, 12).with_release(30),
+                    Event::stop(62),
+                    Event::wait(6u32),
+                    Event::channel(u4::new(9)),
+                    Event::program(5),
+                    Event::control(64, 127),
+                    Event::Tempo {
+                        bpm: 90,
+                        beats: 4,
+                        curve: Curve::Exponential,
+                    },
+                ];
+                round_trip(events.clone());
+                round_trip(vec![
+                    Some(Token::Event(events[0])),
+                    None,
+                    Some(Token::Chord(events[..2].to_vec())),
+                    Some(Token::Step(events[1..3].to_vec(), 4)),
+                ]);
+                round_trip((Some(3u32), vec![(true, 7usize)]));
+            });
+
+            ctx.it("keeps keys in order", |_| {
+                let mut model = Model::default();
+                model.set("a", 1);
+                model.set("b", "two words");
+                model.set("a", 3);
+                let model: Model = model.to_string().parse().unwrap();
+                assert_that!(model.all("a").collect_vec(), eq(vec!["1", "3"]));
+                assert_that!(model.get("b").unwrap(), eq("two words"));
+                assert_that!(model.parse::<u8>("a").unwrap(), eq(1));
+                assert_that!(model.get("c"), err());
+            });
+
+            ctx.it("checks the version", |_| {
+                assert_that!("model v2\na 1".parse::<Model>(), err());
+                assert_that!("groove v1".parse::<Model>(), err());
+                assert_that!("model v1\na 1".parse::<Model>(), ok());
+            });
+
+            ctx.it("rejects bad lines", |_| {
+                assert_that!(read_words::<Event>("wobble 1"), err());
+                assert_that!(read_words::<Event>("wait"), err());
+                assert_that!(read_words::<Event>("wait 1 2"), err());
+                assert_that!(read_words::<Event>("play 128 64"), err());
+            });
+        }));
+    }
+}
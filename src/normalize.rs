@@ -0,0 +1,359 @@
+use clap::ArgEnum;
+use eyre::{eyre, Result};
+use rand::rngs::StdRng;
+
+use crate::{
+    constraint::Constraints,
+    generator::{self, Generator},
+    midi::MidiSequence,
+    model::Model,
+    sequence::Event,
+    theory::Scale,
+};
+
+/// How pitch is learned, so sources in different keys share statistics.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Pitch {
+    /// Keys as they are in each source.
+    #[default]
+    Absolute,
+    /// Keys transposed so every source has C as its tonic.
+    Tonic,
+    /// The interval from the key before, starting from the tonic.
+    Intervals,
+}
+
+/// Key a pitch stands for when there is none before it, C4 plus the tonic.
+const START: i16 = 60;
+
+/// Intervals are learned as keys offset by this so they fit a `u7`. Bigger
+/// leaps are clamped.
+const MIDDLE: i16 = 64;
+
+/// Get the key of `seq`: its key signature, or else a guess from its notes
+/// weighted by their lengths.
+pub fn key_of(seq: &MidiSequence) -> Option<Scale> {
+    seq.key().or_else(|| {
+        Scale::detect(seq.events.iter().filter_map(|ev| match *ev {
+            Event::PlayNote { key, .. } => Some((key.as_int(), 1.0)),
+            Event::PlayNoteTicks { key, ticks, .. } => Some((key.as_int(), ticks.max(1) as f64)),
+            _ => None,
+        }))
+    })
+}
+
+/// Get how far to move `from` to `to` by the shortest way, in semitones.
+fn shift(from: u8, to: u8) -> i16 {
+    (to as i16 - from as i16 + 6).rem_euclid(12) - 6
+}
+
+/// Move `key` up or down octaves until it is a MIDI key.
+fn fold(key: i16) -> u8 {
+    match key {
+        k if k < 0 => (k + (11 - k) / 12 * 12) as u8,
+        k if k > 127 => (k - (k - 116) / 12 * 12) as u8,
+        k => k as u8,
+    }
+}
+
+/// Change the key of every note of `events` with `f`.
+fn map_keys(events: &mut [Event], mut f: impl FnMut(u8) -> u8) {
+    for ev in events {
+        match ev {
+            Event::PlayNote { key, .. }
+            | Event::PlayNoteTicks { key, .. }
+            | Event::StopNote { key, .. } => *key = f(key.as_int()).into(),
+            _ => {}
+        }
+    }
+}
+
+/// Trains a generator on sources moved to a common tonic or turned into
+/// intervals, and moves what it generates into a target key.
+pub struct Normalized {
+    inner: Box<dyn Generator + Send>,
+    pitch: Pitch,
+    source: Option<Scale>,
+    target: Option<Scale>,
+    // the key of the first source, to play absolute keys in the target
+    first: Option<Scale>,
+    // the last key played, for intervals
+    last: i16,
+}
+
+impl Normalized {
+    pub fn new(mut inner: Box<dyn Generator + Send>, pitch: Pitch) -> Self {
+        // intervals played backwards would need negating, so they are only
+        // learned forwards
+        inner.set_backwards(pitch != Pitch::Intervals);
+        Self {
+            inner,
+            pitch,
+            source: None,
+            target: None,
+            first: None,
+            last: START,
+        }
+    }
+
+    /// Use `key` for every source instead of reading or guessing theirs.
+    pub fn with_source_key(mut self, key: Option<Scale>) -> Self {
+        self.source = key;
+        self
+    }
+
+    /// Play in `key`, or in the key of the first source if `None`. Only the
+    /// tonic moves, the mode is what was learned.
+    pub fn with_target_key(mut self, key: Option<Scale>) -> Self {
+        self.target = key;
+        self.reset();
+        self
+    }
+
+    /// Read a generator written by `save`, sampling with `rng`.
+    pub fn load(model: &Model, rng: StdRng) -> Result<Self> {
+        let mut normalized = Self::new(generator::load(model, rng)?, model.choice("pitch")?)
+            .with_source_key(model.words("source_key")?)
+            .with_target_key(model.words("target_key")?);
+        normalized.first = model.words("first_key")?;
+        normalized.reset();
+        Ok(normalized)
+    }
+
+    /// Get the tonic generated material is in before moving to the target.
+    fn learned_tonic(&self) -> u8 {
+        match self.pitch {
+            Pitch::Absolute => self.first.map_or(0, |k| k.tonic()),
+            Pitch::Tonic | Pitch::Intervals => 0,
+        }
+    }
+
+    fn target_tonic(&self) -> u8 {
+        self.target
+            .or(self.first)
+            .map_or(self.learned_tonic(), |k| k.tonic())
+    }
+}
+
+impl Generator for Normalized {
+    fn train(&mut self, seq: &MidiSequence) {
+        let key = self.source.or_else(|| key_of(seq));
+        self.first = self.first.or(key);
+        let tonic = key.map_or(0, |k| k.tonic());
+        let mut seq = seq.clone();
+        match self.pitch {
+            Pitch::Absolute => {}
+            Pitch::Tonic => {
+                let by = shift(tonic, 0);
+                map_keys(&mut seq.events, |k| fold(k as i16 + by));
+            }
+            Pitch::Intervals => {
+                let mut last = START + tonic as i16;
+                map_keys(&mut seq.events, |k| {
+                    let step = (k as i16 - last).clamp(-MIDDLE, 127 - MIDDLE);
+                    last = k as i16;
+                    (MIDDLE + step) as u8
+                });
+            }
+        }
+        self.inner.train(&seq);
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.last = START + self.target_tonic() as i16;
+        self.inner.reset();
+    }
+
+    fn set_seed(&mut self, seed: u64) {
+        self.inner.set_seed(seed);
+        self.reset();
+    }
+
+    fn set_order(&mut self, order: usize) {
+        self.inner.set_order(order);
+        self.reset();
+    }
+
+    fn set_backwards(&mut self, backwards: bool) {
+        self.inner.set_backwards(backwards);
+        self.reset();
+    }
+
+    /// Constraints are on the keys played, so they are moved back into the
+    /// key the inner generator learned in.
+    fn set_constraints(&mut self, constraints: Constraints) -> Result<()> {
+        let constraints = match self.pitch {
+            Pitch::Intervals if constraints.has_pitch() => {
+                return Err(eyre!("pitch constraints need absolute or tonic pitch"))
+            }
+            Pitch::Intervals => constraints,
+            _ => constraints.transposed(-shift(self.learned_tonic(), self.target_tonic())),
+        };
+        self.inner.set_constraints(constraints)?;
+        self.reset();
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Option<Event> {
+        let mut ev = [self.inner.next_event()?];
+        match self.pitch {
+            Pitch::Intervals => {
+                let last = &mut self.last;
+                map_keys(&mut ev, |k| {
+                    let key = fold(*last + k as i16 - MIDDLE);
+                    *last = key as i16;
+                    key
+                });
+            }
+            _ => {
+                let by = shift(self.learned_tonic(), self.target_tonic());
+                map_keys(&mut ev, |k| fold(k as i16 + by));
+            }
+        }
+        Some(ev[0])
+    }
+
+    fn ticks_per_beat(&self) -> u32 {
+        self.inner.ticks_per_beat()
+    }
+
+    fn save(&self, model: &mut Model) {
+        model.set_choice("pitch", &self.pitch);
+        model.set_words("source_key", &self.source);
+        model.set_words("target_key", &self.target);
+        model.set_words("first_key", &self.first);
+        self.inner.save(model);
+    }
+
+    fn dot(&self) -> Option<String> {
+        self.inner.dot()
+    }
+}
+
+#[cfg(test)]
+mod test_normalize {
+    use super::*;
+    use crate::generator::Markov;
+    use hamcrest2::prelude::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    /// A run up the scale of `key` and back, from its tonic in octave 4.
+    fn run(key: &str) -> MidiSequence {
+        let scale: Scale = key.parse().unwrap();
+        let tonic = 60 + scale.tonic();
+        let events = [0i8, 1, 2, 3, 4, 3, 2, 1, 0]
+            .iter()
+            .flat_map(|&d| {
+                [
+                    Event::play_ticks(scale.step(tonic, d).unwrap(), 64, 2),
+                    Event::wait(2u32),
+                ]
+            })
+            .collect();
+        MidiSequence::new(events, 2)
+    }
+
+    fn keys(events: &[Event]) -> Vec<u8> {
+        events
+            .iter()
+            .filter_map(|ev| match ev {
+                Event::PlayNoteTicks { key, .. } => Some(key.as_int()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn normalized(pitch: Pitch) -> Normalized {
+        let markov = Markov::new(1, StdRng::seed_from_u64(1));
+        Normalized::new(Box::new(markov), pitch)
+    }
+
+    #[test]
+    fn test_normalized() {
+        rspec::run(&rspec::describe("Normalized", Env::default(), |ctx| {
+            ctx.it("moves keys the shortest way", |_| {
+                assert_that!(shift(2, 0), eq(-2));
+                assert_that!(shift(9, 0), eq(3));
+                assert_that!(shift(0, 7), eq(-5));
+                assert_that!(fold(-1), eq(11));
+                assert_that!(fold(128), eq(116));
+                assert_that!(fold(60), eq(60));
+            });
+
+            ctx.it("reads the key signature before guessing", |_| {
+                assert_that!(key_of(&run("e")).unwrap().to_string(), eq("e-major"));
+                let seq = run("e").with_key(Some("a-minor".parse().unwrap()));
+                assert_that!(key_of(&seq).unwrap().to_string(), eq("a-minor"));
+            });
+
+            ctx.it("shares statistics across keys", |_| {
+                let mut g = normalized(Pitch::Tonic).with_target_key(Some("c".parse().unwrap()));
+                g.train(&run("d"));
+                g.train(&run("bb"));
+                let played = keys(&g.take_beats(64));
+                assert!(!played.is_empty());
+                let c: Scale = "c".parse().unwrap();
+                assert!(played.iter().all(|&k| c.contains(k)));
+            });
+
+            ctx.it("plays in the key of the first source by default", |_| {
+                let mut g = normalized(Pitch::Tonic);
+                g.train(&run("d"));
+                g.train(&run("f"));
+                let d: Scale = "d".parse().unwrap();
+                assert!(keys(&g.take_beats(64)).iter().all(|&k| d.contains(k)));
+            });
+
+            ctx.it("moves constraints into the learned key", |_| {
+                let mut g = normalized(Pitch::Tonic).with_target_key(Some("g".parse().unwrap()));
+                g.train(&run("d"));
+                let constraints = Constraints {
+                    range: Some("55-59".parse().unwrap()),
+                    ..Default::default()
+                };
+                g.set_constraints(constraints).unwrap();
+                let played = keys(&g.take_beats(32));
+                assert!(!played.is_empty());
+                assert!(played.iter().all(|k| (55..=59).contains(k)));
+                let mut g = normalized(Pitch::Intervals);
+                g.train(&run("d"));
+                let constraints = Constraints {
+                    scale: Some("d".parse().unwrap()),
+                    ..Default::default()
+                };
+                assert_that!(g.set_constraints(constraints), err());
+            });
+
+            ctx.it("saves and loads", |_| {
+                let mut g = normalized(Pitch::Tonic);
+                g.train(&run("e"));
+                let mut model = Model::default();
+                g.save(&mut model);
+                let model: Model = model.to_string().parse().unwrap();
+                let mut loaded = Normalized::load(&model, StdRng::seed_from_u64(1)).unwrap();
+                assert_that!(loaded.pitch, eq(Pitch::Tonic));
+                let name = |k: Option<Scale>| k.map(|k| k.to_string());
+                assert_that!(name(loaded.first), eq(name(key_of(&run("e")))));
+                let played = keys(&loaded.take_beats(16));
+                let e: Scale = "e".parse().unwrap();
+                assert!(!played.is_empty() && played.iter().all(|&k| e.contains(k)));
+            });
+
+            ctx.it("learns intervals forwards only", |_| {
+                // long enough to follow the run without a choice
+                let markov = Markov::new(4, StdRng::seed_from_u64(1));
+                let mut g = Normalized::new(Box::new(markov), Pitch::Intervals)
+                    .with_target_key(Some("g".parse().unwrap()));
+                g.train(&run("e"));
+                assert_that!(
+                    keys(&g.take_beats(9)),
+                    eq(vec![67, 69, 71, 72, 74, 72, 71, 69, 67])
+                );
+            });
+        }));
+    }
+}
@@ -35,6 +35,11 @@ impl Beats {
     pub fn from_bpm(&self, tempo: impl Into<f64>) -> Duration {
         self.from_millis(60_000.0 / tempo.into())
     }
+
+    /// Get the nearest whole number of ticks.
+    pub fn ticks(&self, ticks_per_beat: u32) -> u32 {
+        (self.inner * ticks_per_beat as f64).round() as u32
+    }
 }
 
 impl PartialEq for Beats {
@@ -284,6 +289,33 @@ impl TryFrom<&str> for Note {
     }
 }
 
+/// Parse notes to audition, e.g. `c3+e3+g3b2 c4 1.5@g4`. Notes joined by
+/// `+` start together, and each group starts when the longest note of the
+/// one before ends, unless given an onset in beats before `@`.
+pub fn parse_notes(s: &str) -> eyre::Result<Vec<(Beats, Note)>> {
+    let mut out = Vec::new();
+    let mut next = 0.0;
+    for group in s.split_whitespace() {
+        let (onset, chord) = match group.split_once('@') {
+            Some((onset, chord)) => (onset.parse()?, chord),
+            None => (next, group),
+        };
+        ensure!(
+            onset >= 0.0 && onset < f64::from(u16::MAX),
+            "onset out of range: {}",
+            group
+        );
+        let notes = chord
+            .split('+')
+            .map(Note::try_from)
+            .collect::<eyre::Result<Vec<_>>>()?;
+        next = onset + notes.iter().map(|n| n.beats.inner).fold(0.0, f64::max);
+        out.extend(notes.into_iter().map(|n| (Beats::from(onset), n)));
+    }
+    ensure!(!out.is_empty(), "no notes to play");
+    Ok(out)
+}
+
 #[cfg(test)]
 mod test_notes {
     use super::*;
@@ -395,6 +427,23 @@ mod test_notes {
                 assert_that!(n.beats, eq(22));
                 assert_that!(n.vel, eq(45));
             });
+
+            ctx.it("parses chords and lines with onsets", |_ctx| {
+                let notes = parse_notes("c3+e3b2 g3 0.5@c4").unwrap();
+                let onsets: Vec<(u8, Beats)> = notes.iter().map(|(b, n)| (n.note, *b)).collect();
+                assert_that!(
+                    onsets,
+                    eq(vec![
+                        (60, 0.into()),
+                        (64, 0.into()),
+                        (67, 2.into()),
+                        (72, 0.5.into())
+                    ])
+                );
+                assert_that!(parse_notes(""), err());
+                assert_that!(parse_notes("-1@c3"), err());
+                assert_that!(parse_notes("nan@c3"), err());
+            });
         }));
     }
 }
@@ -0,0 +1,457 @@
+//! A small OSC 1.0 server over UDP for controlling playback from TouchOSC,
+//! Max and the like.
+//!
+//! Received, with int or float arguments:
+//! - `/tempo <bpm>`, kept to 1-999
+//! - `/human/ms <range>` and `/human/vel <range>`, kept to 0-500 and 0-127
+//! - `/play` and `/stop`, ignored with an argument of 0 so buttons work
+//! - `/seed <seed>` and `/order <order>`, which start a new run, with the
+//!   order kept to 1-16
+//!
+//! Sent to everyone who has sent something:
+//! - `/tick <tick>` after every wait
+//! - `/note <channel> <key> <vel>` for every note on, with vel 0 for offs
+
+use std::{
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use eyre::{bail, ensure, eyre, Result};
+
+use crate::{output::Output, player::Player, transport::Transport};
+
+/// Largest packet read, enough for anything a control surface sends.
+const MAX_PACKET: usize = 1536;
+
+/// Tempos a `/tempo` is kept to, so a slider's far end can't stop playback.
+const TEMPO_RANGE: RangeInclusive<f64> = 1.0..=999.0;
+/// Humanization ranges `/human/ms` and `/human/vel` are kept to.
+const HUMAN_MS_RANGE: RangeInclusive<f64> = 0.0..=500.0;
+const HUMAN_VEL_RANGE: RangeInclusive<f64> = 0.0..=127.0;
+/// Orders `/order` is kept to, as every order allocates a chain.
+const ORDER_RANGE: RangeInclusive<f64> = 1.0..=16.0;
+
+// keep a finite `value` to `range`
+fn clamped(addr: &str, value: f64, range: RangeInclusive<f64>) -> Result<f64> {
+    ensure!(value.is_finite(), "{} must be finite", addr);
+    Ok(value.clamp(*range.start(), *range.end()))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Arg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+}
+
+impl Arg {
+    fn as_f64(&self) -> Result<f64> {
+        match *self {
+            Self::Int(x) => Ok(x as f64),
+            Self::Float(x) => Ok(x as f64),
+            Self::Str(ref s) => Err(eyre!("expected a number, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub addr: String,
+    pub args: Vec<Arg>,
+}
+
+fn push_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    // at least one nul, padded to 4 bytes
+    buf.resize((buf.len() / 4 + 1) * 4, 0);
+}
+
+fn read_str(data: &[u8], at: &mut usize) -> Result<String> {
+    let rest = data.get(*at..).unwrap_or_default();
+    let len = rest
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| eyre!("unterminated string"))?;
+    let s = std::str::from_utf8(&rest[..len])?.to_string();
+    *at += (len / 4 + 1) * 4;
+    Ok(s)
+}
+
+fn read_4(data: &[u8], at: &mut usize) -> Result<[u8; 4]> {
+    let bytes = data
+        .get(*at..*at + 4)
+        .ok_or_else(|| eyre!("packet too short"))?;
+    *at += 4;
+    Ok(bytes.try_into()?)
+}
+
+impl Message {
+    pub fn new(addr: impl Into<String>, args: Vec<Arg>) -> Self {
+        Self {
+            addr: addr.into(),
+            args,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        push_str(&mut buf, &self.addr);
+        let tags: String = self
+            .args
+            .iter()
+            .map(|a| match a {
+                Arg::Int(_) => 'i',
+                Arg::Float(_) => 'f',
+                Arg::Str(_) => 's',
+            })
+            .collect();
+        push_str(&mut buf, &format!(",{}", tags));
+        for arg in &self.args {
+            match arg {
+                Arg::Int(x) => buf.extend_from_slice(&x.to_be_bytes()),
+                Arg::Float(x) => buf.extend_from_slice(&x.to_be_bytes()),
+                Arg::Str(s) => push_str(&mut buf, s),
+            }
+        }
+        buf
+    }
+
+    /// Decode a packet, which is one message or a bundle of them.
+    pub fn decode(data: &[u8]) -> Result<Vec<Self>> {
+        let mut at = 0;
+        if data.starts_with(b"#bundle\0") {
+            // skip the time tag, everything is handled straight away
+            at += 16;
+            let mut messages = Vec::new();
+            while at < data.len() {
+                let len = usize::try_from(i32::from_be_bytes(read_4(data, &mut at)?))
+                    .map_err(|_| eyre!("negative bundle element length"))?;
+                let end = at
+                    .checked_add(len)
+                    .filter(|&end| end <= data.len())
+                    .ok_or_else(|| eyre!("bundle element too long"))?;
+                messages.extend(Self::decode(&data[at..end])?);
+                at = end;
+            }
+            return Ok(messages);
+        }
+        let addr = read_str(data, &mut at)?;
+        ensure!(addr.starts_with('/'), "bad address {:?}", addr);
+        // old senders may leave out the type tags
+        let tags = match data.get(at) {
+            Some(b',') => read_str(data, &mut at)?,
+            _ => ",".to_string(),
+        };
+        let mut args = Vec::new();
+        for tag in tags.chars().skip(1) {
+            args.push(match tag {
+                'i' => Arg::Int(i32::from_be_bytes(read_4(data, &mut at)?)),
+                'f' => Arg::Float(f32::from_be_bytes(read_4(data, &mut at)?)),
+                's' => Arg::Str(read_str(data, &mut at)?),
+                'T' => Arg::Int(1),
+                'F' => Arg::Int(0),
+                x => bail!("unsupported argument type {}", x),
+            });
+        }
+        Ok(vec![Self { addr, args }])
+    }
+}
+
+/// Settings changed over OSC since they were last taken.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Changes {
+    pub tempo: Option<f32>,
+    pub human_ms: Option<f64>,
+    pub human_vel: Option<f64>,
+    pub seed: Option<u64>,
+    pub order: Option<usize>,
+}
+
+impl Changes {
+    /// Apply the changes that only concern `player`.
+    pub fn apply(&self, player: &mut Player) {
+        if let Some(tempo) = self.tempo {
+            player.set_tempo(tempo);
+        }
+        if let Some(ms) = self.human_ms {
+            player.set_human_ms_range(ms);
+        }
+        if let Some(vel) = self.human_vel {
+            player.set_human_vel_range(vel);
+        }
+    }
+}
+
+/// Turns OSC messages into `Changes` and transport commands. Clones share
+/// the same changes.
+#[derive(Clone, Default)]
+pub struct Control {
+    changes: Arc<Mutex<Changes>>,
+    transport: Transport,
+}
+
+impl Control {
+    pub fn new(transport: Transport) -> Self {
+        Self {
+            changes: Arc::default(),
+            transport,
+        }
+    }
+
+    /// Get the changes made since the last call.
+    pub fn take(&self) -> Changes {
+        std::mem::take(&mut *self.changes.lock().unwrap())
+    }
+
+    pub fn handle(&self, msg: &Message) -> Result<()> {
+        let arg = msg.args.first().map(Arg::as_f64).transpose()?;
+        let value = || arg.ok_or_else(|| eyre!("{} needs a value", msg.addr));
+        let mut changes = self.changes.lock().unwrap();
+        match msg.addr.as_str() {
+            "/tempo" => {
+                let bpm = value()?;
+                ensure!(bpm > 0.0, "tempo must be positive");
+                changes.tempo = Some(clamped(&msg.addr, bpm, TEMPO_RANGE)? as f32);
+            }
+            "/human/ms" => changes.human_ms = Some(clamped(&msg.addr, value()?, HUMAN_MS_RANGE)?),
+            "/human/vel" => {
+                changes.human_vel = Some(clamped(&msg.addr, value()?, HUMAN_VEL_RANGE)?)
+            }
+            "/play" if arg != Some(0.0) => self.transport.resume(),
+            "/stop" if arg != Some(0.0) => self.transport.pause(),
+            "/play" | "/stop" => {}
+            "/seed" => changes.seed = Some(value()? as u64),
+            "/order" => changes.order = Some(clamped(&msg.addr, value()?, ORDER_RANGE)? as usize),
+            x => bail!("unknown address {}", x),
+        }
+        Ok(())
+    }
+}
+
+/// Sends messages to every address the server has heard from.
+#[derive(Clone)]
+pub struct Publisher {
+    socket: Arc<UdpSocket>,
+    subscribers: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+impl Publisher {
+    pub fn publish(&self, msg: &Message) -> Result<()> {
+        let packet = msg.encode();
+        for addr in self.subscribers.lock().unwrap().iter() {
+            self.socket.send_to(&packet, addr)?;
+        }
+        Ok(())
+    }
+
+    pub fn publish_tick(&self, tick: u32) -> Result<()> {
+        self.publish(&Message::new("/tick", vec![Arg::Int(tick as i32)]))
+    }
+}
+
+/// Publishes every note on and off sent by the player.
+impl Output for Publisher {
+    fn send(&mut self, _at: Duration, msg: &[u8]) -> Result<()> {
+        let vel = match (msg.first().map(|b| b & 0xF0), msg.get(2)) {
+            (Some(0x90), Some(&vel)) => vel,
+            (Some(0x80), Some(_)) => 0,
+            _ => return Ok(()),
+        };
+        let args = vec![
+            Arg::Int((msg[0] & 0x0F) as i32),
+            Arg::Int(msg[1] as i32),
+            Arg::Int(vel as i32),
+        ];
+        self.publish(&Message::new("/note", args))
+    }
+}
+
+/// Listens for OSC messages on a thread of its own, handing them to a
+/// `Control`.
+pub struct Server {
+    addr: SocketAddr,
+    publisher: Publisher,
+}
+
+impl Server {
+    pub fn bind(addr: impl ToSocketAddrs, control: Control) -> Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        let addr = socket.local_addr()?;
+        let publisher = Publisher {
+            socket: Arc::new(socket.try_clone()?),
+            subscribers: Arc::default(),
+        };
+        let subscribers = Arc::clone(&publisher.subscribers);
+        thread::spawn(move || {
+            let mut buf = [0; MAX_PACKET];
+            loop {
+                let (len, from) = match socket.recv_from(&mut buf) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        eprintln!("osc server stopped: {}", e);
+                        return;
+                    }
+                };
+                {
+                    let mut subscribers = subscribers.lock().unwrap();
+                    if !subscribers.contains(&from) {
+                        subscribers.push(from);
+                    }
+                }
+                let handled = Message::decode(&buf[..len])
+                    .and_then(|msgs| msgs.iter().try_for_each(|m| control.handle(m)));
+                if let Err(e) = handled {
+                    eprintln!("bad osc message from {}: {}", from, e);
+                }
+            }
+        });
+        Ok(Self { addr, publisher })
+    }
+
+    /// Get the address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn publisher(&self) -> Publisher {
+        self.publisher.clone()
+    }
+}
+
+#[cfg(test)]
+mod test_osc {
+    use super::*;
+    use hamcrest2::prelude::*;
+    use std::time::Instant;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    fn client(server: &Server) -> UdpSocket {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server.local_addr()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        client
+    }
+
+    // wait for the server thread to pick up everything up to a /human/vel,
+    // which tests send last
+    fn settle(control: &Control) -> Changes {
+        let start = Instant::now();
+        while control.changes.lock().unwrap().human_vel.is_none() {
+            assert_that!(start.elapsed(), lt(Duration::from_secs(1)));
+            thread::sleep(Duration::from_millis(5));
+        }
+        control.take()
+    }
+
+    fn recv(client: &UdpSocket) -> Message {
+        let mut buf = [0; MAX_PACKET];
+        let len = client.recv(&mut buf).unwrap();
+        Message::decode(&buf[..len]).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_osc() {
+        rspec::run(&rspec::describe("OSC", Env::default(), |ctx| {
+            ctx.it("encodes and decodes messages", |_| {
+                let msg = Message::new(
+                    "/abc",
+                    vec![Arg::Int(-3), Arg::Float(0.5), Arg::Str("hi".into())],
+                );
+                let data = msg.encode();
+                assert_that!(data.len() % 4, eq(0));
+                assert_that!(&data[..8], eq(b"/abc\0\0\0\0"));
+                assert_that!(Message::decode(&data).unwrap(), eq(vec![msg.clone()]));
+                let mut bundle = b"#bundle\0\0\0\0\0\0\0\0\x01".to_vec();
+                bundle.extend_from_slice(&(data.len() as i32).to_be_bytes());
+                bundle.extend_from_slice(&data);
+                assert_that!(Message::decode(&bundle).unwrap(), eq(vec![msg]));
+                assert_that!(Message::decode(b"/abc"), err());
+            });
+
+            ctx.it("rejects bundle elements of bad lengths", |_| {
+                for len in [-1i32, i32::MAX] {
+                    let mut bundle = b"#bundle\0\0\0\0\0\0\0\0\x01".to_vec();
+                    bundle.extend_from_slice(&len.to_be_bytes());
+                    bundle.extend_from_slice(b"/abc\0\0\0\0,\0\0\0");
+                    assert_that!(Message::decode(&bundle), err());
+                }
+            });
+
+            ctx.it("keeps tempo in range", |_| {
+                let control = Control::default();
+                let send = |addr: &str, x: f32| {
+                    control
+                        .handle(&Message::new(addr, vec![Arg::Float(x)]))
+                        .map(|_| control.take())
+                };
+                assert_that!(send("/tempo", 1e-40).unwrap().tempo, eq(Some(1.0)));
+                assert_that!(send("/tempo", 5000.0).unwrap().tempo, eq(Some(999.0)));
+                assert_that!(send("/tempo", 0.0), err());
+                assert_that!(send("/tempo", f32::INFINITY), err());
+                assert_that!(send("/human/ms", 1e30).unwrap().human_ms, eq(Some(500.0)));
+                assert_that!(send("/human/vel", -1.0).unwrap().human_vel, eq(Some(0.0)));
+                assert_that!(send("/human/ms", f32::INFINITY), err());
+                assert_that!(send("/human/vel", f32::NAN), err());
+                assert_that!(send("/order", 1e18).unwrap().order, eq(Some(16)));
+                assert_that!(send("/order", 0.0).unwrap().order, eq(Some(1)));
+                assert_that!(send("/order", f32::NAN), err());
+            });
+
+            ctx.it("takes settings from a udp client", |_| {
+                let transport = Transport::default();
+                let control = Control::new(transport.clone());
+                let server = Server::bind("127.0.0.1:0", control.clone()).unwrap();
+                let client = client(&server);
+                let send = |addr: &str, args| {
+                    client.send(&Message::new(addr, args).encode()).unwrap();
+                };
+                send("/tempo", vec![Arg::Float(90.0)]);
+                send("/seed", vec![Arg::Int(7)]);
+                send("/order", vec![Arg::Int(3)]);
+                send("/stop", vec![]);
+                send("/human/vel", vec![Arg::Int(10)]);
+                let changes = settle(&control);
+                assert_that!(changes.tempo, eq(Some(90.0)));
+                assert_that!(changes.seed, eq(Some(7)));
+                assert_that!(changes.order, eq(Some(3)));
+                assert!(transport.is_paused());
+                // a button being let go
+                send("/play", vec![Arg::Float(0.0)]);
+                send("/human/vel", vec![Arg::Int(10)]);
+                settle(&control);
+                assert!(transport.is_paused());
+            });
+
+            ctx.it("publishes ticks and notes to clients", |_| {
+                let server = Server::bind("127.0.0.1:0", Control::default()).unwrap();
+                let client = client(&server);
+                client
+                    .send(&Message::new("/play", vec![]).encode())
+                    .unwrap();
+                let mut publisher = server.publisher();
+                let start = Instant::now();
+                while publisher.subscribers.lock().unwrap().is_empty() {
+                    assert_that!(start.elapsed(), lt(Duration::from_secs(1)));
+                    thread::sleep(Duration::from_millis(5));
+                }
+                publisher.publish_tick(12).unwrap();
+                publisher.send(Duration::ZERO, &[0x91, 60, 100]).unwrap();
+                publisher.send(Duration::ZERO, &[0xC1, 5]).unwrap();
+                publisher.send(Duration::ZERO, &[0x81, 60, 64]).unwrap();
+                assert_that!(recv(&client), eq(Message::new("/tick", vec![Arg::Int(12)])));
+                let note =
+                    |vel| Message::new("/note", vec![Arg::Int(1), Arg::Int(60), Arg::Int(vel)]);
+                assert_that!(recv(&client), eq(note(100)));
+                assert_that!(recv(&client), eq(note(0)));
+            });
+        }));
+    }
+}
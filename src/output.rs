@@ -0,0 +1,258 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use eyre::{eyre, Result};
+use midir::{MidiOutput, MidiOutputConnection};
+use midly::{live::LiveEvent, num::u28, MetaMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+/// A destination for raw MIDI messages sent by the `Player`.
+///
+/// `at` is the time since playback started at which the message is sent,
+/// including any humanization shift.
+pub trait Output: Send {
+    fn send(&mut self, at: Duration, msg: &[u8]) -> Result<()>;
+
+    /// Send messages held back until `now`, getting when the next one is
+    /// due. Only outputs that hold messages back, like `Router`, need this.
+    fn poll(&mut self, _now: Duration) -> Result<Option<Duration>> {
+        Ok(None)
+    }
+
+    /// Called when the player is done sending, e.g. to write a file.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: Output + ?Sized> Output for Box<T> {
+    fn send(&mut self, at: Duration, msg: &[u8]) -> Result<()> {
+        (**self).send(at, msg)
+    }
+
+    fn poll(&mut self, now: Duration) -> Result<Option<Duration>> {
+        (**self).poll(now)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+/// Sends messages to a midir output port.
+pub struct MidirOutput {
+    conn: MidiOutputConnection,
+}
+
+impl MidirOutput {
+    pub fn connect(client_name: &str, port_name: &str) -> Result<Self> {
+        let midi_out = MidiOutput::new(client_name)?;
+        let out_ports = midi_out.ports();
+        let port = out_ports
+            .iter()
+            .find(|p| {
+                midi_out
+                    .port_name(p)
+                    .map(|name| name == port_name)
+                    .unwrap_or(false)
+            })
+            .ok_or_else(|| eyre!("could not find port"))?;
+        let conn = midi_out
+            .connect(port, "midir-test")
+            .map_err(|e| eyre!("could not connect to {}: {}", port_name, e))?;
+        Ok(Self { conn })
+    }
+}
+
+impl Output for MidirOutput {
+    fn send(&mut self, _at: Duration, msg: &[u8]) -> Result<()> {
+        self.conn.send(msg)?;
+        Ok(())
+    }
+}
+
+/// A message captured by `Capture`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sent {
+    pub at: Duration,
+    pub msg: Vec<u8>,
+}
+
+/// Keeps every message in memory. Clones share the same buffer so a test can
+/// hold on to one while the player owns the other.
+#[derive(Clone, Default)]
+pub struct Capture {
+    sent: Arc<Mutex<Vec<Sent>>>,
+}
+
+impl Capture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get a copy of the captured messages.
+    pub fn sent(&self) -> Vec<Sent> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl Output for Capture {
+    fn send(&mut self, at: Duration, msg: &[u8]) -> Result<()> {
+        self.sent.lock().unwrap().push(Sent {
+            at,
+            msg: msg.to_vec(),
+        });
+        Ok(())
+    }
+}
+
+/// Records messages and writes them to a standard MIDI file on flush.
+pub struct SmfRecorder {
+    path: String,
+    tempo: f32,
+    ticks_per_beat: u16,
+    sent: Vec<Sent>,
+}
+
+impl SmfRecorder {
+    pub fn new(path: impl Into<String>, tempo: f32) -> Self {
+        Self {
+            path: path.into(),
+            tempo,
+            ticks_per_beat: 480,
+            sent: Vec::new(),
+        }
+    }
+
+    fn ticks(&self, at: Duration) -> u32 {
+        (at.as_secs_f64() * self.tempo as f64 / 60.0 * self.ticks_per_beat as f64).round() as u32
+    }
+
+    /// Build the recorded track as an SMF.
+    pub fn smf(&self) -> Smf<'_> {
+        let usec_per_beat = (60_000_000.0 / self.tempo).round() as u32;
+        let mut track = vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(usec_per_beat.into())),
+        }];
+        let mut prev = 0;
+        for sent in &self.sent {
+            if let Ok(LiveEvent::Midi { channel, message }) = LiveEvent::parse(&sent.msg) {
+                let ticks = self.ticks(sent.at).max(prev);
+                track.push(TrackEvent {
+                    delta: (ticks - prev).into(),
+                    kind: TrackEventKind::Midi { channel, message },
+                });
+                prev = ticks;
+            }
+        }
+        track.push(TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+        Smf {
+            header: midly::Header {
+                format: midly::Format::SingleTrack,
+                timing: Timing::Metrical(self.ticks_per_beat.into()),
+            },
+            tracks: vec![track],
+        }
+    }
+}
+
+impl Output for SmfRecorder {
+    fn send(&mut self, at: Duration, msg: &[u8]) -> Result<()> {
+        self.sent.push(Sent {
+            at,
+            msg: msg.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.smf().save(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Sends every message to each of its outputs.
+#[derive(Default)]
+pub struct Tee {
+    outputs: Vec<Box<dyn Output>>,
+}
+
+impl Tee {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, output: impl Output + 'static) -> Self {
+        self.outputs.push(Box::new(output));
+        self
+    }
+}
+
+impl Output for Tee {
+    fn send(&mut self, at: Duration, msg: &[u8]) -> Result<()> {
+        self.outputs.iter_mut().try_for_each(|o| o.send(at, msg))
+    }
+
+    fn poll(&mut self, now: Duration) -> Result<Option<Duration>> {
+        let mut next = None;
+        for o in &mut self.outputs {
+            next = match (next, o.poll(now)?) {
+                (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+                (a, b) => a.or(b),
+            };
+        }
+        Ok(next)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.outputs.iter_mut().try_for_each(|o| o.flush())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_output {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    pub(crate) trait Messages {
+        /// Get a copy of the captured messages without their timestamps.
+        fn messages(&self) -> Vec<Vec<u8>>;
+    }
+
+    impl Messages for Capture {
+        fn messages(&self) -> Vec<Vec<u8>> {
+            self.sent().into_iter().map(|s| s.msg).collect()
+        }
+    }
+
+    #[test]
+    fn test_outputs() {
+        rspec::run(&rspec::describe("outputs", Env::default(), |ctx| {
+            ctx.it("tee sends to every output", |_| {
+                let (a, b) = (Capture::new(), Capture::new());
+                let mut tee = Tee::new().with(a.clone()).with(b.clone());
+                tee.send(Duration::from_millis(5), &[0x90, 60, 64]).unwrap();
+                assert_that!(a.messages(), eq(vec![vec![0x90, 60, 64]]));
+                assert_that!(b.messages(), eq(vec![vec![0x90, 60, 64]]));
+            });
+
+            ctx.it("recorder converts time to smf ticks", |_| {
+                let mut rec = SmfRecorder::new("unused.mid", 120.0);
+                rec.send(Duration::ZERO, &[0x90, 60, 64]).unwrap();
+                rec.send(Duration::from_millis(500), &[0x80, 60, 0])
+                    .unwrap();
+                let smf = rec.smf();
+                let deltas: Vec<u32> = smf.tracks[0].iter().map(|e| e.delta.as_int()).collect();
+                assert_that!(deltas, eq(vec![0, 0, 480, 0]));
+            });
+        }));
+    }
+}
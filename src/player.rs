@@ -1,40 +1,131 @@
 use std::{thread::sleep, time::Duration};
 
-use eyre::{ensure, eyre, Result};
-use midir::{MidiOutput, MidiOutputConnection};
+use clap::ArgEnum;
+use eyre::{eyre, Result};
 
 use crate::{
-    notes::Note,
-    sequence::{Dynamic, Event},
+    groove::{Groove, Step, DEFAULT_BEATS_PER_BAR},
+    human::{Humanizer, Model},
+    notes::{Beats, Note},
+    output::{MidirOutput, Output, Tee},
+    sequence::{Curve, Event, DEFAULT_RELEASE},
+    trace::{Entry, Trace},
 };
 
 const NOTE_ON_MSG: u8 = 0x90;
 const NOTE_OFF_MSG: u8 = 0x80;
+const PROGRAM_CHANGE_MSG: u8 = 0xC0;
+const CONTROL_CHANGE_MSG: u8 = 0xB0;
 const HUMAN_MS_RANGE: f64 = 30.0;
 const HUMAN_VEL_RANGE: f64 = 12.0;
 /// Max number of beats the player will let a note ring for.
 const MAX_NOTE_BEATS: u32 = 4;
+/// Slowest tempo, as a tick at 0 bpm would never end.
+const MIN_TEMPO: f32 = 1.0;
+
+/// What to do when a key that is already sounding is played again.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Repeat {
+    /// Send another note on and let the synth decide.
+    #[default]
+    Stack,
+    /// Stop the sounding note first.
+    Retrigger,
+    /// Keep the sounding note going until the later of the two ends.
+    Legato,
+    /// Drop the new note.
+    Ignore,
+}
+
+/// Which sounding key to stop when a new one would go over the polyphony
+/// limit.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Steal {
+    #[default]
+    Oldest,
+    Quietest,
+    Lowest,
+    Highest,
+}
+
+/// A key the player has sounding.
+#[derive(Clone, Copy, Debug)]
+struct Sounding {
+    // the tick # after which the key is expected to stop
+    off: u32,
+    vel: u8,
+    release: u8,
+    // the number of note ons before this one, to find the oldest
+    age: u64,
+}
+
+/// A tempo change spread over a number of ticks.
+#[derive(Clone, Copy, Debug)]
+struct Ramp {
+    from: f32,
+    to: f32,
+    start: u32,
+    ticks: u32,
+    curve: Curve,
+}
+
+impl Ramp {
+    fn tempo_at(&self, tick: u32) -> f32 {
+        let t = (tick.saturating_sub(self.start) as f32 / self.ticks as f32).min(1.0);
+        match self.curve {
+            Curve::Linear => self.from + (self.to - self.from) * t,
+            Curve::Exponential => self.from * (self.to / self.from).powf(t),
+        }
+    }
+
+    fn is_done(&self, tick: u32) -> bool {
+        tick >= self.start + self.ticks
+    }
+}
 
 pub struct Player<'a> {
     client_name: &'a str,
     tempo: f32,
     ticks_per_beat: u32,
+    beats_per_bar: u32,
     tick_dur: Duration,
-    conn_out: Option<MidiOutputConnection>,
+    ramp: Option<Ramp>,
+    out: Option<Box<dyn Output>>,
+    // whether to sleep between events, off when rendering offline
+    realtime: bool,
+    // send note offs as note ons with velocity 0
+    zero_vel_off: bool,
+    repeat: Repeat,
+    // each note ends when the next one on its channel starts
+    mono_legato: bool,
+    max_voices: Option<usize>,
+    steal: Steal,
+    notes_played: u64,
 
     ticks_played: u32,
-    // all keys including the tick # after which they are expected to stop
-    notes_on: [Option<u32>; 128],
+    // time since playback started, including humanization shifts
+    elapsed: Duration,
+    // time since playback started without them
+    intended: Duration,
+    trace: Option<Trace>,
+    // channel notes are sent on, set by `Event::Channel`
+    channel: u8,
+    // indexed by channel then key
+    notes_on: [[Option<Sounding>; 128]; 16],
+    // (channel, key)s silenced by `pause` to be struck again by `resume`
+    paused: Vec<(u8, u8, Sounding)>,
     // timeshift is to correct a prior humanization delay
     timeshift: f64,
-    human_ms_range: f64,
-    human_vel_range: f64,
+    human: Humanizer,
+    groove: Option<Groove>,
+    // ms the groove shifted the last wait by, undone by the next one
+    groove_ms: f64,
 }
 
 fn add_ms(dur: Duration, ms: f64) -> Duration {
     match ms {
-        x if x >= 0.0 => dur + Duration::from_millis(ms as u64),
-        _ => dur.saturating_sub(Duration::from_millis(-ms as u64)),
+        x if x >= 0.0 => dur + Duration::from_secs_f64(ms / 1000.0),
+        _ => dur.saturating_sub(Duration::from_secs_f64(-ms / 1000.0)),
     }
 }
 
@@ -46,12 +137,27 @@ impl<'a> Player<'a> {
             tempo: 120.0,
             tick_dur: Duration::from_secs_f32(60.0 / 120.0 / tpb as f32),
             ticks_per_beat: tpb,
-            conn_out: None,
+            beats_per_bar: DEFAULT_BEATS_PER_BAR,
+            ramp: None,
+            out: None,
+            realtime: true,
+            zero_vel_off: false,
+            repeat: Repeat::default(),
+            mono_legato: false,
+            max_voices: None,
+            steal: Steal::default(),
+            notes_played: 0,
             ticks_played: 0,
-            notes_on: [None; 128],
+            elapsed: Duration::ZERO,
+            intended: Duration::ZERO,
+            trace: None,
+            channel: 0,
+            notes_on: [[None; 128]; 16],
+            paused: Vec::new(),
             timeshift: 0.0,
-            human_ms_range: HUMAN_MS_RANGE,
-            human_vel_range: HUMAN_VEL_RANGE,
+            human: Humanizer::new(HUMAN_MS_RANGE, HUMAN_VEL_RANGE),
+            groove: None,
+            groove_ms: 0.0,
         }
     }
 
@@ -60,116 +166,465 @@ impl<'a> Player<'a> {
         self.ticks_per_beat = ticks_per_beat.into();
     }
 
+    /// Set the beats in a bar for accents and grooves.
+    pub fn set_beats_per_bar(&mut self, beats_per_bar: u32) {
+        self.beats_per_bar = beats_per_bar.max(1);
+        self.human.set_beats_per_bar(self.beats_per_bar);
+    }
+
     pub fn tempo(&self) -> f32 {
         self.tempo
     }
 
+    /// Set the tempo straight away, cancelling any ramp. Tempos below 1
+    /// bpm are taken as 1.
     pub fn set_tempo(&mut self, tempo: impl Into<f32>) {
-        self.tempo = tempo.into();
-        self.tick_dur = Duration::from_secs_f32(60.0 / self.tempo / self.ticks_per_beat as f32);
+        self.ramp = None;
+        self.apply_tempo(tempo.into().max(MIN_TEMPO));
+    }
+
+    fn apply_tempo(&mut self, tempo: f32) {
+        self.tempo = tempo;
+        self.tick_dur = self.tick_dur_at(tempo);
+    }
+
+    fn tick_dur_at(&self, tempo: f32) -> Duration {
+        Duration::from_secs_f32(60.0 / tempo / self.ticks_per_beat as f32)
+    }
+
+    /// Move the tempo to `tempo` over the next `beats`.
+    pub fn ramp_tempo(&mut self, tempo: impl Into<f32>, beats: u32, curve: Curve) {
+        let to = tempo.into().max(MIN_TEMPO);
+        let ticks = beats * self.ticks_per_beat;
+        if ticks == 0 {
+            self.set_tempo(to);
+            return;
+        }
+        self.ramp = Some(Ramp {
+            from: self.tempo,
+            to,
+            start: self.ticks_played,
+            ticks,
+            curve,
+        });
     }
 
     pub fn connect(&mut self, port_name: &str) -> Result<&Self> {
-        let midi_out = MidiOutput::new(self.client_name)?;
-        let out_ports = midi_out.ports();
-        let port = out_ports
-            .iter()
-            .find(|p| {
-                midi_out
-                    .port_name(p)
-                    .map(|name| name == port_name)
-                    .unwrap_or(false)
-            })
-            .ok_or_else(|| eyre!("could not find port"))?;
-        self.conn_out = Some(midi_out.connect(port, "midir-test")?);
+        let out = MidirOutput::connect(self.client_name, port_name)?;
+        self.set_output(out);
         Ok(self)
     }
 
+    /// Send everything the player plays to `out` instead of a midir port.
+    pub fn set_output(&mut self, out: impl Output + 'static) {
+        self.out = Some(Box::new(out));
+    }
+
+    /// Also send everything the player plays to `out`.
+    pub fn add_output(&mut self, out: impl Output + 'static) {
+        self.out = Some(match self.out.take() {
+            Some(prev) => Box::new(Tee::new().with(prev).with(out)),
+            None => Box::new(out),
+        });
+    }
+
+    /// Write every message sent to `trace`, with its timing and the keys
+    /// sounding after it.
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+        self.trace = trace;
+    }
+
+    /// Set whether the player waits in real time. Without it events are sent
+    /// as fast as possible, still stamped with the time they would play at.
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime;
+    }
+
+    /// Send note offs as note ons with velocity 0 so running status can cover
+    /// whole passages. Release velocities are dropped.
+    pub fn set_zero_vel_off(&mut self, zero_vel_off: bool) {
+        self.zero_vel_off = zero_vel_off;
+    }
+
+    /// Set what happens when a sounding key is played again.
+    pub fn set_repeat(&mut self, repeat: Repeat) {
+        self.repeat = repeat;
+    }
+
+    /// End each note when the next one on its channel starts, overlapping
+    /// them slightly so mono synths slide instead of retriggering. Notes
+    /// without a length are held until then.
+    pub fn set_mono_legato(&mut self, mono_legato: bool) {
+        self.mono_legato = mono_legato;
+    }
+
+    /// Limit how many keys sound at once, stopping one picked by `steal` to
+    /// make room for a new one.
+    pub fn set_max_voices(&mut self, max_voices: Option<usize>, steal: Steal) {
+        self.max_voices = max_voices.map(|n| n.max(1));
+        self.steal = steal;
+    }
+
+    // stop keys until there is room for one more
+    fn steal_voices(&mut self) -> Result<()> {
+        let max = match self.max_voices {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let mut sounding = self.sounding();
+        while sounding.len() >= max {
+            let (i, _) = sounding
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, &(_, key, s))| match self.steal {
+                    Steal::Oldest => s.age,
+                    Steal::Quietest => s.vel as u64,
+                    Steal::Lowest => key as u64,
+                    Steal::Highest => u8::MAX as u64 - key as u64,
+                })
+                .expect("sounding is not empty");
+            let (channel, key, s) = sounding.remove(i);
+            self.stop_key(channel, key, s.release)?;
+        }
+        Ok(())
+    }
+
+    fn sleep(&self, dur: Duration) {
+        if self.realtime {
+            sleep(dur);
+        }
+    }
+
+    // let `dur` pass, sending anything the output holds back when it is due
+    fn pass(&mut self, dur: Duration) -> Result<()> {
+        let until = self.elapsed + dur;
+        while let Some(due) = self.poll()?.filter(|&due| due < until) {
+            self.sleep(due.saturating_sub(self.elapsed));
+            self.elapsed = self.elapsed.max(due);
+        }
+        self.sleep(until.saturating_sub(self.elapsed));
+        self.elapsed = until;
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Option<Duration>> {
+        match self.out.as_mut() {
+            Some(out) => out.poll(self.elapsed),
+            None => Ok(None),
+        }
+    }
+
+    fn send(&mut self, msg: &[u8]) -> Result<()> {
+        self.send_humanized(msg, 0)
+    }
+
+    // like `send`, tracing the humanization added to a note on's velocity
+    fn send_humanized(&mut self, msg: &[u8], vel_offset: i16) -> Result<()> {
+        let at = self.elapsed;
+        if let Some(mut trace) = self.trace.take() {
+            let entry = Entry {
+                tick: self.ticks_played,
+                intended: self.intended,
+                at,
+                actual: trace.elapsed(),
+                vel_offset,
+                msg: msg.to_vec(),
+                notes_on: self
+                    .sounding()
+                    .iter()
+                    .map(|&(ch, key, _)| (ch, key))
+                    .collect(),
+            };
+            let recorded = trace.record(&entry);
+            self.trace = Some(trace);
+            recorded?;
+        }
+        self.out
+            .as_mut()
+            .ok_or_else(|| eyre!("not connected to out port"))?
+            .send(at, msg)
+    }
+
+    /// Play one note, blocking until it ends.
     pub fn play(&mut self, note: &Note) -> Result<()> {
-        ensure!(self.conn_out.is_some(), "not connected to out port");
-        let conn = self.conn_out.as_mut().unwrap();
-        let _ = conn.send(&[NOTE_ON_MSG, note.note(), note.vel()]);
-        sleep(note.beats().from_bpm(self.tempo));
-        let _ = conn.send(&[NOTE_OFF_MSG, note.note(), note.vel()]);
+        self.play_notes([(Beats::default(), note.clone())])
+    }
+
+    /// Play notes that each start `onset` beats from now, so chords and
+    /// overlapping lines sound together. Notes are humanized and limited
+    /// like `Event::PlayNoteTicks`, at their exact velocity. Blocks until the
+    /// last note ends.
+    pub fn play_notes(&mut self, notes: impl IntoIterator<Item = (Beats, Note)>) -> Result<()> {
+        let tpb = self.ticks_per_beat;
+        // (onset, note) in ticks from now
+        let mut notes: Vec<_> = notes
+            .into_iter()
+            .map(|(onset, note)| (onset.ticks(tpb), note))
+            .collect();
+        notes.sort_by_key(|&(onset, _)| onset);
+        let mut times: Vec<u32> = notes
+            .iter()
+            .flat_map(|(onset, note)| [*onset, onset.saturating_add(note.beats().ticks(tpb))])
+            .collect();
+        times.sort_unstable();
+        times.dedup();
+        let (start, mut notes) = (self.ticks_played, notes.into_iter().peekable());
+        for at in times {
+            self.event(&Event::wait(start.saturating_add(at) - self.ticks_played))?;
+            // end notes as they are due, not on the tick after like sequences
+            let ticks_played = self.ticks_played;
+            self.sounding()
+                .iter()
+                .filter(|&(_, _, s)| s.off <= ticks_played)
+                .try_for_each(|&(channel, key, s)| self.stop_key(channel, key, s.release))?;
+            while let Some((_, note)) = notes.next_if(|&(onset, _)| onset == at) {
+                let ticks = note.beats().ticks(tpb);
+                self.play_key(note.note(), note.vel(), ticks, DEFAULT_RELEASE)?;
+            }
+        }
         Ok(())
     }
 
-    fn play_key(&mut self, key: u8, dynamic: Dynamic, max_ticks: u32) {
-        let conn = self.conn_out.as_mut().unwrap();
-        let off_tick = self.ticks_played + max_ticks;
-        self.notes_on[key as usize] = Some(off_tick);
-        let vel_range = self.human_vel_range;
-        let human = (rand::random::<f64>() * vel_range - vel_range).round() as i8;
+    fn play_key(&mut self, key: u8, base_vel: u8, max_ticks: u32, release: u8) -> Result<()> {
+        let human = self
+            .human
+            .vel(self.ticks_played, self.ticks_per_beat)
+            .saturating_add(self.groove_step(self.ticks_played).map_or(0, |s| s.vel));
         let vel = match human {
-            x if x >= 0 => dynamic.vel().saturating_add(human as u8),
-            _ => dynamic.vel().saturating_sub(-human as u8),
-        };
+            x if x >= 0 => base_vel.saturating_add(human as u8),
+            _ => base_vel.saturating_sub(-human as u8),
+        }
+        .clamp(1, 127);
+        let (channel, off) = (self.channel, self.ticks_played.saturating_add(max_ticks));
+        if let Some(s) = self.notes_on[channel as usize][key as usize] {
+            match self.repeat {
+                Repeat::Stack => {}
+                Repeat::Retrigger => self.stop_key(channel, key, s.release)?,
+                Repeat::Legato => {
+                    let off = s.off.max(off);
+                    self.notes_on[channel as usize][key as usize] = Some(Sounding { off, ..s });
+                    return Ok(());
+                }
+                Repeat::Ignore => return Ok(()),
+            }
+        }
+        // a stacked key keeps its voice
+        if self.notes_on[channel as usize][key as usize].is_none() {
+            self.steal_voices()?;
+        }
+        let age = self.notes_played;
+        self.notes_played += 1;
+        self.notes_on[channel as usize][key as usize] = Some(Sounding {
+            off,
+            vel,
+            release,
+            age,
+        });
         // println!(
         //     "playing {} @ {:?} ({}) for at least {} ticks",
         //     key, dynamic, vel, max_ticks
         // );
-        conn.send(&[NOTE_ON_MSG, key, vel]).unwrap();
+        self.send_humanized(
+            &[NOTE_ON_MSG | channel, key, vel],
+            vel as i16 - base_vel as i16,
+        )?;
+        if self.mono_legato {
+            self.sounding()
+                .iter()
+                .filter(|&&(ch, k, _)| ch == channel && k != key)
+                .try_for_each(|&(ch, k, s)| self.stop_key(ch, k, s.release))?;
+        }
+        Ok(())
+    }
+
+    /// Strike `key` on the current channel at exactly `vel` until
+    /// `release_key`, for notes played live.
+    pub fn strike(&mut self, key: u8, vel: u8) -> Result<()> {
+        self.play_key(key, vel, u32::MAX, DEFAULT_RELEASE)
+    }
+
+    /// Stop `key` on the current channel.
+    pub fn release_key(&mut self, key: u8, release: u8) -> Result<()> {
+        self.stop_key(self.channel, key, release)
+    }
+
+    /// Set the time messages are stamped with, for live playing where time
+    /// passes outside the player.
+    pub fn set_elapsed(&mut self, elapsed: Duration) {
+        self.elapsed = elapsed;
+        self.intended = elapsed;
+    }
+
+    fn send_off(&mut self, channel: u8, key: u8, release: u8) -> Result<()> {
+        match self.zero_vel_off {
+            true => self.send(&[NOTE_ON_MSG | channel, key, 0]),
+            false => self.send(&[NOTE_OFF_MSG | channel, key, release]),
+        }
+    }
+
+    fn stop_key(&mut self, channel: u8, key: u8, release: u8) -> Result<()> {
+        self.notes_on[channel as usize][key as usize] = None;
+        self.send_off(channel, key, release)
     }
 
-    fn stop_key(&mut self, key: u8) {
-        self.notes_on[key as usize] = None;
-        let conn = self.conn_out.as_mut().unwrap();
-        conn.send(&[NOTE_OFF_MSG, key, 0]).unwrap();
+    // (channel, key, sounding) of every sounding key
+    fn sounding(&self) -> Vec<(u8, u8, Sounding)> {
+        self.notes_on
+            .iter()
+            .enumerate()
+            .flat_map(|(channel, keys)| {
+                keys.iter()
+                    .enumerate()
+                    .filter_map(move |(key, s)| s.map(|s| (channel as u8, key as u8, s)))
+            })
+            .collect()
+    }
+
+    /// Stop every sounding key.
+    pub fn all_notes_off(&mut self) -> Result<()> {
+        self.paused.clear();
+        self.sounding()
+            .iter()
+            .try_for_each(|&(channel, key, s)| self.stop_key(channel, key, s.release))
+    }
+
+    /// Stop every sounding key, remembering them so `resume` can strike them
+    /// again.
+    pub fn pause(&mut self) -> Result<()> {
+        let held = self.sounding();
+        held.iter()
+            .try_for_each(|&(channel, key, s)| self.stop_key(channel, key, s.release))?;
+        self.paused = held;
+        Ok(())
     }
 
-    fn wait(&mut self, ticks: u32) {
-        let dur = self.tick_dur * ticks;
+    /// Strike the keys silenced by `pause` again for the rest of their ticks.
+    pub fn resume(&mut self) -> Result<()> {
+        std::mem::take(&mut self.paused)
+            .into_iter()
+            .try_for_each(|(channel, key, sounding)| {
+                self.notes_on[channel as usize][key as usize] = Some(sounding);
+                self.send(&[NOTE_ON_MSG | channel, key, sounding.vel])
+            })
+    }
+
+    fn groove_step(&self, tick: u32) -> Option<Step> {
+        self.groove
+            .as_ref()
+            .and_then(|g| g.step_at(tick, self.ticks_per_beat, self.beats_per_bar))
+    }
+
+    // the duration of the next `ticks`, following any tempo ramp
+    fn ticks_dur(&self, ticks: u32) -> Duration {
+        match self.ramp {
+            None => self.tick_dur * ticks,
+            Some(ramp) => (self.ticks_played..self.ticks_played + ticks)
+                .map(|tick| self.tick_dur_at(ramp.tempo_at(tick)))
+                .sum(),
+        }
+    }
+
+    fn wait(&mut self, ticks: u32) -> Result<()> {
+        let dur = self.ticks_dur(ticks);
+        self.intended += dur;
         let dur = add_ms(dur, self.timeshift);
-        let ms_range = self.human_ms_range;
-        let shift_ms = (rand::random::<f64>() * ms_range - ms_range).round();
+        let shift_ms = self.human.ms().round();
         self.timeshift = -shift_ms;
         let dur = add_ms(dur, shift_ms);
+        let groove_ms = self
+            .groove_step(self.ticks_played + ticks)
+            .map_or(0.0, |s| s.shift * 60_000.0 / self.tempo as f64);
+        let dur = add_ms(dur, groove_ms - self.groove_ms);
+        self.groove_ms = groove_ms;
         // println!("waiting {} ticks ({:?})", ticks, dur);
-        sleep(dur);
+        self.pass(dur)?;
         self.ticks_played += ticks;
+        if let Some(ramp) = self.ramp {
+            self.apply_tempo(ramp.tempo_at(self.ticks_played));
+            if ramp.is_done(self.ticks_played) {
+                self.ramp = None;
+            }
+        }
+        Ok(())
     }
 
     pub fn event(&mut self, event: &Event) -> Result<()> {
-        ensure!(self.conn_out.is_some(), "not connected to out port");
         match event {
             &Event::PlayNote { key, dynamic } => {
-                self.play_key(key.as_int(), dynamic, self.ticks_per_beat * MAX_NOTE_BEATS);
+                let max_ticks = match self.mono_legato {
+                    true => u32::MAX,
+                    false => self.ticks_per_beat * MAX_NOTE_BEATS,
+                };
+                self.play_key(key.as_int(), dynamic.vel(), max_ticks, DEFAULT_RELEASE)?;
             }
             &Event::PlayNoteTicks {
                 key,
                 dynamic,
                 ticks,
+                release,
             } => {
-                self.play_key(key.as_int(), dynamic, ticks);
+                self.play_key(key.as_int(), dynamic.vel(), ticks, release.as_int())?;
             }
-            Event::StopNote { key } => {
+            Event::StopNote { key, release } => {
                 println!("stopping {}", key);
-                self.stop_key(key.as_int());
+                self.stop_key(self.channel, key.as_int(), release.as_int())?;
             }
             Event::Wait { ticks } => {
-                self.wait(*ticks);
+                self.wait(*ticks)?;
+            }
+            Event::Channel { channel } => {
+                self.channel = channel.as_int();
+            }
+            Event::Program { program } => {
+                self.send(&[PROGRAM_CHANGE_MSG | self.channel, program.as_int()])?;
+            }
+            Event::Control { controller, value } => {
+                self.send(&[
+                    CONTROL_CHANGE_MSG | self.channel,
+                    controller.as_int(),
+                    value.as_int(),
+                ])?;
+            }
+            &Event::Tempo { bpm, beats, curve } => {
+                self.ramp_tempo(bpm, beats.into(), curve);
             }
         }
-        self.notes_on
+        let ticks_played = self.ticks_played;
+        self.sounding()
             .iter()
-            .enumerate()
-            .filter(|&(_, end)| end.is_some())
-            .filter(|&(_, &end)| end.unwrap() < self.ticks_played)
-            .map(|(key, _)| key as u8)
-            .collect::<Vec<u8>>()
-            .iter()
-            .for_each(|&key| self.stop_key(key));
-        Ok(())
+            .filter(|&(_, _, s)| s.off < ticks_played)
+            .try_for_each(|&(channel, key, s)| self.stop_key(channel, key, s.release))
     }
 
     /// Set the player's human ms range.
     pub fn set_human_ms_range(&mut self, human_ms_range: f64) {
-        self.human_ms_range = human_ms_range;
+        self.human.set_ms_range(human_ms_range);
     }
 
     /// Set the player's human vel range.
     pub fn set_human_vel_range(&mut self, human_vel_range: f64) {
-        self.human_vel_range = human_vel_range;
+        self.human.set_vel_range(human_vel_range);
+    }
+
+    /// Set the groove the player shifts notes by, e.g. `Groove::swing`.
+    pub fn set_groove(&mut self, groove: Option<Groove>) {
+        self.groove = groove;
+        self.groove_ms = 0.0;
+    }
+
+    /// Set the player's humanization model.
+    pub fn set_human_model(&mut self, model: Model) {
+        self.human.set_model(model);
+    }
+
+    /// Seed the player's humanization so a take can be repeated.
+    pub fn set_human_seed(&mut self, seed: u64) {
+        self.human.set_seed(seed);
+    }
+
+    /// Set the extra velocity the player adds on beats.
+    pub fn set_human_accent(&mut self, accent: f64) {
+        self.human.set_accent(accent);
     }
 
     /// Get a reference to the player's ticks played.
@@ -177,7 +632,35 @@ impl<'a> Player<'a> {
         self.ticks_played
     }
 
-    /// Get a reference to the player's tick duration.
+    /// Send anything the output holds back when it is due, then flush it
+    /// and the trace, e.g. to write a file. Dropping the player does the same but can only
+    /// print errors.
+    pub fn finish(mut self) -> Result<()> {
+        self.flush()
+    }
+
+    // flush the output and trace once, leaving the player without them
+    fn flush(&mut self) -> Result<()> {
+        let flushed = match self.out.take() {
+            Some(out) => self.flush_output(out),
+            None => Ok(()),
+        };
+        let traced = match self.trace.take() {
+            Some(mut trace) => trace.flush(),
+            None => Ok(()),
+        };
+        flushed.and(traced)
+    }
+
+    fn flush_output(&mut self, mut out: Box<dyn Output>) -> Result<()> {
+        while let Some(due) = out.poll(self.elapsed)? {
+            self.sleep(due.saturating_sub(self.elapsed));
+            self.elapsed = self.elapsed.max(due);
+        }
+        out.flush()
+    }
+
+    /// Get a reference to the player's tick duration at the current tempo.
     pub fn tick_dur(&self) -> Duration {
         self.tick_dur
     }
@@ -185,11 +668,339 @@ impl<'a> Player<'a> {
 
 impl<'a> Drop for Player<'a> {
     fn drop(&mut self) {
-        if self.conn_out.is_none() {
-            return;
+        if let Err(e) = self.flush() {
+            eprintln!("failed to flush output: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_player {
+    use super::*;
+    use crate::{
+        output::{test_output::Messages, Capture},
+        routing::Router,
+        trace::{test_trace::Written, Format},
+    };
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    /// Fails to flush, like a file that can't be written.
+    struct Unwritable;
+
+    impl Output for Unwritable {
+        fn send(&mut self, _at: Duration, _msg: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Err(eyre!("no such directory"))
+        }
+    }
+
+    /// A trace file that fails to be written out.
+    struct Unflushable;
+
+    impl std::io::Write for Unflushable {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::StorageFull.into())
         }
+    }
+
+    fn player(out: &Capture) -> Player<'static> {
+        let mut player = Player::new("test");
+        player.set_ticks_per_beat(1u32);
+        player.set_tempo(6000.0);
+        player.set_human_ms_range(0.0);
+        player.set_human_vel_range(0.0);
+        player.set_output(out.clone());
+        player
+    }
+
+    #[test]
+    fn test_player_events() {
+        rspec::run(&rspec::describe("Player::event", Env::default(), |ctx| {
+            ctx.it("fails without an output", |_| {
+                let mut player = Player::new("test");
+                assert_that!(player.event(&Event::play(60, 64)), err());
+            });
+
+            ctx.it("stops notes after their ticks have passed", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                player
+                    .event(&Event::play_ticks(60, 64, 1).with_release(30))
+                    .unwrap();
+                player.event(&Event::wait(1u32)).unwrap();
+                assert_that!(out.messages(), eq(vec![vec![0x90, 60, 64]]));
+                player.event(&Event::wait(1u32)).unwrap();
+                assert_that!(
+                    out.messages(),
+                    eq(vec![vec![0x90, 60, 64], vec![0x80, 60, 30]])
+                );
+            });
+
+            ctx.it("can stop notes with velocity 0 note ons", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                player.set_zero_vel_off(true);
+                player.event(&Event::play_ticks(60, 64, 1)).unwrap();
+                player.event(&Event::stop(60).with_release(30)).unwrap();
+                assert_that!(
+                    out.messages(),
+                    eq(vec![vec![0x90, 60, 64], vec![0x90, 60, 0]])
+                );
+            });
+
+            ctx.it("ramps the tempo over beats", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                let ramp = Event::Tempo {
+                    bpm: 12000,
+                    beats: 2,
+                    curve: Curve::Linear,
+                };
+                player.event(&ramp).unwrap();
+                player.event(&Event::wait(2u32)).unwrap();
+                player.event(&Event::stop(60)).unwrap();
+                // 10ms at 6000bpm then 6.7ms at 9000bpm
+                let at = out.sent()[0].at.as_micros();
+                assert_that!(at, is(all!(gt(16_600), lt(16_700))));
+                assert_that!(player.tempo(), eq(12000.0));
+            });
+
+            ctx.it("takes tempos below 1 bpm as 1", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                player.event(&Event::tempo(0)).unwrap();
+                assert_that!(player.tempo(), eq(1.0));
+                assert_that!(player.tick_dur(), eq(Duration::from_secs(60)));
+            });
+
+            ctx.it("swings off-beats", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                player.set_ticks_per_beat(2u32);
+                player.set_tempo(6000.0);
+                player.set_groove(Some(Groove::swing(75.0, 2).unwrap()));
+                player.event(&Event::wait(1u32)).unwrap();
+                player.event(&Event::stop(60)).unwrap();
+                player.event(&Event::wait(1u32)).unwrap();
+                player.event(&Event::stop(60)).unwrap();
+                let at: Vec<u128> = out.sent().iter().map(|s| s.at.as_micros()).collect();
+                assert_that!(at, eq(vec![7_500, 10_000]));
+            });
+
+            ctx.it(
+                "sends program and control changes on the current channel",
+                |_| {
+                    let out = Capture::new();
+                    let mut player = player(&out);
+                    player.event(&Event::channel(2)).unwrap();
+                    player.event(&Event::program(40)).unwrap();
+                    player.event(&Event::control(64, 127)).unwrap();
+                    assert_that!(
+                        out.messages(),
+                        eq(vec![vec![0xC2, 40], vec![0xB2, 64, 127]])
+                    );
+                },
+            );
+
+            ctx.it("traces messages with their timing and held keys", |_| {
+                let out = Capture::new();
+                let written = Written::default();
+                let mut player = player(&out);
+                player.set_trace(Some(Trace::new(written.clone(), Format::Jsonl)));
+                player.set_groove(Some(Groove::swing(75.0, 1).unwrap()));
+                player.event(&Event::play_ticks(60, 64, 1)).unwrap();
+                player.event(&Event::wait(2u32)).unwrap();
+                let lines = written.lines();
+                assert_that!(lines.len(), eq(2));
+                assert!(lines[0].contains(r#""tick":0,"intended_ms":0.000"#));
+                assert!(lines[0].contains(r#""msg":"90 3c 40","notes_on":[[0,60]]"#));
+                assert!(lines[1].contains(r#""tick":2,"intended_ms":20.000"#));
+                assert!(lines[1].contains(r#""notes_on":[]"#));
+            });
+
+            ctx.it("sends held back messages as time passes", |_| {
+                let (a, b) = (Capture::new(), Capture::new());
+                let mut player = player(&a);
+                let mut router = Router::new("a", a.clone())
+                    .with_port("b", b.clone())
+                    .with_latencies(&["b=25".parse().unwrap()])
+                    .unwrap();
+                router.route("0=".parse().unwrap()).unwrap();
+                router.route("0=b".parse().unwrap()).unwrap();
+                player.set_output(router);
+                player.event(&Event::play(60, 64)).unwrap();
+                player.event(&Event::wait(1u32)).unwrap();
+                assert_that!(a.sent().len(), eq(0));
+                player.event(&Event::wait(2u32)).unwrap();
+                assert_that!(a.sent()[0].at, eq(Duration::from_millis(25)));
+                assert_that!(b.sent()[0].at, eq(Duration::ZERO));
+            });
+
+            ctx.it("plays chords and overlapping notes", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                player.set_ticks_per_beat(2u32);
+                player.set_tempo(6000.0);
+                let chord = [Note::from(60), Note::from(64)];
+                let late = Note::from(67).with_vel(100).with_beats(1.0);
+                player
+                    .play_notes(
+                        chord
+                            .iter()
+                            .map(|n| (Beats::default(), n.clone()))
+                            .chain([(Beats::from(0.5), late)]),
+                    )
+                    .unwrap();
+                let sent: Vec<(u128, Vec<u8>)> = out
+                    .sent()
+                    .into_iter()
+                    .map(|s| (s.at.as_millis(), s.msg))
+                    .collect();
+                assert_that!(
+                    sent,
+                    eq(vec![
+                        (0, vec![0x90, 60, 64]),
+                        (0, vec![0x90, 64, 64]),
+                        (5, vec![0x90, 67, 100]),
+                        (10, vec![0x80, 60, 64]),
+                        (10, vec![0x80, 64, 64]),
+                        (15, vec![0x80, 67, 64]),
+                    ])
+                );
+            });
+
+            [
+                (
+                    "stacks repeated keys",
+                    Repeat::Stack,
+                    vec![vec![0x90, 60, 64], vec![0x90, 60, 88]],
+                ),
+                (
+                    "retriggers repeated keys",
+                    Repeat::Retrigger,
+                    vec![vec![0x90, 60, 64], vec![0x80, 60, 64], vec![0x90, 60, 88]],
+                ),
+                (
+                    "extends repeated keys with legato",
+                    Repeat::Legato,
+                    vec![vec![0x90, 60, 64]],
+                ),
+                (
+                    "ignores repeated keys",
+                    Repeat::Ignore,
+                    vec![vec![0x90, 60, 64]],
+                ),
+            ]
+            .iter()
+            .for_each(|(name, repeat, expected)| {
+                let (repeat, expected) = (*repeat, expected.clone());
+                ctx.it(name, move |_| {
+                    let out = Capture::new();
+                    let mut player = player(&out);
+                    player.set_repeat(repeat);
+                    player.event(&Event::play_ticks(60, 64, 2)).unwrap();
+                    player.event(&Event::wait(1u32)).unwrap();
+                    player.event(&Event::play_ticks(60, 88, 2)).unwrap();
+                    assert_that!(out.messages(), eq(expected.clone()));
+                });
+            });
+
+            ctx.it("holds legato keys until the later note ends", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                player.set_repeat(Repeat::Legato);
+                player.event(&Event::play_ticks(60, 64, 1)).unwrap();
+                player.event(&Event::play_ticks(60, 64, 3)).unwrap();
+                player.event(&Event::wait(2u32)).unwrap();
+                assert_that!(out.messages().len(), eq(1));
+                player.event(&Event::wait(2u32)).unwrap();
+                assert_that!(out.messages().len(), eq(2));
+            });
+
+            ctx.it("ends notes when the next starts in mono legato", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                player.set_mono_legato(true);
+                player.event(&Event::play(60, 64)).unwrap();
+                player.event(&Event::wait(5u32)).unwrap();
+                player.event(&Event::play(62, 64)).unwrap();
+                assert_that!(
+                    out.messages(),
+                    eq(vec![
+                        vec![0x90, 60, 64],
+                        vec![0x90, 62, 64],
+                        vec![0x80, 60, 64]
+                    ])
+                );
+            });
+
+            [
+                ("steals the oldest voice", Steal::Oldest, 62),
+                ("steals the quietest voice", Steal::Quietest, 64),
+                ("steals the lowest voice", Steal::Lowest, 60),
+                ("steals the highest voice", Steal::Highest, 64),
+            ]
+            .iter()
+            .for_each(|&(name, steal, stolen)| {
+                ctx.it(name, move |_| {
+                    let out = Capture::new();
+                    let mut player = player(&out);
+                    player.set_max_voices(Some(3), steal);
+                    player.event(&Event::play_ticks(62, 64, 4)).unwrap();
+                    player.event(&Event::play_ticks(64, 16, 4)).unwrap();
+                    player.event(&Event::play_ticks(60, 64, 4)).unwrap();
+                    player.event(&Event::play_ticks(55, 64, 4)).unwrap();
+                    assert_that!(
+                        out.messages()[3..].to_vec(),
+                        eq(vec![vec![0x80, stolen, 64], vec![0x90, 55, 64]])
+                    );
+                });
+            });
+
+            ctx.it("steals nothing for a key already sounding", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                player.set_max_voices(Some(2), Steal::Oldest);
+                player.event(&Event::play_ticks(60, 64, 4)).unwrap();
+                player.event(&Event::play_ticks(62, 64, 4)).unwrap();
+                player.event(&Event::play_ticks(60, 64, 4)).unwrap();
+                assert_that!(
+                    out.messages(),
+                    eq(vec![
+                        vec![0x90, 60, 64],
+                        vec![0x90, 62, 64],
+                        vec![0x90, 60, 64]
+                    ])
+                );
+            });
+
+            ctx.it("reports a failed flush when finished", |_| {
+                let mut unwritten = Player::new("test");
+                unwritten.set_output(Unwritable);
+                assert_that!(unwritten.finish(), err());
+                let mut untraced = player(&Capture::new());
+                untraced.set_trace(Some(Trace::new(Unflushable, Format::Csv)));
+                untraced.event(&Event::play(60, 64)).unwrap();
+                assert_that!(untraced.finish(), err());
+            });
 
-        let conn = self.conn_out.take().unwrap();
-        conn.close();
+            ctx.it("timestamps messages with elapsed time", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                player.event(&Event::wait(2u32)).unwrap();
+                player.event(&Event::stop(60)).unwrap();
+                assert_that!(out.sent()[0].at, eq(Duration::from_millis(20)));
+            });
+        }));
     }
 }
@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use eyre::Result;
+
+use crate::output::{Output, Sent};
+
+/// Samples rendered at a time while waiting for the tail to die away.
+const TAIL_BLOCK: usize = 1024;
+/// Longest tail rendered after the last message, in seconds.
+const MAX_TAIL_SECS: u32 = 10;
+
+/// Something that turns note messages into mono audio.
+pub trait Instrument: Send {
+    fn sample_rate(&self) -> u32;
+    /// Switch `channel` to a GM program, for instruments that have several.
+    fn program_change(&mut self, _channel: u8, _program: u8) {}
+    fn note_on(&mut self, channel: u8, key: u8, vel: u8);
+    fn note_off(&mut self, channel: u8, key: u8);
+    /// Release every held note, e.g. at the end of a render.
+    fn release_all(&mut self);
+    /// Fill `out` with the next samples.
+    fn render(&mut self, out: &mut [f32]);
+    /// Whether every note has died away.
+    fn is_silent(&self) -> bool;
+}
+
+/// Renders messages through an `Instrument` and writes a 16-bit mono WAV
+/// file on flush, so no audio or MIDI device is needed.
+pub struct WavRenderer {
+    path: String,
+    instrument: Box<dyn Instrument>,
+    sent: Vec<Sent>,
+}
+
+impl WavRenderer {
+    pub fn new(path: impl Into<String>, instrument: impl Instrument + 'static) -> Self {
+        Self {
+            path: path.into(),
+            instrument: Box::new(instrument),
+            sent: Vec::new(),
+        }
+    }
+
+    fn play(&mut self, msg: &[u8]) {
+        let (status, channel) = match msg.first() {
+            Some(&b) => (b & 0xF0, b & 0x0F),
+            None => return,
+        };
+        match (status, msg.get(1), msg.get(2)) {
+            (0x90, Some(&key), Some(&vel)) if vel > 0 => self.instrument.note_on(channel, key, vel),
+            (0x80 | 0x90, Some(&key), _) => self.instrument.note_off(channel, key),
+            (0xC0, Some(&program), _) => self.instrument.program_change(channel, program),
+            _ => {}
+        }
+    }
+
+    /// Render everything sent so far, letting held notes ring out at the end.
+    pub fn render(&mut self) -> Vec<f32> {
+        let rate = self.instrument.sample_rate() as f64;
+        let mut sent = std::mem::take(&mut self.sent);
+        sent.sort_by_key(|s| s.at);
+        let mut samples = Vec::new();
+        for s in &sent {
+            let at = (s.at.as_secs_f64() * rate).round() as usize;
+            if at > samples.len() {
+                let from = samples.len();
+                samples.resize(at, 0.0);
+                self.instrument.render(&mut samples[from..]);
+            }
+            self.play(&s.msg);
+        }
+        self.sent = sent;
+        self.instrument.release_all();
+        let max_len = samples.len() + (MAX_TAIL_SECS * self.instrument.sample_rate()) as usize;
+        while !self.instrument.is_silent() && samples.len() < max_len {
+            let from = samples.len();
+            samples.resize(from + TAIL_BLOCK, 0.0);
+            self.instrument.render(&mut samples[from..]);
+        }
+        samples
+    }
+}
+
+impl Output for WavRenderer {
+    fn send(&mut self, at: Duration, msg: &[u8]) -> Result<()> {
+        self.sent.push(Sent {
+            at,
+            msg: msg.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: self.instrument.sample_rate(),
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&self.path, spec)?;
+        for sample in self.render() {
+            writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+        }
+        writer.finalize()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_render {
+    use super::*;
+    use crate::synth::{Synth, Waveform};
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    fn renderer(path: &str) -> WavRenderer {
+        let mut r = WavRenderer::new(path, Synth::new(1000, Waveform::Sine));
+        r.send(Duration::from_millis(100), &[0x90, 69, 100])
+            .unwrap();
+        r.send(Duration::from_millis(600), &[0x80, 69, 0]).unwrap();
+        r
+    }
+
+    #[test]
+    fn test_wav_renderer() {
+        rspec::run(&rspec::describe("WavRenderer", Env::default(), |ctx| {
+            ctx.it("places notes at their time", |_| {
+                let samples = renderer("unused.wav").render();
+                assert!(samples[..100].iter().all(|&s| s == 0.0));
+                assert!(samples[100..600].iter().any(|&s| s.abs() > 0.05));
+                // the release tail is rendered after the last message
+                assert_that!(samples.len(), gt(600));
+            });
+
+            ctx.it("writes a wav file", |_| {
+                let path = std::env::temp_dir().join("test_wav_renderer.wav");
+                let path = path.to_str().unwrap();
+                renderer(path).flush().unwrap();
+                let reader = hound::WavReader::open(path).unwrap();
+                assert_that!(reader.spec().sample_rate, eq(1000));
+                assert_that!(reader.duration(), gt(600));
+                std::fs::remove_file(path).unwrap();
+            });
+        }));
+    }
+}
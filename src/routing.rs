@@ -0,0 +1,293 @@
+use std::{fs, str::FromStr, time::Duration};
+
+use eyre::{ensure, eyre, Result};
+
+use crate::{
+    latency::Latency,
+    output::{MidirOutput, Output},
+};
+
+/// Sends one source channel (or track, see `midi::Channels`) to a port.
+///
+/// Written as `source=[port][@channel][,transpose[,velocity]]`, e.g.
+/// `1=Synth B@3,-12,0.8` sends source 1 to channel 3 of "Synth B" an octave
+/// down at 80% velocity. An empty port is the default port. The channel
+/// isn't marked with `:` as ALSA port names end in `client:port`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    pub source: u8,
+    pub port: Option<String>,
+    pub channel: Option<u8>,
+    pub transpose: i8,
+    pub velocity: f64,
+}
+
+impl FromStr for Route {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (source, rest) = s
+            .split_once('=')
+            .ok_or_else(|| eyre!("route needs a source: {}", s))?;
+        let source: u8 = source.trim().parse()?;
+        ensure!(source < 16, "route source must be 0-15");
+        let mut parts = rest.split(',');
+        let dest = parts.next().unwrap_or_default().trim();
+        let (port, channel) = match dest.rsplit_once('@') {
+            Some((port, channel)) => (port, Some(channel.trim().parse::<u8>()?)),
+            None => (dest, None),
+        };
+        ensure!(channel.is_none_or(|c| c < 16), "route channel must be 0-15");
+        let transpose = match parts.next() {
+            Some(t) => t.trim().parse()?,
+            None => 0,
+        };
+        let velocity = match parts.next() {
+            Some(v) => v.trim().parse()?,
+            None => 1.0,
+        };
+        ensure!(parts.next().is_none(), "too many parts in route: {}", s);
+        Ok(Self {
+            source,
+            port: (!port.is_empty()).then(|| port.to_string()),
+            channel,
+            transpose,
+            velocity,
+        })
+    }
+}
+
+impl Route {
+    /// Read routes from a file with one route per line and `#` comments.
+    pub fn load(path: &str) -> Result<Vec<Self>> {
+        fs::read_to_string(path)?
+            .lines()
+            .map(|l| l.split('#').next().unwrap_or_default().trim())
+            .filter(|l| !l.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+
+    fn apply(&self, msg: &[u8]) -> Vec<u8> {
+        let mut msg = msg.to_vec();
+        let status = msg[0] & 0xF0;
+        if let Some(channel) = self.channel {
+            msg[0] = status | channel;
+        }
+        // note off, note on and aftertouch all carry a key
+        if matches!(status, 0x80 | 0x90 | 0xA0) && msg.len() > 1 {
+            msg[1] = (msg[1] as i16 + self.transpose as i16).clamp(0, 127) as u8;
+        }
+        if status == 0x90 && msg.len() > 2 && msg[2] > 0 {
+            msg[2] = (msg[2] as f64 * self.velocity).round().clamp(1.0, 127.0) as u8;
+        }
+        msg
+    }
+}
+
+/// Sends each channel message to the ports its source channel is routed to,
+/// and everything else to every port. Unrouted sources go to the default
+/// port unchanged.
+pub struct Router {
+    // the first port is the default
+    ports: Vec<(String, Box<dyn Output>)>,
+    // how long messages to each port are held back, by port
+    holds: Vec<Duration>,
+    // (due, port, message)s waiting for `poll`
+    held: Vec<(Duration, usize, Vec<u8>)>,
+    routes: Vec<(usize, Route)>,
+}
+
+impl Router {
+    pub fn new(default_port: &str, output: impl Output + 'static) -> Self {
+        Self {
+            ports: vec![(default_port.to_string(), Box::new(output))],
+            holds: vec![Duration::ZERO],
+            held: Vec::new(),
+            routes: Vec::new(),
+        }
+    }
+
+    /// Connect to the default port and every port in `routes`.
+    pub fn connect(client_name: &str, default_port: &str, routes: Vec<Route>) -> Result<Self> {
+        let mut router = Self::new(
+            default_port,
+            MidirOutput::connect(client_name, default_port)?,
+        );
+        for route in routes {
+            if let Some(port) = &route.port {
+                if router.port(port).is_none() {
+                    let output = MidirOutput::connect(client_name, port)?;
+                    router = router.with_port(port, output);
+                }
+            }
+            router.route(route)?;
+        }
+        Ok(router)
+    }
+
+    /// Send to ports with more latency ahead of the rest, holding the rest
+    /// back until the player's `poll` catches up, so every port sounds
+    /// together.
+    pub fn with_latencies(mut self, latencies: &[Latency]) -> Result<Self> {
+        for l in latencies {
+            ensure!(self.port(&l.port).is_some(), "no port named {}", l.port);
+        }
+        let latency = |name: &str| {
+            latencies
+                .iter()
+                .filter(|l| l.port == name)
+                .fold(0.0, |max, l| l.ms.max(max))
+        };
+        let slowest = self
+            .ports
+            .iter()
+            .fold(0.0, |max, (name, _)| latency(name).max(max));
+        self.holds = self
+            .ports
+            .iter()
+            .map(|(name, _)| Duration::from_secs_f64((slowest - latency(name)) / 1000.0))
+            .collect();
+        Ok(self)
+    }
+
+    pub fn with_port(mut self, name: &str, output: impl Output + 'static) -> Self {
+        self.ports.push((name.to_string(), Box::new(output)));
+        self.holds.push(Duration::ZERO);
+        self
+    }
+
+    fn port(&self, name: &str) -> Option<usize> {
+        self.ports.iter().position(|(n, _)| n == name)
+    }
+
+    pub fn route(&mut self, route: Route) -> Result<()> {
+        let port = match &route.port {
+            Some(name) => self
+                .port(name)
+                .ok_or_else(|| eyre!("no port named {}", name))?,
+            None => 0,
+        };
+        self.routes.push((port, route));
+        Ok(())
+    }
+
+    fn send_to(&mut self, port: usize, at: Duration, msg: &[u8]) -> Result<()> {
+        match self.holds[port] {
+            Duration::ZERO => self.ports[port].1.send(at, msg),
+            hold => {
+                self.held.push((at + hold, port, msg.to_vec()));
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Output for Router {
+    fn send(&mut self, at: Duration, msg: &[u8]) -> Result<()> {
+        let status = msg.first().copied().unwrap_or_default();
+        if !(0x80..0xF0).contains(&status) {
+            return (0..self.ports.len()).try_for_each(|port| self.send_to(port, at, msg));
+        }
+        let source = status & 0x0F;
+        let mut sends: Vec<_> = self
+            .routes
+            .iter()
+            .filter(|(_, r)| r.source == source)
+            .map(|(port, route)| (*port, route.apply(msg)))
+            .collect();
+        if sends.is_empty() {
+            sends.push((0, msg.to_vec()));
+        }
+        sends
+            .into_iter()
+            .try_for_each(|(port, msg)| self.send_to(port, at, &msg))
+    }
+
+    fn poll(&mut self, now: Duration) -> Result<Option<Duration>> {
+        self.held.sort_by_key(|&(due, _, _)| due);
+        let ready = self.held.iter().take_while(|h| h.0 <= now).count();
+        for (at, port, msg) in self.held.drain(..ready) {
+            self.ports[port].1.send(at, &msg)?;
+        }
+        Ok(self.held.first().map(|&(due, _, _)| due))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.poll(Duration::MAX)?;
+        self.ports.iter_mut().try_for_each(|(_, out)| out.flush())
+    }
+}
+
+#[cfg(test)]
+mod test_routing {
+    use super::*;
+    use crate::output::{test_output::Messages, Capture};
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    #[test]
+    fn test_router() {
+        rspec::run(&rspec::describe("Router", Env::default(), |ctx| {
+            ctx.it("parses routes", |_| {
+                let route: Route = "1=Synth B@3,-12,0.5".parse().unwrap();
+                assert_that!(
+                    route,
+                    eq(Route {
+                        source: 1,
+                        port: Some("Synth B".to_string()),
+                        channel: Some(3),
+                        transpose: -12,
+                        velocity: 0.5,
+                    })
+                );
+                let route: Route = "2=@4".parse().unwrap();
+                assert_that!(route.port, none());
+                assert_that!("16=@1".parse::<Route>(), err());
+                let route: Route = "0=Midi Through:Midi Through Port-0 14:0".parse().unwrap();
+                assert_that!(
+                    route.port.as_deref(),
+                    eq(Some("Midi Through:Midi Through Port-0 14:0"))
+                );
+                assert_that!(route.channel, none());
+            });
+
+            ctx.it("routes sources to ports with transforms", |_| {
+                let (a, b) = (Capture::new(), Capture::new());
+                let mut router = Router::new("a", a.clone()).with_port("b", b.clone());
+                router.route("1=b@3,12,0.5".parse().unwrap()).unwrap();
+                router.send(Duration::ZERO, &[0x91, 60, 100]).unwrap();
+                router.send(Duration::ZERO, &[0x90, 60, 100]).unwrap();
+                assert_that!(a.messages(), eq(vec![vec![0x90, 60, 100]]));
+                assert_that!(b.messages(), eq(vec![vec![0x93, 72, 50]]));
+            });
+
+            ctx.it("holds back ports with less latency", |_| {
+                let (a, b) = (Capture::new(), Capture::new());
+                let mut router = Router::new("a", a.clone())
+                    .with_port("b", b.clone())
+                    .with_latencies(&["b=20".parse().unwrap()])
+                    .unwrap();
+                router.send(Duration::ZERO, &[0xF8]).unwrap();
+                assert_that!(a.sent().len(), eq(0));
+                assert_that!(b.sent()[0].at, eq(Duration::ZERO));
+                let due = Duration::from_millis(20);
+                assert_that!(router.poll(Duration::ZERO).unwrap(), eq(Some(due)));
+                assert_that!(router.poll(due).unwrap(), none());
+                assert_that!(a.sent()[0].at, eq(due));
+            });
+
+            ctx.it("sends a source to every port it is routed to", |_| {
+                let (a, b) = (Capture::new(), Capture::new());
+                let mut router = Router::new("a", a.clone()).with_port("b", b.clone());
+                router.route("0=".parse().unwrap()).unwrap();
+                router.route("0=b".parse().unwrap()).unwrap();
+                router.send(Duration::ZERO, &[0x80, 60, 0]).unwrap();
+                assert_that!(a.messages().len(), eq(1));
+                assert_that!(b.messages().len(), eq(1));
+            });
+        }));
+    }
+}
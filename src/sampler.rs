@@ -0,0 +1,216 @@
+use std::sync::Arc;
+
+use crate::{
+    render::Instrument,
+    sf2::{Looping, SoundFont, Zone, ENV_RANGE_DB},
+};
+
+/// GM channel 10 plays drums from bank 128.
+const DRUM_CHANNEL: u8 = 9;
+const DRUM_BANK: u16 = 128;
+/// Mix level of one voice, leaving headroom for chords.
+const VOICE_GAIN: f64 = 0.5;
+
+fn gain(db: f64) -> f64 {
+    10f64.powf(-db / 20.0)
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Voice {
+    channel: u8,
+    key: u8,
+    zone: Zone,
+    // position in the sound font's samples and how far it moves per sample
+    pos: f64,
+    step: f64,
+    amp: f64,
+    // seconds since the note started
+    age: f64,
+    // (seconds since release, attenuation in dB at release)
+    released: Option<(f64, f64)>,
+}
+
+impl Voice {
+    fn db(&self) -> f64 {
+        match self.released {
+            None => self.zone.env.held_db(self.age),
+            Some((since, from)) => from + ENV_RANGE_DB * since / self.zone.env.release,
+        }
+    }
+
+    fn is_looping(&self) -> bool {
+        self.zone.loop_end > self.zone.loop_start
+            && match self.zone.looping {
+                Looping::None => false,
+                Looping::Continuous => true,
+                Looping::UntilRelease => self.released.is_none(),
+            }
+    }
+
+    fn is_done(&self) -> bool {
+        self.pos >= self.zone.end as f64 || self.db() >= ENV_RANGE_DB && self.released.is_some()
+    }
+}
+
+/// Plays notes with the samples of a SoundFont, choosing presets by the GM
+/// program of each channel.
+pub struct Sampler {
+    font: Arc<SoundFont>,
+    sample_rate: u32,
+    programs: [u8; 16],
+    voices: Vec<Voice>,
+}
+
+impl Sampler {
+    pub fn new(font: Arc<SoundFont>, sample_rate: u32) -> Self {
+        Self {
+            font,
+            sample_rate,
+            programs: [0; 16],
+            voices: Vec::new(),
+        }
+    }
+}
+
+// the bank a channel's programs are in
+fn bank(channel: u8) -> u16 {
+    match channel {
+        DRUM_CHANNEL => DRUM_BANK,
+        _ => 0,
+    }
+}
+
+impl Instrument for Sampler {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn program_change(&mut self, channel: u8, program: u8) {
+        self.programs[channel as usize & 0x0F] = program;
+        let preset = self.font.preset(bank(channel), program);
+        if (preset.bank, preset.program) != (bank(channel), program as u16) {
+            eprintln!(
+                "no preset for program {} on channel {}, playing {}",
+                program, channel, preset.name
+            );
+        }
+    }
+
+    fn note_on(&mut self, channel: u8, key: u8, vel: u8) {
+        self.note_off(channel, key);
+        let preset = self
+            .font
+            .preset(bank(channel), self.programs[channel as usize & 0x0F]);
+        for zone in preset.zones.iter().filter(|z| z.contains(key, vel)) {
+            let cents = (key as f64 - zone.root_key as f64) * 100.0 + zone.tune as f64;
+            let rate = zone.sample_rate as f64 / self.sample_rate as f64;
+            // velocity follows the default SF2 curve, roughly squared
+            let vel = vel as f64 / 127.0;
+            self.voices.push(Voice {
+                channel,
+                key,
+                zone: *zone,
+                pos: zone.start as f64,
+                step: rate * 2f64.powf(cents / 1200.0),
+                amp: vel * vel * gain(zone.attenuation) * VOICE_GAIN,
+                age: 0.0,
+                released: None,
+            });
+        }
+    }
+
+    fn note_off(&mut self, channel: u8, key: u8) {
+        self.voices
+            .iter_mut()
+            .filter(|v| v.channel == channel && v.key == key && v.released.is_none())
+            .for_each(|v| v.released = Some((0.0, v.db())));
+    }
+
+    fn release_all(&mut self) {
+        self.voices
+            .iter_mut()
+            .filter(|v| v.released.is_none())
+            .for_each(|v| v.released = Some((0.0, v.db())));
+    }
+
+    fn render(&mut self, out: &mut [f32]) {
+        let dt = 1.0 / self.sample_rate as f64;
+        let samples = &self.font.samples;
+        for sample in out.iter_mut() {
+            let mut mix = 0.0;
+            for v in self.voices.iter_mut().filter(|v| !v.is_done()) {
+                let i = v.pos as usize;
+                let frac = v.pos.fract();
+                let at = |i: usize| samples.get(i).copied().unwrap_or_default() as f64 / 32768.0;
+                let s = at(i) * (1.0 - frac) + at(i + 1) * frac;
+                mix += s * v.amp * gain(v.db());
+                v.pos += v.step;
+                if v.is_looping() && v.pos >= v.zone.loop_end as f64 {
+                    v.pos -= (v.zone.loop_end - v.zone.loop_start) as f64;
+                }
+                v.age += dt;
+                if let Some((since, _)) = v.released.as_mut() {
+                    *since += dt;
+                }
+            }
+            *sample = mix as f32;
+        }
+        self.voices.retain(|v| !v.is_done());
+    }
+
+    fn is_silent(&self) -> bool {
+        self.voices.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test_sampler {
+    use super::*;
+    use crate::sf2::test_sf2::tiny_sf2;
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    fn sampler() -> Sampler {
+        Sampler::new(Arc::new(SoundFont::parse(&tiny_sf2()).unwrap()), 1000)
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |m, s| m.max(s.abs()))
+    }
+
+    #[test]
+    fn test_sampler() {
+        rspec::run(&rspec::describe("Sampler", Env::default(), |ctx| {
+            ctx.it("loops a held note past the end of its sample", |_| {
+                let mut s = sampler();
+                s.note_on(0, 60, 127);
+                let mut buf = vec![0.0; 1000];
+                s.render(&mut buf);
+                assert_that!(peak(&buf[900..]), gt(0.05));
+                s.release_all();
+                s.render(&mut buf);
+                s.render(&mut buf);
+                assert!(s.is_silent());
+            });
+
+            ctx.it("plays at the pitch of the key", |_| {
+                let mut s = sampler();
+                s.note_on(0, 72, 127);
+                assert_that!(s.voices[0].step, eq(2.0));
+            });
+
+            ctx.it("picks presets by program", |_| {
+                let mut s = sampler();
+                s.program_change(0, 5);
+                s.note_on(0, 72, 127);
+                assert!(s.is_silent());
+                s.note_on(0, 48, 127);
+                assert!(!s.is_silent());
+                s.note_on(1, 72, 127);
+                assert_that!(s.voices.len(), eq(2));
+            });
+        }));
+    }
+}
@@ -1,4 +1,7 @@
-use midly::num::u7;
+use midly::num::{u4, u7};
+
+/// Release velocity for notes whose source had none, per the MIDI spec.
+pub const DEFAULT_RELEASE: u8 = 64;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Dynamic {
@@ -68,6 +71,15 @@ impl Default for Dynamic {
     }
 }
 
+/// The shape of a tempo ramp.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Curve {
+    #[default]
+    Linear,
+    /// Changes by the same ratio every beat, which sounds even to the ear.
+    Exponential,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Event {
     PlayNote {
@@ -78,13 +90,34 @@ pub enum Event {
         key: u7,
         dynamic: Dynamic,
         ticks: u32,
+        release: u7,
     },
     StopNote {
         key: u7,
+        release: u7,
     },
     Wait {
         ticks: u32,
     },
+    /// Play the following notes on `channel`.
+    Channel {
+        channel: u4,
+    },
+    /// Switch the current channel to GM `program`.
+    Program {
+        program: u7,
+    },
+    /// Set a controller of the current channel, e.g. 64 for the sustain pedal.
+    Control {
+        controller: u7,
+        value: u7,
+    },
+    /// Move to `bpm` over `beats`, or straight away if `beats` is 0.
+    Tempo {
+        bpm: u16,
+        beats: u16,
+        curve: Curve,
+    },
 }
 
 impl Event {
@@ -100,11 +133,37 @@ impl Event {
             key: key.into(),
             dynamic: dynamic.into(),
             ticks,
+            release: DEFAULT_RELEASE.into(),
         }
     }
 
     pub fn stop(key: impl Into<u7>) -> Self {
-        Self::StopNote { key: key.into() }
+        Self::StopNote {
+            key: key.into(),
+            release: DEFAULT_RELEASE.into(),
+        }
+    }
+
+    /// Set the release velocity of a note with a length or a stop.
+    pub fn with_release(self, vel: impl Into<u7>) -> Self {
+        match self {
+            Self::PlayNoteTicks {
+                key,
+                dynamic,
+                ticks,
+                ..
+            } => Self::PlayNoteTicks {
+                key,
+                dynamic,
+                ticks,
+                release: vel.into(),
+            },
+            Self::StopNote { key, .. } => Self::StopNote {
+                key,
+                release: vel.into(),
+            },
+            ev => ev,
+        }
     }
 
     pub fn wait(ticks: impl Into<u32>) -> Self {
@@ -112,4 +171,31 @@ impl Event {
             ticks: ticks.into(),
         }
     }
+
+    pub fn channel(channel: impl Into<u4>) -> Self {
+        Self::Channel {
+            channel: channel.into(),
+        }
+    }
+
+    pub fn program(program: impl Into<u7>) -> Self {
+        Self::Program {
+            program: program.into(),
+        }
+    }
+
+    pub fn control(controller: impl Into<u7>, value: impl Into<u7>) -> Self {
+        Self::Control {
+            controller: controller.into(),
+            value: value.into(),
+        }
+    }
+
+    pub fn tempo(bpm: u16) -> Self {
+        Self::Tempo {
+            bpm,
+            beats: 0,
+            curve: Curve::Linear,
+        }
+    }
 }
@@ -0,0 +1,529 @@
+use std::fs;
+
+use eyre::{bail, ensure, eyre, Result};
+
+// generator operators, from the SoundFont 2.01 spec
+const START_ADDRS_OFFSET: usize = 0;
+const END_ADDRS_OFFSET: usize = 1;
+const STARTLOOP_ADDRS_OFFSET: usize = 2;
+const ENDLOOP_ADDRS_OFFSET: usize = 3;
+const START_ADDRS_COARSE_OFFSET: usize = 4;
+const END_ADDRS_COARSE_OFFSET: usize = 12;
+const DELAY_VOL_ENV: usize = 33;
+const ATTACK_VOL_ENV: usize = 34;
+const HOLD_VOL_ENV: usize = 35;
+const DECAY_VOL_ENV: usize = 36;
+const SUSTAIN_VOL_ENV: usize = 37;
+const RELEASE_VOL_ENV: usize = 38;
+const INSTRUMENT: usize = 41;
+const KEY_RANGE: usize = 43;
+const VEL_RANGE: usize = 44;
+const STARTLOOP_ADDRS_COARSE_OFFSET: usize = 45;
+const INITIAL_ATTENUATION: usize = 48;
+const ENDLOOP_ADDRS_COARSE_OFFSET: usize = 50;
+const COARSE_TUNE: usize = 51;
+const FINE_TUNE: usize = 52;
+const SAMPLE_ID: usize = 53;
+const SAMPLE_MODES: usize = 54;
+const OVERRIDING_ROOT_KEY: usize = 58;
+const GENERATORS: usize = 61;
+
+/// Envelope times default to -12000 timecents, about 1ms.
+const MIN_TIMECENTS: i16 = -12000;
+const FULL_RANGE: i16 = 0x7F00;
+const COARSE_OFFSET: i64 = 32768;
+/// Release and decay times are for a drop of this many dB.
+pub const ENV_RANGE_DB: f64 = 100.0;
+
+/// How a zone's sample loops.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Looping {
+    None,
+    Continuous,
+    /// Loop while the key is held then play out the rest of the sample.
+    UntilRelease,
+}
+
+/// A volume envelope. Times are in seconds and `sustain` is an attenuation
+/// in dB.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VolEnv {
+    pub delay: f64,
+    pub attack: f64,
+    pub hold: f64,
+    pub decay: f64,
+    pub sustain: f64,
+    pub release: f64,
+}
+
+impl VolEnv {
+    /// Get the attenuation in dB `t` seconds after the note started.
+    pub fn held_db(&self, t: f64) -> f64 {
+        let t = t - self.delay;
+        if t < 0.0 {
+            ENV_RANGE_DB
+        } else if t < self.attack {
+            // linear in amplitude
+            -20.0 * (t / self.attack).max(1e-5).log10()
+        } else if t < self.attack + self.hold {
+            0.0
+        } else {
+            let t = t - self.attack - self.hold;
+            (ENV_RANGE_DB * t / self.decay).min(self.sustain)
+        }
+    }
+}
+
+/// A sample with everything needed to play it over a key and velocity range.
+/// Sample positions are indices into `SoundFont::samples`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Zone {
+    pub keys: (u8, u8),
+    pub vels: (u8, u8),
+    pub start: usize,
+    pub end: usize,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    pub looping: Looping,
+    pub sample_rate: u32,
+    pub root_key: u8,
+    /// Tuning in cents.
+    pub tune: i32,
+    /// Attenuation in dB.
+    pub attenuation: f64,
+    pub env: VolEnv,
+}
+
+impl Zone {
+    pub fn contains(&self, key: u8, vel: u8) -> bool {
+        (self.keys.0..=self.keys.1).contains(&key) && (self.vels.0..=self.vels.1).contains(&vel)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Preset {
+    pub name: String,
+    pub bank: u16,
+    pub program: u16,
+    pub zones: Vec<Zone>,
+}
+
+/// The presets and sample data of an SF2 file.
+#[derive(Clone, Debug)]
+pub struct SoundFont {
+    pub samples: Vec<i16>,
+    pub presets: Vec<Preset>,
+}
+
+// generator amounts set in one zone
+#[derive(Clone, Copy)]
+struct Gens([Option<i16>; GENERATORS]);
+
+impl Gens {
+    fn parse(gens: &[u8]) -> Self {
+        let mut out = [None; GENERATORS];
+        for g in gens.chunks_exact(4) {
+            let op = u16_at(g, 0) as usize;
+            if op < GENERATORS {
+                out[op] = Some(u16_at(g, 2) as i16);
+            }
+        }
+        Gens(out)
+    }
+
+    // `self` with anything unset taken from the global zone
+    fn or(self, global: Option<Gens>) -> Self {
+        match global {
+            None => self,
+            Some(global) => {
+                let mut out = self.0;
+                out.iter_mut()
+                    .zip(global.0)
+                    .for_each(|(g, global)| *g = g.or(global));
+                Gens(out)
+            }
+        }
+    }
+
+    fn get(&self, op: usize, default: i16) -> i16 {
+        self.0[op].unwrap_or(default)
+    }
+
+    fn range(&self, op: usize) -> (u8, u8) {
+        let raw = self.get(op, FULL_RANGE) as u16;
+        ((raw & 0xFF) as u8, (raw >> 8) as u8)
+    }
+}
+
+fn u16_at(b: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([b[i], b[i + 1]])
+}
+
+fn u32_at(b: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]])
+}
+
+fn name_at(b: &[u8]) -> String {
+    let name = &b[..20];
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+fn timecents(tc: i16) -> f64 {
+    2f64.powf(tc as f64 / 1200.0)
+}
+
+fn intersect(a: (u8, u8), b: (u8, u8)) -> (u8, u8) {
+    (a.0.max(b.0), a.1.min(b.1))
+}
+
+// the (id, body) of each chunk in a RIFF list
+fn chunks(mut data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    let mut out = Vec::new();
+    while data.len() >= 8 {
+        let id = [data[0], data[1], data[2], data[3]];
+        let len = u32_at(data, 4) as usize;
+        ensure!(data.len() >= 8 + len, "truncated chunk");
+        out.push((id, &data[8..8 + len]));
+        // chunks are padded to an even length
+        data = &data[(8 + len + len % 2).min(data.len())..];
+    }
+    Ok(out)
+}
+
+// the body of the LIST chunk of type `kind`
+fn list<'a>(chunks: &[([u8; 4], &'a [u8])], kind: &[u8; 4]) -> Result<&'a [u8]> {
+    chunks
+        .iter()
+        .find(|(id, body)| id == b"LIST" && body.len() >= 4 && &body[..4] == kind)
+        .map(|(_, body)| &body[4..])
+        .ok_or_else(|| eyre!("missing {} list", String::from_utf8_lossy(kind)))
+}
+
+fn chunk<'a>(chunks: &[([u8; 4], &'a [u8])], id: &[u8; 4]) -> Result<&'a [u8]> {
+    chunks
+        .iter()
+        .find(|(i, _)| i == id)
+        .map(|(_, body)| *body)
+        .ok_or_else(|| eyre!("missing {} chunk", String::from_utf8_lossy(id)))
+}
+
+// the generators of each zone in `bags`, starting at `from` up to `to`
+fn zones(bags: &[u8], gens: &[u8], from: usize, to: usize) -> Result<Vec<Gens>> {
+    (from..to)
+        .map(|b| {
+            ensure!((b + 1) * 4 + 2 <= bags.len(), "bag {} out of range", b);
+            let (g0, g1) = (
+                u16_at(bags, b * 4) as usize,
+                u16_at(bags, (b + 1) * 4) as usize,
+            );
+            ensure!(
+                g0 <= g1 && g1 * 4 <= gens.len(),
+                "bad generators in bag {}",
+                b
+            );
+            Ok(Gens::parse(&gens[g0 * 4..g1 * 4]))
+        })
+        .collect()
+}
+
+// split off the global zone, which is the first zone if it lacks `op`
+fn split_global(mut zones: Vec<Gens>, op: usize) -> (Option<Gens>, Vec<Gens>) {
+    match zones.first() {
+        Some(z) if z.0[op].is_none() => {
+            let global = zones.remove(0);
+            (Some(global), zones)
+        }
+        _ => (None, zones),
+    }
+}
+
+struct SampleHeader {
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    sample_rate: u32,
+    pitch: u8,
+    correction: i8,
+}
+
+impl SoundFont {
+    pub fn load(path: &str) -> Result<Self> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        ensure!(
+            data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"sfbk",
+            "not a SoundFont file"
+        );
+        let top = chunks(&data[12..])?;
+        let sdta = chunks(list(&top, b"sdta")?)?;
+        let samples = chunk(&sdta, b"smpl")?
+            .chunks_exact(2)
+            .map(|s| i16::from_le_bytes([s[0], s[1]]))
+            .collect::<Vec<_>>();
+        let pdta = chunks(list(&top, b"pdta")?)?;
+        let (phdr, pbag, pgen) = (
+            chunk(&pdta, b"phdr")?,
+            chunk(&pdta, b"pbag")?,
+            chunk(&pdta, b"pgen")?,
+        );
+        let (inst, ibag, igen) = (
+            chunk(&pdta, b"inst")?,
+            chunk(&pdta, b"ibag")?,
+            chunk(&pdta, b"igen")?,
+        );
+        let shdrs = chunk(&pdta, b"shdr")?
+            .chunks_exact(46)
+            .map(|s| SampleHeader {
+                start: u32_at(s, 20),
+                end: u32_at(s, 24),
+                loop_start: u32_at(s, 28),
+                loop_end: u32_at(s, 32),
+                sample_rate: u32_at(s, 36),
+                pitch: s[40],
+                correction: s[41] as i8,
+            })
+            .collect::<Vec<_>>();
+
+        // instruments as (global zone, zones), the last record is a terminator
+        let insts = inst
+            .chunks_exact(22)
+            .collect::<Vec<_>>()
+            .windows(2)
+            .map(|w| {
+                let zones = zones(ibag, igen, u16_at(w[0], 20).into(), u16_at(w[1], 20).into())?;
+                Ok(split_global(zones, SAMPLE_ID))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut presets = Vec::new();
+        for w in phdr.chunks_exact(38).collect::<Vec<_>>().windows(2) {
+            let pzones = zones(pbag, pgen, u16_at(w[0], 24).into(), u16_at(w[1], 24).into())?;
+            let (pglobal, pzones) = split_global(pzones, INSTRUMENT);
+            let mut zones_out = Vec::new();
+            for pz in pzones {
+                let pz = pz.or(pglobal);
+                let (iglobal, izones) = insts
+                    .get(pz.get(INSTRUMENT, 0) as usize)
+                    .ok_or_else(|| eyre!("missing instrument"))?;
+                for iz in izones {
+                    let iz = iz.or(*iglobal);
+                    let s = shdrs
+                        .get(iz.get(SAMPLE_ID, 0) as usize)
+                        .ok_or_else(|| eyre!("missing sample"))?;
+                    let keys = intersect(pz.range(KEY_RANGE), iz.range(KEY_RANGE));
+                    let vels = intersect(pz.range(VEL_RANGE), iz.range(VEL_RANGE));
+                    if keys.0 > keys.1 || vels.0 > vels.1 {
+                        continue;
+                    }
+                    // preset generators add to instrument ones
+                    let sum = |op, default| iz.get(op, default) as i64 + pz.get(op, 0) as i64;
+                    let time = |op| timecents(sum(op, MIN_TIMECENTS).clamp(-32768, 32767) as i16);
+                    let offset = |base: u32, fine, coarse| {
+                        let at = base as i64
+                            + iz.get(fine, 0) as i64
+                            + iz.get(coarse, 0) as i64 * COARSE_OFFSET;
+                        at.clamp(0, samples.len() as i64) as usize
+                    };
+                    let root_key = match iz.get(OVERRIDING_ROOT_KEY, -1) {
+                        k @ 0..=127 => k as u8,
+                        _ => s.pitch.min(127),
+                    };
+                    zones_out.push(Zone {
+                        keys,
+                        vels,
+                        start: offset(s.start, START_ADDRS_OFFSET, START_ADDRS_COARSE_OFFSET),
+                        end: offset(s.end, END_ADDRS_OFFSET, END_ADDRS_COARSE_OFFSET),
+                        loop_start: offset(
+                            s.loop_start,
+                            STARTLOOP_ADDRS_OFFSET,
+                            STARTLOOP_ADDRS_COARSE_OFFSET,
+                        ),
+                        loop_end: offset(
+                            s.loop_end,
+                            ENDLOOP_ADDRS_OFFSET,
+                            ENDLOOP_ADDRS_COARSE_OFFSET,
+                        ),
+                        looping: match iz.get(SAMPLE_MODES, 0) & 3 {
+                            1 => Looping::Continuous,
+                            3 => Looping::UntilRelease,
+                            _ => Looping::None,
+                        },
+                        sample_rate: s.sample_rate,
+                        root_key,
+                        tune: (sum(COARSE_TUNE, 0) * 100 + sum(FINE_TUNE, 0)) as i32
+                            + s.correction as i32,
+                        attenuation: sum(INITIAL_ATTENUATION, 0).max(0) as f64 / 10.0,
+                        env: VolEnv {
+                            delay: time(DELAY_VOL_ENV),
+                            attack: time(ATTACK_VOL_ENV),
+                            hold: time(HOLD_VOL_ENV),
+                            decay: time(DECAY_VOL_ENV),
+                            sustain: sum(SUSTAIN_VOL_ENV, 0).clamp(0, 1440) as f64 / 10.0,
+                            release: time(RELEASE_VOL_ENV),
+                        },
+                    });
+                }
+            }
+            presets.push(Preset {
+                name: name_at(w[0]),
+                program: u16_at(w[0], 20),
+                bank: u16_at(w[0], 22),
+                zones: zones_out,
+            });
+        }
+        if presets.is_empty() {
+            bail!("no presets in SoundFont");
+        }
+        Ok(Self { samples, presets })
+    }
+
+    /// Get the preset for a GM program, falling back to bank 0 and then to
+    /// the first preset.
+    pub fn preset(&self, bank: u16, program: u8) -> &Preset {
+        let find = |bank| {
+            self.presets
+                .iter()
+                .find(|p| p.bank == bank && p.program == program as u16)
+        };
+        find(bank).or_else(|| find(0)).unwrap_or(&self.presets[0])
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_sf2 {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    fn riff_chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend((body.len() as u32).to_le_bytes());
+        out.extend(body);
+        if body.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    fn riff_list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = kind.to_vec();
+        chunks.iter().for_each(|c| body.extend(c));
+        riff_chunk(b"LIST", &body)
+    }
+
+    fn named(name: &str) -> Vec<u8> {
+        let mut out = name.as_bytes().to_vec();
+        out.resize(20, 0);
+        out
+    }
+
+    fn gen(op: usize, amount: i16) -> Vec<u8> {
+        [(op as u16).to_le_bytes(), amount.to_le_bytes()].concat()
+    }
+
+    fn bag(gen: u16) -> Vec<u8> {
+        [gen.to_le_bytes(), 0u16.to_le_bytes()].concat()
+    }
+
+    /// A SoundFont with a looped square wave as program 0 over every key,
+    /// and as program 5 over keys up to 59 only.
+    pub(crate) fn tiny_sf2() -> Vec<u8> {
+        let smpl: Vec<u8> = (0..100i16)
+            .flat_map(|i| (if i % 10 < 5 { 8000i16 } else { -8000 }).to_le_bytes())
+            .chain([0; 92])
+            .collect();
+        let phdr = |name, program: u16, bag: u16| {
+            [
+                named(name),
+                program.to_le_bytes().to_vec(),
+                0u16.to_le_bytes().to_vec(),
+                bag.to_le_bytes().to_vec(),
+                vec![0; 12],
+            ]
+            .concat()
+        };
+        let inst = |name, bag: u16| [named(name), bag.to_le_bytes().to_vec()].concat();
+        let mut shdr = named("square");
+        [0u32, 100, 10, 90, 1000]
+            .iter()
+            .for_each(|n| shdr.extend(n.to_le_bytes()));
+        shdr.extend([60, 0, 0, 0, 1, 0]);
+        let pdta = riff_list(
+            b"pdta",
+            &[
+                riff_chunk(
+                    b"phdr",
+                    &[phdr("Square", 0, 0), phdr("Low", 5, 1), phdr("EOP", 0, 2)].concat(),
+                ),
+                riff_chunk(b"pbag", &[bag(0), bag(1), bag(3)].concat()),
+                riff_chunk(b"pmod", &[0; 10]),
+                riff_chunk(
+                    b"pgen",
+                    &[
+                        gen(INSTRUMENT, 0),
+                        gen(KEY_RANGE, 0x3B00),
+                        gen(INSTRUMENT, 0),
+                        gen(0, 0),
+                    ]
+                    .concat(),
+                ),
+                riff_chunk(b"inst", &[inst("Square", 0), inst("EOI", 2)].concat()),
+                riff_chunk(b"ibag", &[bag(0), bag(1), bag(3)].concat()),
+                riff_chunk(b"imod", &[0; 10]),
+                riff_chunk(
+                    b"igen",
+                    &[
+                        gen(RELEASE_VOL_ENV, 0),
+                        gen(SAMPLE_MODES, 1),
+                        gen(SAMPLE_ID, 0),
+                        gen(0, 0),
+                    ]
+                    .concat(),
+                ),
+                riff_chunk(b"shdr", &[shdr, vec![0; 46]].concat()),
+            ],
+        );
+        let sdta = riff_list(b"sdta", &[riff_chunk(b"smpl", &smpl)]);
+        let info = riff_list(b"INFO", &[riff_chunk(b"ifil", &[2, 0, 1, 0])]);
+        let mut body = b"sfbk".to_vec();
+        body.extend(info);
+        body.extend(sdta);
+        body.extend(pdta);
+        riff_chunk(b"RIFF", &body)
+    }
+
+    #[test]
+    fn test_sf2() {
+        rspec::run(&rspec::describe("SoundFont", Env::default(), |ctx| {
+            ctx.it("reads presets and zones", |_| {
+                let sf = SoundFont::parse(&tiny_sf2()).unwrap();
+                assert_that!(sf.samples.len(), eq(146));
+                assert_that!(sf.presets.len(), eq(2));
+                let zone = sf.preset(0, 0).zones[0];
+                assert_that!(zone.keys, eq((0, 127)));
+                assert_that!((zone.loop_start, zone.loop_end), eq((10, 90)));
+                assert_that!(zone.looping, eq(Looping::Continuous));
+                assert_that!(zone.root_key, eq(60));
+                // from the instrument's global zone
+                assert_that!(zone.env.release, eq(1.0));
+                assert_that!(sf.preset(0, 5).zones[0].keys, eq((0, 59)));
+            });
+
+            ctx.it("falls back to another preset", |_| {
+                let sf = SoundFont::parse(&tiny_sf2()).unwrap();
+                assert_that!(sf.preset(128, 5).name.as_str(), eq("Low"));
+                assert_that!(sf.preset(0, 40).name.as_str(), eq("Square"));
+            });
+
+            ctx.it("rejects other files", |_| {
+                assert_that!(SoundFont::parse(b"RIFF\0\0\0\0WAVE"), err());
+            });
+        }));
+    }
+}
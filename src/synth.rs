@@ -0,0 +1,241 @@
+use std::f64::consts::PI;
+
+use clap::ArgEnum;
+
+use crate::render::Instrument;
+
+/// Mix level of one voice at full velocity, leaving headroom for chords.
+const VOICE_GAIN: f64 = 0.2;
+
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Waveform {
+    #[default]
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+}
+
+impl Waveform {
+    // `phase` is in cycles, 0..1
+    fn sample(&self, phase: f64) -> f64 {
+        match self {
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Saw => 2.0 * phase - 1.0,
+            Waveform::Square if phase < 0.5 => 1.0,
+            Waveform::Square => -1.0,
+        }
+    }
+}
+
+/// An attack, decay, sustain, release envelope. Times are in seconds and
+/// `sustain` is a level from 0 to 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Adsr {
+    pub attack: f64,
+    pub decay: f64,
+    pub sustain: f64,
+    pub release: f64,
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Self {
+            attack: 0.01,
+            decay: 0.2,
+            sustain: 0.6,
+            release: 0.3,
+        }
+    }
+}
+
+impl Adsr {
+    /// Get the level `t` seconds after the note started.
+    fn held(&self, t: f64) -> f64 {
+        if t < self.attack {
+            t / self.attack
+        } else if t < self.attack + self.decay {
+            1.0 - (1.0 - self.sustain) * (t - self.attack) / self.decay
+        } else {
+            self.sustain
+        }
+    }
+
+    /// Get the level `t` seconds after a note at `from` was released.
+    fn released(&self, from: f64, t: f64) -> f64 {
+        match self.release {
+            r if t >= r => 0.0,
+            r => from * (1.0 - t / r),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Voice {
+    channel: u8,
+    key: u8,
+    // cycles per sample
+    step: f64,
+    phase: f64,
+    amp: f64,
+    // samples since the note started
+    age: u64,
+    // (samples since release, level at release)
+    released: Option<(u64, f64)>,
+}
+
+/// A simple polyphonic synth with one oscillator and envelope per voice.
+pub struct Synth {
+    sample_rate: u32,
+    waveform: Waveform,
+    adsr: Adsr,
+    voices: Vec<Voice>,
+}
+
+impl Synth {
+    pub fn new(sample_rate: u32, waveform: Waveform) -> Self {
+        Self {
+            sample_rate,
+            waveform,
+            adsr: Adsr::default(),
+            voices: Vec::new(),
+        }
+    }
+
+    fn secs(&self, samples: u64) -> f64 {
+        samples as f64 / self.sample_rate as f64
+    }
+
+    fn level(&self, voice: &Voice) -> f64 {
+        match voice.released {
+            None => self.adsr.held(self.secs(voice.age)),
+            Some((since, from)) => self.adsr.released(from, self.secs(since)),
+        }
+    }
+}
+
+impl Instrument for Synth {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn note_on(&mut self, channel: u8, key: u8, vel: u8) {
+        self.note_off(channel, key);
+        let freq = 440.0 * 2f64.powf((key as f64 - 69.0) / 12.0);
+        self.voices.push(Voice {
+            channel,
+            key,
+            step: freq / self.sample_rate as f64,
+            phase: 0.0,
+            amp: vel as f64 / 127.0,
+            age: 0,
+            released: None,
+        });
+    }
+
+    fn note_off(&mut self, channel: u8, key: u8) {
+        let held: Vec<usize> = (0..self.voices.len())
+            .filter(|&i| {
+                let v = &self.voices[i];
+                v.channel == channel && v.key == key && v.released.is_none()
+            })
+            .collect();
+        for i in held {
+            let level = self.level(&self.voices[i]);
+            self.voices[i].released = Some((0, level));
+        }
+    }
+
+    fn release_all(&mut self) {
+        let held: Vec<(u8, u8)> = self.voices.iter().map(|v| (v.channel, v.key)).collect();
+        held.into_iter()
+            .for_each(|(ch, key)| self.note_off(ch, key));
+    }
+
+    fn render(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            let mut mix = 0.0;
+            for i in 0..self.voices.len() {
+                let level = self.level(&self.voices[i]);
+                let v = &mut self.voices[i];
+                mix += self.waveform.sample(v.phase) * level * v.amp * VOICE_GAIN;
+                v.phase = (v.phase + v.step).fract();
+                v.age += 1;
+                if let Some((since, _)) = v.released.as_mut() {
+                    *since += 1;
+                }
+            }
+            *sample = mix as f32;
+        }
+        let (rate, release) = (self.sample_rate as f64, self.adsr.release);
+        self.voices
+            .retain(|v| !matches!(v.released, Some((since, _)) if since as f64 / rate >= release));
+    }
+
+    fn is_silent(&self) -> bool {
+        self.voices.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test_synth {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |m, s| m.max(s.abs()))
+    }
+
+    #[test]
+    fn test_synth() {
+        rspec::run(&rspec::describe("Synth", Env::default(), |ctx| {
+            ctx.it("follows the envelope", |_| {
+                let adsr = Adsr {
+                    attack: 0.1,
+                    decay: 0.1,
+                    sustain: 0.5,
+                    release: 0.1,
+                };
+                assert_that!(adsr.held(0.05), eq(0.5));
+                assert_that!(adsr.held(0.1), eq(1.0));
+                assert_that!(adsr.held(1.0), eq(0.5));
+                assert_that!(adsr.released(0.5, 0.05), eq(0.25));
+                assert_that!(adsr.released(0.5, 0.2), eq(0.0));
+            });
+
+            ctx.it("plays notes until their release ends", |_| {
+                let mut synth = Synth::new(1000, Waveform::Square);
+                synth.adsr = Adsr {
+                    release: 0.1,
+                    ..Adsr::default()
+                };
+                synth.note_on(0, 69, 127);
+                synth.note_on(0, 72, 127);
+                let mut buf = vec![0.0; 100];
+                synth.render(&mut buf);
+                assert_that!(peak(&buf), gt(0.2));
+                synth.release_all();
+                synth.render(&mut buf[..50]);
+                assert!(!synth.is_silent());
+                synth.render(&mut buf);
+                assert!(synth.is_silent());
+                assert_that!(peak(&buf[50..]), eq(0.0));
+            });
+
+            ctx.it("scales voices by velocity", |_| {
+                let render = |vel| {
+                    let mut synth = Synth::new(1000, Waveform::Square);
+                    synth.note_on(0, 60, vel);
+                    let mut buf = vec![0.0; 500];
+                    synth.render(&mut buf);
+                    peak(&buf)
+                };
+                assert_that!(render(127), gt(render(32)));
+            });
+        }));
+    }
+}
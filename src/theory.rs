@@ -1,6 +1,8 @@
-use std::slice::Iter;
+use std::{slice::Iter, str::FromStr};
 
-#[derive(Clone, Copy, Debug)]
+use eyre::{eyre, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Sign {
     DoubleFlat,
     Flat,
@@ -89,6 +91,7 @@ impl DegreeNum for Degree {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Key {
     Midi(u8),
     A(Option<Sign>),
@@ -112,6 +115,7 @@ impl RelativeSemitones {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Mode {
     Ionian,
     Natural,
@@ -127,27 +131,99 @@ impl Mode {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Scale {
     key: Key,
     mode: Mode,
 }
 
+/// Krumhansl-Kessler key profiles, from the tonic up.
+const MAJOR_PROFILE: [f64; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f64; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+/// Correlation of `a` and `b`, 0 if either is flat.
+fn correlation(a: &[f64; 12], b: &[f64; 12]) -> f64 {
+    let mean = |x: &[f64; 12]| x.iter().sum::<f64>() / 12.0;
+    let (ma, mb) = (mean(a), mean(b));
+    let cov: f64 = a.iter().zip(b).map(|(x, y)| (x - ma) * (y - mb)).sum();
+    let var = |x: &[f64; 12], m: f64| x.iter().map(|v| (v - m).powi(2)).sum::<f64>();
+    match var(a, ma) * var(b, mb) {
+        v if v > 0.0 => cov / v.sqrt(),
+        _ => 0.0,
+    }
+}
+
 impl Scale {
     pub fn new(key: Key, mode: Mode) -> Self {
         Self { key, mode }
     }
 
+    /// Get the scale of a MIDI key signature, from its sharps (flats when
+    /// negative) and whether it is minor.
+    pub fn from_signature(sharps: i8, minor: bool) -> Self {
+        let major = (sharps as i16 * 7).rem_euclid(12) as u8;
+        match minor {
+            false => Self::new(Key::Midi(major), Mode::Ionian),
+            true => Self::new(Key::Midi((major + 9) % 12), Mode::Natural),
+        }
+    }
+
+    /// Guess the scale of some `(key, weight)` notes by how well their
+    /// pitch classes fit each major and minor key profile.
+    pub fn detect(notes: impl IntoIterator<Item = (u8, f64)>) -> Option<Self> {
+        let mut classes = [0.0; 12];
+        for (key, weight) in notes {
+            classes[key as usize % 12] += weight;
+        }
+        if classes.iter().all(|&w| w <= 0.0) {
+            return None;
+        }
+        let candidates = (0..12u8).flat_map(|tonic| {
+            [
+                (Mode::Ionian, MAJOR_PROFILE),
+                (Mode::Natural, MINOR_PROFILE),
+            ]
+            .map(|(mode, profile)| {
+                let rotated = std::array::from_fn(|i| classes[(tonic as usize + i) % 12]);
+                (correlation(&rotated, &profile), tonic, mode)
+            })
+        });
+        candidates
+            .fold(None, |best: Option<(f64, u8, Mode)>, c| match best {
+                Some(b) if b.0 >= c.0 => Some(b),
+                _ => Some(c),
+            })
+            .map(|(_, tonic, mode)| Self::new(Key::Midi(tonic), mode))
+    }
+
+    /// Get the pitch class of the first degree, 0 for C.
+    pub fn tonic(&self) -> u8 {
+        self.semitones()[0] % 12
+    }
+
+    /// Get the same mode on a tonic `by` semitones away.
+    pub fn transposed(&self, by: i16) -> Self {
+        let tonic = (self.tonic() as i16 + by).rem_euclid(12) as u8;
+        Self::new(Key::Midi(tonic), self.mode)
+    }
+
+    /// Get the keys of the scale's degrees in the octave from key 12 up,
+    /// padded with `u8::MAX`. Lettered tonics take their sign, so Bb is 10.
     pub fn semitones(&self) -> [u8; 16] {
         let mut out = [u8::MAX; 16];
         let base = match self.key {
             Key::Midi(m) => m as i8,
-            Key::A(s) => 8 + Sign::from(s).rel(),
-            Key::B(_s) => 10,
-            Key::C(_s) => 0,
-            Key::D(_s) => 2,
-            Key::E(_s) => 4,
-            Key::F(_s) => 5,
-            Key::G(_s) => 7,
+            Key::A(s) => 9 + Sign::from(s).rel(),
+            Key::B(s) => 11 + Sign::from(s).rel(),
+            Key::C(s) => Sign::from(s).rel(),
+            Key::D(s) => 2 + Sign::from(s).rel(),
+            Key::E(s) => 4 + Sign::from(s).rel(),
+            Key::F(s) => 5 + Sign::from(s).rel(),
+            Key::G(s) => 7 + Sign::from(s).rel(),
         };
         self.mode
             .semitones()
@@ -158,4 +234,151 @@ impl Scale {
             });
         out
     }
+
+    /// Whether `key` is in the scale, in any octave.
+    pub fn contains(&self, key: u8) -> bool {
+        self.semitones()
+            .iter()
+            .any(|&tone| tone != u8::MAX && tone % 12 == key % 12)
+    }
+
+    /// Get the nearest key in the scale to `key`, going down on a tie.
+    pub fn snap(&self, key: u8) -> u8 {
+        (0..12u8)
+            .flat_map(|d| [key.checked_sub(d), key.checked_add(d)])
+            .flatten()
+            .find(|&k| k <= 127 && self.contains(k))
+            .unwrap_or(key)
+    }
+
+    /// Move `key` by `steps` degrees of the scale, snapping it first.
+    pub fn step(&self, key: u8, steps: i8) -> Option<u8> {
+        let mut key = self.snap(key);
+        for _ in 0..steps.unsigned_abs() {
+            key = (1..12u8)
+                .map(|d| match steps > 0 {
+                    true => key.checked_add(d),
+                    false => key.checked_sub(d),
+                })
+                .find(|k| k.is_none_or(|k| self.contains(k)))
+                .flatten()
+                .filter(|&k| k <= 127)?;
+        }
+        Some(key)
+    }
+}
+
+impl std::fmt::Display for Scale {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const NAMES: [&str; 12] = [
+            "c", "c#", "d", "eb", "e", "f", "f#", "g", "ab", "a", "bb", "b",
+        ];
+        let mode = match self.mode {
+            Mode::Ionian => "major",
+            Mode::Natural => "minor",
+        };
+        write!(f, "{}-{}", NAMES[self.tonic() as usize], mode)
+    }
+}
+
+impl FromStr for Scale {
+    type Err = eyre::Error;
+
+    /// Parse a key with an optional mode, e.g. `c`, `f#-major` or `bb-minor`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        let (key, mode) = s.split_once('-').unwrap_or((&s, "major"));
+        let mut chars = key.chars();
+        let letter = chars.next().ok_or_else(|| eyre!("missing key"))?;
+        let sign = match chars.as_str() {
+            "" => None,
+            "#" => Some(Sign::Sharp),
+            "b" => Some(Sign::Flat),
+            "##" | "x" => Some(Sign::DoubleSharp),
+            "bb" => Some(Sign::DoubleFlat),
+            x => return Err(eyre!("unknown accidental {}", x)),
+        };
+        let key = match letter {
+            'a' => Key::A(sign),
+            'b' => Key::B(sign),
+            'c' => Key::C(sign),
+            'd' => Key::D(sign),
+            'e' => Key::E(sign),
+            'f' => Key::F(sign),
+            'g' => Key::G(sign),
+            x => return Err(eyre!("unknown key {}", x)),
+        };
+        let mode = match mode {
+            "major" | "ionian" => Mode::Ionian,
+            "minor" | "natural" => Mode::Natural,
+            x => return Err(eyre!("unknown mode {}", x)),
+        };
+        Ok(Self::new(key, mode))
+    }
+}
+
+#[cfg(test)]
+mod test_theory {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    #[test]
+    fn test_scale() {
+        rspec::run(&rspec::describe("Scale", Env::default(), |ctx| {
+            ctx.it("parses keys and modes", |_| {
+                let scale: Scale = "a-minor".parse().unwrap();
+                assert_that!(scale, eq(Scale::new(Key::A(None), Mode::Natural)));
+                assert!(scale.contains(69) && scale.contains(60) && !scale.contains(61));
+                let scale: Scale = "F#".parse().unwrap();
+                assert!(scale.contains(66) && scale.contains(70) && !scale.contains(67));
+                assert_that!("h".parse::<Scale>(), err());
+            });
+
+            ctx.it("puts lettered tonics on their pitch class", |_| {
+                let tonic = |key| Scale::new(key, Mode::Ionian).tonic();
+                assert_that!(tonic(Key::A(None)), eq(9));
+                assert_that!(tonic(Key::B(None)), eq(11));
+                assert_that!(tonic(Key::B(Some(Sign::Flat))), eq(10));
+                assert_that!(tonic(Key::C(Some(Sign::Flat))), eq(11));
+                assert_that!(tonic(Key::E(Some(Sign::Sharp))), eq(5));
+            });
+
+            ctx.it("snaps keys into the scale", |_| {
+                let scale: Scale = "c".parse().unwrap();
+                assert_that!(scale.snap(60), eq(60));
+                assert_that!(scale.snap(61), eq(60));
+                assert_that!(scale.snap(66), eq(65));
+            });
+
+            ctx.it("steps by degrees", |_| {
+                let scale: Scale = "c".parse().unwrap();
+                assert_that!(scale.step(60, 2), eq(Some(64)));
+                assert_that!(scale.step(62, 2), eq(Some(65)));
+                assert_that!(scale.step(64, -2), eq(Some(60)));
+                assert_that!(scale.step(127, 1), none());
+            });
+
+            ctx.it("reads key signatures", |_| {
+                assert_that!(Scale::from_signature(0, false).to_string(), eq("c-major"));
+                assert_that!(Scale::from_signature(2, false).to_string(), eq("d-major"));
+                assert_that!(Scale::from_signature(-3, true).to_string(), eq("c-minor"));
+                assert_that!(Scale::from_signature(1, true).to_string(), eq("e-minor"));
+            });
+
+            ctx.it("detects the key of notes", |_| {
+                let scale: Scale = "d".parse().unwrap();
+                let notes = [0i8, 2, 4, 5, 7, 9, 11, 12, 7, 4, 0]
+                    .map(|d| (scale.step(62, d).unwrap(), 1.0));
+                let detected = Scale::detect(notes).unwrap();
+                assert_that!(detected.tonic(), eq(2));
+                assert_that!(detected.mode, eq(Mode::Ionian));
+                let minor = [57u8, 60, 64, 57, 64, 60, 59, 57, 62, 65].map(|k| (k, 1.0));
+                assert_that!(Scale::detect(minor).unwrap().to_string(), eq("a-minor"));
+                assert_that!(Scale::detect([]), none());
+            });
+        }));
+    }
 }
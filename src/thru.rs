@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use eyre::Result;
+use midly::{live::LiveEvent, MidiMessage};
+
+use crate::{
+    output::Output,
+    player::Player,
+    sequence::{Event, DEFAULT_RELEASE},
+    transform::Transforms,
+};
+
+/// Plays notes coming in from an input through `Transforms` to a `Player`
+/// as soon as they arrive, e.g. from `input::listen`. Controllers and
+/// program changes pass through untouched.
+pub struct Thru<'a> {
+    player: Player<'a>,
+    transforms: Transforms,
+}
+
+impl<'a> Thru<'a> {
+    pub fn new(player: Player<'a>, transforms: Transforms) -> Self {
+        Self { player, transforms }
+    }
+}
+
+impl<'a> Output for Thru<'a> {
+    fn send(&mut self, at: Duration, msg: &[u8]) -> Result<()> {
+        use MidiMessage::*;
+
+        let (channel, message) = match LiveEvent::parse(msg) {
+            Ok(LiveEvent::Midi { channel, message }) => (channel, message),
+            _ => return Ok(()),
+        };
+        self.player.set_elapsed(at);
+        self.player.event(&Event::channel(channel))?;
+        match message {
+            NoteOn { key, vel } if vel > 0 => self
+                .transforms
+                .notes(key.as_int(), vel.as_int())
+                .into_iter()
+                .try_for_each(|(k, v)| self.player.strike(k, v)),
+            NoteOn { key, .. } | NoteOff { key, .. } => {
+                let release = match message {
+                    NoteOff { vel, .. } => vel.as_int(),
+                    _ => DEFAULT_RELEASE,
+                };
+                self.transforms
+                    .notes(key.as_int(), 1)
+                    .into_iter()
+                    .try_for_each(|(k, _)| self.player.release_key(k, release))
+            }
+            Controller { controller, value } => {
+                self.player.event(&Event::control(controller, value))
+            }
+            ProgramChange { program } => self.player.event(&Event::program(program)),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.player.all_notes_off()
+    }
+}
+
+impl<'a> Drop for Thru<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("failed to stop notes: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_thru {
+    use super::*;
+    use crate::output::{test_output::Messages, Capture};
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    fn thru(out: &Capture, transforms: &[&str]) -> Thru<'static> {
+        let mut player = Player::new("test");
+        player.set_human_vel_range(0.0);
+        player.set_output(out.clone());
+        let transforms = transforms.iter().map(|t| t.parse().unwrap()).collect();
+        Thru::new(player, Transforms::new(transforms))
+    }
+
+    #[test]
+    fn test_thru() {
+        rspec::run(&rspec::describe("Thru", Env::default(), |ctx| {
+            ctx.it("plays transformed notes straight away", |_| {
+                let out = Capture::new();
+                let mut thru = thru(&out, &["harmonize=c", "velocity=2"]);
+                let at = Duration::from_millis(30);
+                thru.send(at, &[0x92, 60, 127]).unwrap();
+                thru.send(at, &[0x82, 60, 40]).unwrap();
+                assert_that!(
+                    out.messages(),
+                    eq(vec![
+                        vec![0x92, 60, 127],
+                        vec![0x92, 64, 127],
+                        vec![0x82, 60, 40],
+                        vec![0x82, 64, 40],
+                    ])
+                );
+                assert!(out.sent().iter().all(|s| s.at == at));
+            });
+
+            ctx.it("passes controllers through", |_| {
+                let out = Capture::new();
+                let mut thru = thru(&out, &["transpose=12"]);
+                thru.send(Duration::ZERO, &[0xB1, 64, 127]).unwrap();
+                assert_that!(out.messages(), eq(vec![vec![0xB1, 64, 127]]));
+            });
+
+            ctx.it("stops held notes when dropped", |_| {
+                let out = Capture::new();
+                let mut thru = thru(&out, &["transpose=12"]);
+                thru.send(Duration::ZERO, &[0x90, 60, 100]).unwrap();
+                drop(thru);
+                assert_that!(
+                    out.messages(),
+                    eq(vec![vec![0x90, 72, 100], vec![0x80, 72, 64]])
+                );
+            });
+        }));
+    }
+}
@@ -0,0 +1,240 @@
+use clap::ArgEnum;
+
+use crate::sequence::{Dynamic, Event};
+
+/// How a sequence is split into tokens for a Markov chain.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Tokens {
+    /// Every event is a token of its own.
+    #[default]
+    Events,
+    /// Events starting together are one token, so chords stay whole.
+    Chords,
+    /// Events starting together and the wait until the next onset are one
+    /// token, so chords keep their rhythm too.
+    Intervals,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Token {
+    Event(Event),
+    /// Events starting together, e.g. the notes of a chord with their lengths.
+    Chord(Vec<Event>),
+    /// Events starting together and the ticks until the next onset.
+    Step(Vec<Event>, u32),
+}
+
+impl Token {
+    /// Get the events the token stands for.
+    pub fn events(&self) -> Vec<Event> {
+        match self {
+            Self::Event(ev) => vec![*ev],
+            Self::Chord(evs) => evs.clone(),
+            Self::Step(evs, 0) => evs.clone(),
+            Self::Step(evs, ticks) => {
+                let mut evs = evs.clone();
+                evs.push(Event::wait(*ticks));
+                evs
+            }
+        }
+    }
+}
+
+/// Pedals such as sustain, 64 to 69, which are only ever down or up.
+const PEDALS: std::ops::RangeInclusive<u8> = 64..=69;
+
+/// Get `ev` as a chain learns it, with its release velocity quantized like
+/// its dynamic so notes only let go differently are the same token. Pedals
+/// are just down or up, and other controllers are left out as their raw
+/// values would make nearly every token different.
+fn learned(ev: Event) -> Option<Event> {
+    match ev {
+        Event::PlayNoteTicks { release, .. } | Event::StopNote { release, .. } => {
+            Some(ev.with_release(Dynamic::from(release).vel()))
+        }
+        Event::Control { controller, value } if PEDALS.contains(&controller.as_int()) => {
+            let down = value.as_int() >= 64;
+            Some(Event::control(controller, if down { 127 } else { 0 }))
+        }
+        Event::Control { .. } => None,
+        ev => Some(ev),
+    }
+}
+
+/// Sort each run of notes in `onset` by key then length, so a chord voiced
+/// in another order is the same token. Other events, e.g. a channel change
+/// the notes after it belong to, keep their place.
+fn voiced(mut onset: Vec<Event>) -> Vec<Event> {
+    let is_note = |ev: &Event| matches!(ev, Event::PlayNote { .. } | Event::PlayNoteTicks { .. });
+    for notes in onset.split_mut(|ev| !is_note(ev)) {
+        notes.sort_by_key(|ev| match *ev {
+            Event::PlayNoteTicks { key, ticks, .. } => (key, Some(ticks)),
+            Event::PlayNote { key, .. } => (key, None),
+            _ => unreachable!("only notes are sorted"),
+        });
+    }
+    onset
+}
+
+/// Split `events` into tokens.
+pub fn tokenize(events: &[Event], tokens: Tokens) -> Vec<Token> {
+    let mut out = Vec::new();
+    let mut onset = Vec::new();
+    let mut waited = 0;
+    for ev in events.iter().copied().filter_map(learned) {
+        match (tokens, ev) {
+            (Tokens::Events, ev) => out.push(Token::Event(ev)),
+            (Tokens::Chords, Event::Wait { .. }) => {
+                if !onset.is_empty() {
+                    out.push(Token::Chord(voiced(std::mem::take(&mut onset))));
+                }
+                out.push(Token::Event(ev));
+            }
+            (Tokens::Intervals, Event::Wait { ticks }) => waited += ticks,
+            (Tokens::Intervals, ev) if waited > 0 => {
+                out.push(Token::Step(voiced(std::mem::take(&mut onset)), waited));
+                waited = 0;
+                onset.push(ev);
+            }
+            (_, ev) => onset.push(ev),
+        }
+    }
+    match tokens {
+        Tokens::Chords if !onset.is_empty() => out.push(Token::Chord(voiced(onset))),
+        Tokens::Intervals if !onset.is_empty() || waited > 0 => {
+            out.push(Token::Step(voiced(onset), waited))
+        }
+        _ => {}
+    }
+    out
+}
+
+#[cfg(test)]
+mod test_token {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    fn events() -> Vec<Event> {
+        vec![
+            Event::play_ticks(60, 64, 4),
+            Event::play_ticks(64, 64, 4),
+            Event::wait(2u32),
+            Event::wait(2u32),
+            Event::play_ticks(62, 64, 2),
+            Event::wait(2u32),
+        ]
+    }
+
+    #[test]
+    fn test_tokenize() {
+        rspec::run(&rspec::describe("tokenize", Env::default(), |ctx| {
+            ctx.it("keeps events apart", |_| {
+                let tokens = tokenize(&events(), Tokens::Events);
+                assert_that!(tokens.len(), eq(6));
+                assert_that!(
+                    tokens[0].clone(),
+                    eq(Token::Event(Event::play_ticks(60, 64, 4)))
+                );
+            });
+
+            ctx.it("groups chords", |_| {
+                let tokens = tokenize(&events(), Tokens::Chords);
+                assert_that!(
+                    tokens,
+                    eq(vec![
+                        Token::Chord(events()[..2].to_vec()),
+                        Token::Event(Event::wait(2u32)),
+                        Token::Event(Event::wait(2u32)),
+                        Token::Chord(vec![Event::play_ticks(62, 64, 2)]),
+                        Token::Event(Event::wait(2u32)),
+                    ])
+                );
+            });
+
+            ctx.it("groups chords with the time to the next", |_| {
+                let tokens = tokenize(&events(), Tokens::Intervals);
+                assert_that!(
+                    tokens,
+                    eq(vec![
+                        Token::Step(events()[..2].to_vec(), 4),
+                        Token::Step(vec![Event::play_ticks(62, 64, 2)], 2),
+                    ])
+                );
+            });
+
+            ctx.it("pools chords however they are voiced", |_| {
+                let chord = |keys: [u8; 3]| {
+                    let mut events = vec![Event::channel(1)];
+                    events.extend(keys.map(|k| Event::play_ticks(k, 64, 4)));
+                    events.push(Event::wait(4u32));
+                    events
+                };
+                for mode in [Tokens::Chords, Tokens::Intervals] {
+                    let (a, b) = (chord([60, 64, 67]), chord([64, 67, 60]));
+                    assert_that!(
+                        tokenize(&a, mode)[0].clone(),
+                        eq(tokenize(&b, mode)[0].clone())
+                    );
+                    assert_that!(
+                        tokenize(&b, mode)[0].events()[..4].to_vec(),
+                        eq(a[..4].to_vec())
+                    );
+                }
+            });
+
+            ctx.it("quantizes release velocities", |_| {
+                let events = [
+                    Event::play_ticks(60, 64, 4).with_release(30),
+                    Event::play_ticks(60, 64, 4).with_release(35),
+                    Event::stop(60).with_release(127),
+                ];
+                let tokens = tokenize(&events, Tokens::Events);
+                assert_that!(tokens[0].clone(), eq(tokens[1].clone()));
+                assert_that!(
+                    tokens[2].clone(),
+                    eq(Token::Event(Event::stop(60).with_release(112)))
+                );
+            });
+
+            ctx.it("keeps pedals and drops other controllers", |_| {
+                let events = [
+                    Event::control(64, 100),
+                    Event::control(1, 37),
+                    Event::control(64, 20),
+                ];
+                assert_that!(
+                    tokenize(&events, Tokens::Events),
+                    eq(vec![
+                        Token::Event(Event::control(64, 127)),
+                        Token::Event(Event::control(64, 0)),
+                    ])
+                );
+            });
+
+            ctx.it("gives back the same music", |_| {
+                for mode in [Tokens::Events, Tokens::Chords, Tokens::Intervals] {
+                    let played: Vec<Event> = tokenize(&events(), mode)
+                        .iter()
+                        .flat_map(Token::events)
+                        .collect();
+                    let onsets = |evs: &[Event]| {
+                        evs.iter()
+                            .scan(0, |at, ev| {
+                                let start = *at;
+                                if let Event::Wait { ticks } = ev {
+                                    *at += ticks;
+                                }
+                                Some((start, *ev))
+                            })
+                            .filter(|(_, ev)| !matches!(ev, Event::Wait { .. }))
+                            .collect::<Vec<_>>()
+                    };
+                    assert_that!(onsets(&played), eq(onsets(&events())));
+                }
+            });
+        }));
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    time::{Duration, Instant},
+};
+
+use clap::ArgEnum;
+use eyre::Result;
+use itertools::Itertools;
+
+/// How a trace is written.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON object per line.
+    #[default]
+    Jsonl,
+    /// Comma separated values with a header.
+    Csv,
+}
+
+/// One message sent by the `Player`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub tick: u32,
+    /// When the message was due before humanization and groove.
+    pub intended: Duration,
+    /// When the message was stamped, as the output sees it.
+    pub at: Duration,
+    /// Wall-clock time since the first traced message.
+    pub actual: Duration,
+    /// Humanization added to a note on's velocity.
+    pub vel_offset: i16,
+    pub msg: Vec<u8>,
+    /// (channel, key) of every key sounding after the message.
+    pub notes_on: Vec<(u8, u8)>,
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+impl Entry {
+    /// Get how far humanization and groove moved the message, in ms.
+    pub fn shift_ms(&self) -> f64 {
+        ms(self.at) - ms(self.intended)
+    }
+
+    fn hex(&self) -> String {
+        self.msg.iter().map(|b| format!("{:02x}", b)).join(" ")
+    }
+
+    fn to_json(&self) -> String {
+        let notes_on = self
+            .notes_on
+            .iter()
+            .map(|(ch, key)| format!("[{},{}]", ch, key))
+            .join(",");
+        format!(
+            "{{\"tick\":{},\"intended_ms\":{:.3},\"at_ms\":{:.3},\"actual_ms\":{:.3},\"shift_ms\":{:.3},\"vel_offset\":{},\"msg\":\"{}\",\"notes_on\":[{}]}}",
+            self.tick,
+            ms(self.intended),
+            ms(self.at),
+            ms(self.actual),
+            self.shift_ms(),
+            self.vel_offset,
+            self.hex(),
+            notes_on
+        )
+    }
+
+    fn to_csv(&self) -> String {
+        let notes_on = self
+            .notes_on
+            .iter()
+            .map(|(ch, key)| format!("{}:{}", ch, key))
+            .join(" ");
+        format!(
+            "{},{:.3},{:.3},{:.3},{:.3},{},{},{}",
+            self.tick,
+            ms(self.intended),
+            ms(self.at),
+            ms(self.actual),
+            self.shift_ms(),
+            self.vel_offset,
+            self.hex(),
+            notes_on
+        )
+    }
+}
+
+const CSV_HEADER: &str = "tick,intended_ms,at_ms,actual_ms,shift_ms,vel_offset,msg,notes_on";
+
+/// Writes an `Entry` for every message the player sends, to find out
+/// whether the chain, the parser or the scheduler is at fault.
+pub struct Trace {
+    out: Box<dyn Write + Send>,
+    format: Format,
+    start: Option<Instant>,
+}
+
+impl Trace {
+    pub fn new(out: impl Write + Send + 'static, format: Format) -> Self {
+        Self {
+            out: Box::new(out),
+            format,
+            start: None,
+        }
+    }
+
+    pub fn create(path: &str, format: Format) -> Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?), format))
+    }
+
+    /// Get the wall-clock time since the first message.
+    pub fn elapsed(&mut self) -> Duration {
+        self.start.get_or_insert_with(Instant::now).elapsed()
+    }
+
+    pub fn record(&mut self, entry: &Entry) -> Result<()> {
+        let line = match self.format {
+            Format::Jsonl => entry.to_json(),
+            Format::Csv => entry.to_csv(),
+        };
+        if self.format == Format::Csv && self.start.is_none() {
+            writeln!(self.out, "{}", CSV_HEADER)?;
+        }
+        self.start.get_or_insert_with(Instant::now);
+        writeln!(self.out, "{}", line)?;
+        Ok(())
+    }
+
+    /// Write out anything buffered.
+    pub fn flush(&mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_trace {
+    use super::*;
+    use hamcrest2::prelude::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    /// A writer that clones share, so a test can read what a trace wrote.
+    #[derive(Clone, Default)]
+    pub(crate) struct Written(Arc<Mutex<Vec<u8>>>);
+
+    impl Written {
+        pub(crate) fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect()
+        }
+    }
+
+    impl Write for Written {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn entry() -> Entry {
+        Entry {
+            tick: 2,
+            intended: Duration::from_millis(20),
+            at: Duration::from_millis(25),
+            actual: Duration::from_millis(26),
+            vel_offset: -3,
+            msg: vec![0x90, 60, 61],
+            notes_on: vec![(0, 60), (1, 64)],
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        rspec::run(&rspec::describe("Trace", Env::default(), |ctx| {
+            ctx.it("writes json lines", |_| {
+                let out = Written::default();
+                let mut trace = Trace::new(out.clone(), Format::Jsonl);
+                trace.record(&entry()).unwrap();
+                assert_that!(
+                    out.lines(),
+                    eq(vec![concat!(
+                        r#"{"tick":2,"intended_ms":20.000,"at_ms":25.000,"actual_ms":26.000,"#,
+                        r#""shift_ms":5.000,"vel_offset":-3,"msg":"90 3c 3d","notes_on":[[0,60],[1,64]]}"#
+                    )
+                    .to_string()])
+                );
+            });
+
+            ctx.it("writes csv with a header", |_| {
+                let out = Written::default();
+                let mut trace = Trace::new(out.clone(), Format::Csv);
+                trace.record(&entry()).unwrap();
+                trace.record(&entry()).unwrap();
+                let lines = out.lines();
+                assert_that!(lines.len(), eq(3));
+                assert_that!(lines[0].as_str(), eq(CSV_HEADER));
+                assert_that!(
+                    lines[1].as_str(),
+                    eq("2,20.000,25.000,26.000,5.000,-3,90 3c 3d,0:60 1:64")
+                );
+            });
+        }));
+    }
+}
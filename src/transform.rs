@@ -0,0 +1,199 @@
+use std::str::FromStr;
+
+use eyre::{eyre, Result};
+
+use crate::{midi::MidiSequence, sequence::Event, theory::Scale};
+
+/// A change made to every note, whether played live or in a sequence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transform {
+    /// Move by semitones.
+    Transpose(i8),
+    /// Move keys outside the scale to the nearest key in it.
+    Snap(Scale),
+    /// Add the third above in the scale.
+    Harmonize(Scale),
+    /// Bend velocities by a power, above 1 is softer and below is louder.
+    Velocity(f64),
+    /// Add the same key this many octaves away.
+    Double(i8),
+}
+
+impl Transform {
+    /// Get the notes a `(key, vel)` becomes. Keys moved out of range are
+    /// dropped.
+    pub fn apply(&self, key: u8, vel: u8) -> Vec<(u8, u8)> {
+        let moved = |by: i16| u8::try_from(key as i16 + by).ok().filter(|&k| k <= 127);
+        match *self {
+            Self::Transpose(by) => moved(by as i16).map(|k| (k, vel)).into_iter().collect(),
+            Self::Snap(scale) => vec![(scale.snap(key), vel)],
+            Self::Harmonize(scale) => std::iter::once((key, vel))
+                .chain(scale.step(key, 2).map(|k| (k, vel)))
+                .collect(),
+            Self::Velocity(power) => {
+                let vel = (vel as f64 / 127.0).powf(power) * 127.0;
+                vec![(key, (vel.round() as u8).clamp(1, 127))]
+            }
+            Self::Double(octaves) => std::iter::once((key, vel))
+                .chain(moved(octaves as i16 * 12).map(|k| (k, vel)))
+                .collect(),
+        }
+    }
+}
+
+impl FromStr for Transform {
+    type Err = eyre::Error;
+
+    /// Parse `name=value`, e.g. `transpose=-2`, `snap=a-minor`,
+    /// `harmonize=c`, `velocity=0.8` or `double=1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once('=')
+            .ok_or_else(|| eyre!("transform must be name=value: {}", s))?;
+        let value = value.trim();
+        Ok(match name.trim() {
+            "transpose" => Self::Transpose(value.parse()?),
+            "snap" => Self::Snap(value.parse()?),
+            "harmonize" => Self::Harmonize(value.parse()?),
+            "velocity" => Self::Velocity(value.parse()?),
+            "double" => Self::Double(value.parse()?),
+            x => return Err(eyre!("unknown transform {}", x)),
+        })
+    }
+}
+
+/// Transforms applied one after another.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transforms {
+    transforms: Vec<Transform>,
+}
+
+impl Transforms {
+    pub fn new(transforms: Vec<Transform>) -> Self {
+        Self { transforms }
+    }
+
+    /// Get the notes a `(key, vel)` becomes, each key once.
+    pub fn notes(&self, key: u8, vel: u8) -> Vec<(u8, u8)> {
+        let mut notes = vec![(key, vel)];
+        for t in &self.transforms {
+            notes = notes.into_iter().flat_map(|(k, v)| t.apply(k, v)).collect();
+        }
+        let mut seen = [false; 128];
+        notes.retain(|&(k, _)| !std::mem::replace(&mut seen[k as usize], true));
+        notes
+    }
+
+    /// Transform every note of `events`, passing anything else through.
+    pub fn events(&self, events: impl IntoIterator<Item = Event>) -> Vec<Event> {
+        events
+            .into_iter()
+            .flat_map(|ev| match ev {
+                Event::PlayNote { key, dynamic } => self
+                    .notes(key.as_int(), dynamic.vel())
+                    .into_iter()
+                    .map(|(k, v)| Event::play(k, v))
+                    .collect(),
+                Event::PlayNoteTicks {
+                    key,
+                    dynamic,
+                    ticks,
+                    release,
+                } => self
+                    .notes(key.as_int(), dynamic.vel())
+                    .into_iter()
+                    .map(|(k, v)| Event::play_ticks(k, v, ticks).with_release(release))
+                    .collect(),
+                Event::StopNote { key, release } => self
+                    .notes(key.as_int(), 1)
+                    .into_iter()
+                    .map(|(k, _)| Event::stop(k).with_release(release))
+                    .collect(),
+                ev => vec![ev],
+            })
+            .collect()
+    }
+
+    /// Transform every note of a sequence. Its key signature is dropped if
+    /// any transform moves keys out of it.
+    pub fn sequence(&self, seq: MidiSequence) -> MidiSequence {
+        let ticks_per_beat = seq.ticks_per_beat();
+        let key = seq.key().filter(|_| {
+            self.transforms
+                .iter()
+                .all(|t| !matches!(t, Transform::Transpose(_) | Transform::Snap(_)))
+        });
+        let programs = seq.programs().to_vec();
+        MidiSequence::new(self.events(seq), ticks_per_beat)
+            .with_key(key)
+            .with_programs(programs)
+    }
+}
+
+#[cfg(test)]
+mod test_transform {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    fn transforms(s: &[&str]) -> Transforms {
+        Transforms::new(s.iter().map(|t| t.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn test_transforms() {
+        rspec::run(&rspec::describe("Transforms", Env::default(), |ctx| {
+            ctx.it("parses name=value", |_| {
+                assert_that!(
+                    "transpose=-2".parse::<Transform>().unwrap(),
+                    eq(Transform::Transpose(-2))
+                );
+                assert_that!("snap".parse::<Transform>(), err());
+                assert_that!("wobble=1".parse::<Transform>(), err());
+            });
+
+            ctx.it("applies transforms in order", |_| {
+                let t = transforms(&["transpose=1", "snap=c", "harmonize=c"]);
+                assert_that!(t.notes(60, 64), eq(vec![(60, 64), (64, 64)]));
+                let t = transforms(&["double=-1", "velocity=2"]);
+                assert_that!(t.notes(60, 127), eq(vec![(60, 127), (48, 127)]));
+                assert_that!(t.notes(60, 64)[0].1, eq(32));
+            });
+
+            ctx.it("drops keys out of range and repeats", |_| {
+                let t = transforms(&["double=1"]);
+                assert_that!(t.notes(120, 64), eq(vec![(120, 64)]));
+                let t = transforms(&["snap=c", "double=0"]);
+                assert_that!(t.notes(61, 64), eq(vec![(60, 64)]));
+            });
+
+            ctx.it("transforms the notes of a sequence", |_| {
+                let t = transforms(&["double=1"]);
+                let events = t.events(vec![
+                    Event::play_ticks(60, 64, 2).with_release(30),
+                    Event::wait(2u32),
+                    Event::stop(62),
+                ]);
+                assert_that!(
+                    events,
+                    eq(vec![
+                        Event::play_ticks(60, 64, 2).with_release(30),
+                        Event::play_ticks(72, 64, 2).with_release(30),
+                        Event::wait(2u32),
+                        Event::stop(62),
+                        Event::stop(74),
+                    ])
+                );
+            });
+
+            ctx.it("keeps the key signature unless keys move", |_| {
+                let key = Some("d".parse().unwrap());
+                let seq = || MidiSequence::new(vec![Event::play(62, 64)], 2).with_key(key);
+                assert_that!(transforms(&["double=1"]).sequence(seq()).key(), eq(key));
+                assert_that!(transforms(&["transpose=2"]).sequence(seq()).key(), none());
+            });
+        }));
+    }
+}
@@ -0,0 +1,353 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::sleep,
+    time::Duration,
+};
+
+use eyre::{bail, Result};
+
+use crate::{midi::MidiSequence, player::Player, sequence::Event};
+
+/// How often a paused `Sequencer` checks whether it should resume.
+const PAUSE_POLL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Default)]
+struct Commands {
+    paused: bool,
+    seek: Option<f64>,
+    // (start, end) in beats
+    looping: Option<(f64, f64)>,
+}
+
+/// A handle for controlling a `Sequencer` from another thread. Positions are
+/// in beats.
+#[derive(Clone, Default)]
+pub struct Transport {
+    commands: Arc<Mutex<Commands>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Transport {
+    /// Use `stopped` as the stop flag, e.g. one registered with
+    /// `signal_hook::flag`.
+    pub fn with_stop_flag(stopped: Arc<AtomicBool>) -> Self {
+        Self {
+            stopped,
+            ..Default::default()
+        }
+    }
+
+    pub fn pause(&self) {
+        self.commands.lock().unwrap().paused = true;
+    }
+
+    pub fn resume(&self) {
+        self.commands.lock().unwrap().paused = false;
+    }
+
+    pub fn toggle_pause(&self) {
+        let mut commands = self.commands.lock().unwrap();
+        commands.paused = !commands.paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.commands.lock().unwrap().paused
+    }
+
+    /// Jump to `beat` before the next event.
+    pub fn seek(&self, beat: f64) {
+        self.commands.lock().unwrap().seek = Some(beat.max(0.0));
+    }
+
+    /// Jump back to `start` whenever playback reaches `end`.
+    pub fn set_loop(&self, start: f64, end: f64) {
+        let (start, end) = (start.min(end).max(0.0), start.max(end));
+        self.commands.lock().unwrap().looping = (end > start).then_some((start, end));
+    }
+
+    pub fn clear_loop(&self) {
+        self.commands.lock().unwrap().looping = None;
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Silence `player` while paused, blocking until resumed or stopped.
+    pub fn wait_while_paused(&self, player: &mut Player) -> Result<()> {
+        if !self.is_paused() {
+            return Ok(());
+        }
+        player.pause()?;
+        while self.is_paused() && !self.is_stopped() {
+            sleep(PAUSE_POLL);
+        }
+        player.resume()
+    }
+
+    /// Run a text command: `p` toggles pause, `s <beat>` seeks, `l <start>
+    /// <end>` loops, `l` clears the loop and `q` stops.
+    pub fn command(&self, line: &str) -> Result<()> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["p" | "pause"] => self.toggle_pause(),
+            ["s" | "seek", beat] => self.seek(beat.parse()?),
+            ["l" | "loop"] => self.clear_loop(),
+            ["l" | "loop", start, end] => self.set_loop(start.parse()?, end.parse()?),
+            ["q" | "quit"] => self.stop(),
+            _ => bail!("unknown transport command: {}", line),
+        }
+        Ok(())
+    }
+}
+
+/// Plays a pre-rendered sequence under the control of a `Transport`.
+pub struct Sequencer {
+    events: Vec<Event>,
+    // tick at which each event starts
+    starts: Vec<u32>,
+    ticks_per_beat: u32,
+    transport: Transport,
+    i: usize,
+    pos: u32,
+    paused: bool,
+}
+
+fn len(event: &Event) -> u32 {
+    match *event {
+        Event::Wait { ticks } => ticks,
+        _ => 0,
+    }
+}
+
+impl Sequencer {
+    pub fn new(seq: MidiSequence, transport: Transport) -> Self {
+        let ticks_per_beat = seq.ticks_per_beat();
+        let events = seq.events;
+        let starts = events
+            .iter()
+            .scan(0, |pos, ev| {
+                let start = *pos;
+                *pos += len(ev);
+                Some(start)
+            })
+            .collect();
+        Self {
+            events,
+            starts,
+            ticks_per_beat,
+            transport,
+            i: 0,
+            pos: 0,
+            paused: false,
+        }
+    }
+
+    fn ticks(&self, beat: f64) -> u32 {
+        (beat * self.ticks_per_beat as f64).round() as u32
+    }
+
+    fn jump(&mut self, player: &mut Player, tick: u32) -> Result<()> {
+        player.all_notes_off()?;
+        self.i = self
+            .events
+            .iter()
+            .zip(&self.starts)
+            .position(|(ev, &start)| match ev {
+                Event::Wait { ticks } => start + ticks > tick,
+                _ => start >= tick,
+            })
+            .unwrap_or(self.events.len());
+        self.pos = tick;
+        // land at the tempo in effect there
+        match self.events[..self.i]
+            .iter()
+            .rfind(|ev| matches!(ev, Event::Tempo { .. }))
+        {
+            Some(tempo) => player.event(tempo),
+            None => Ok(()),
+        }
+    }
+
+    /// Get the next event to play, handling any transport commands first.
+    /// Blocks while paused and returns `None` once stopped or finished.
+    pub fn step(&mut self, player: &mut Player) -> Result<Option<Event>> {
+        loop {
+            if self.transport.is_stopped() {
+                player.all_notes_off()?;
+                return Ok(None);
+            }
+            let (paused, seek, looping) = {
+                let mut commands = self.transport.commands.lock().unwrap();
+                (commands.paused, commands.seek.take(), commands.looping)
+            };
+            if let Some(beat) = seek {
+                self.jump(player, self.ticks(beat))?;
+            }
+            if paused != self.paused {
+                match paused {
+                    true => player.pause()?,
+                    false => player.resume()?,
+                }
+                self.paused = paused;
+            }
+            if paused {
+                sleep(PAUSE_POLL);
+                continue;
+            }
+            let looping = looping.map(|(start, end)| (self.ticks(start), self.ticks(end)));
+            if let Some((start, end)) = looping {
+                if self.pos >= end || self.i >= self.events.len() {
+                    self.jump(player, start)?;
+                }
+            }
+            let event = match self.events.get(self.i) {
+                Some(&event) => event,
+                None => return Ok(None),
+            };
+            if let Event::Wait { ticks } = event {
+                let end = self.starts[self.i] + ticks;
+                let until = looping.map_or(end, |(_, loop_end)| end.min(loop_end));
+                let wait = until.saturating_sub(self.pos);
+                self.pos = until;
+                if until == end {
+                    self.i += 1;
+                }
+                return Ok(Some(Event::wait(wait)));
+            }
+            self.i += 1;
+            return Ok(Some(event));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_transport {
+    use super::*;
+    use crate::output::{test_output::Messages, Capture};
+    use hamcrest2::prelude::*;
+
+    #[derive(Clone, Default, Debug)]
+    struct Env {}
+
+    fn seq() -> MidiSequence {
+        MidiSequence::new(
+            vec![
+                Event::play_ticks(60, 64, 1),
+                Event::wait(2u32),
+                Event::play_ticks(62, 64, 1),
+                Event::wait(2u32),
+                Event::play_ticks(64, 64, 1),
+                Event::wait(2u32),
+            ],
+            1,
+        )
+    }
+
+    fn player(out: &Capture) -> Player<'static> {
+        let mut player = Player::new("test");
+        player.set_ticks_per_beat(1u32);
+        player.set_tempo(60_000.0);
+        player.set_human_ms_range(0.0);
+        player.set_human_vel_range(0.0);
+        player.set_output(out.clone());
+        player
+    }
+
+    fn keys_played(out: &Capture) -> Vec<u8> {
+        out.messages()
+            .iter()
+            .filter(|m| m[0] == 0x90)
+            .map(|m| m[1])
+            .collect()
+    }
+
+    #[test]
+    fn test_sequencer() {
+        rspec::run(&rspec::describe("Sequencer", Env::default(), |ctx| {
+            ctx.it("plays the whole sequence", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                let mut seqr = Sequencer::new(seq(), Transport::default());
+                while let Some(ev) = seqr.step(&mut player).unwrap() {
+                    player.event(&ev).unwrap();
+                }
+                assert_that!(keys_played(&out), eq(vec![60, 62, 64]));
+            });
+
+            ctx.it("seeks to a beat", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                let transport = Transport::default();
+                let mut seqr = Sequencer::new(seq(), transport.clone());
+                transport.seek(4.0);
+                while let Some(ev) = seqr.step(&mut player).unwrap() {
+                    player.event(&ev).unwrap();
+                }
+                assert_that!(keys_played(&out), eq(vec![64]));
+            });
+
+            ctx.it("seeks to the tempo in effect there", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                let transport = Transport::default();
+                let mut seq = seq();
+                seq.events.insert(0, Event::tempo(30_000));
+                let mut seqr = Sequencer::new(seq, transport.clone());
+                transport.seek(4.0);
+                seqr.step(&mut player).unwrap();
+                assert_that!(player.tempo(), eq(30_000.0));
+            });
+
+            ctx.it("loops between two beats", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                let transport = Transport::default();
+                let mut seqr = Sequencer::new(seq(), transport.clone());
+                transport.set_loop(2.0, 4.0);
+                for _ in 0..5 {
+                    let ev = seqr.step(&mut player).unwrap().unwrap();
+                    player.event(&ev).unwrap();
+                }
+                assert_that!(keys_played(&out), eq(vec![60, 62, 62]));
+            });
+
+            ctx.it("strikes paused notes again on resume", |_| {
+                let out = Capture::new();
+                let mut player = player(&out);
+                player.event(&Event::play_ticks(60, 64, 4)).unwrap();
+                player.pause().unwrap();
+                player.resume().unwrap();
+                assert_that!(
+                    out.messages(),
+                    eq(vec![
+                        vec![0x90, 60, 64],
+                        vec![0x80, 60, 64],
+                        vec![0x90, 60, 64]
+                    ])
+                );
+            });
+
+            ctx.it("parses text commands", |_| {
+                let transport = Transport::default();
+                transport.command("p").unwrap();
+                assert!(transport.is_paused());
+                transport.command("l 4 2").unwrap();
+                assert_that!(
+                    transport.commands.lock().unwrap().looping,
+                    eq(Some((2.0, 4.0)))
+                );
+                assert_that!(transport.command("x"), err());
+                transport.command("q").unwrap();
+                assert!(transport.is_stopped());
+            });
+        }));
+    }
+}